@@ -0,0 +1,275 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    ptr,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// An interned name, such as a tag, attribute or class name. Every live
+/// atom with the same text points at the same string, so atoms compare and
+/// hash by pointer. Names other than the static ones, such as the ids and
+/// classes of a page, are refcounted and freed with the last atom holding
+/// them.
+#[derive(Clone)]
+pub struct Atom(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Static(&'static str),
+    Dynamic(Arc<str>),
+}
+
+/// Names interned up front, sorted so they can be found by binary search.
+/// A `static`, so each name has one address for atoms to point at.
+#[rustfmt::skip]
+static STATIC_ATOMS: [&str; 177] = [
+    "a", "abbr", "accept", "action", "address", "alt", "area", "article", "aside", "async", "audio",
+    "autocomplete", "autofocus", "b", "base", "bdi", "bdo", "blockquote", "body", "br", "button",
+    "canvas", "caption", "charset", "checked", "cite", "class", "code", "col", "colgroup", "cols",
+    "colspan", "content", "crossorigin", "data", "datalist", "dd", "defer", "del", "details", "dfn",
+    "dialog", "dir", "disabled", "div", "dl", "download", "draggable", "dt", "em", "embed",
+    "enctype", "fieldset", "figcaption", "figure", "footer", "for", "form", "h1", "h2", "h3", "h4",
+    "h5", "h6", "head", "header", "height", "hgroup", "hidden", "hr", "href", "hreflang", "html",
+    "http-equiv", "i", "id", "iframe", "img", "input", "ins", "integrity", "kbd", "label", "lang",
+    "legend", "li", "link", "loading", "main", "map", "mark", "math", "max", "maxlength", "media",
+    "menu", "meta", "meter", "method", "min", "minlength", "multiple", "name", "nav", "noembed",
+    "noframes", "noscript", "object", "ol", "optgroup", "option", "output", "p", "pattern",
+    "picture", "placeholder", "pre", "progress", "q", "readonly", "rel", "required", "role", "rows",
+    "rowspan", "rp", "rt", "ruby", "s", "samp", "sandbox", "scope", "script", "section", "select",
+    "selected", "size", "sizes", "slot", "small", "source", "span", "src", "srcdoc", "srcset",
+    "start", "step", "strong", "style", "sub", "summary", "sup", "svg", "tabindex", "table",
+    "target", "tbody", "td", "template", "textarea", "tfoot", "th", "thead", "time", "title", "tr",
+    "track", "type", "u", "ul", "value", "var", "video", "wbr", "width", "wrap", "xmp",
+];
+
+/// Names interned while running, by the strings of the atoms holding them.
+/// The last atom for a name removes it.
+struct DynamicAtoms {
+    names: BTreeMap<Box<str>, Weak<str>>,
+    /// How many names there were after dead ones were last pruned.
+    pruned_len: usize,
+}
+
+static DYNAMIC_ATOMS: Mutex<DynamicAtoms> = Mutex::new(DynamicAtoms {
+    names: BTreeMap::new(),
+    pruned_len: 0,
+});
+
+impl DynamicAtoms {
+    fn get(&self, name: &str) -> Option<Arc<str>> {
+        self.names.get(name).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, name: &str) -> Arc<str> {
+        let interned: Arc<str> = name.into();
+        self.names.insert(name.into(), Arc::downgrade(&interned));
+        // Two atoms dropped at once can both miss being the last one and
+        // leave a dead name behind, so those are pruned now and then.
+        if self.names.len() > 2 * self.pruned_len.max(64) {
+            self.names.retain(|_, interned| interned.strong_count() > 0);
+            self.pruned_len = self.names.len();
+        }
+        interned
+    }
+}
+
+impl Atom {
+    /// The atom for `name`, as it is.
+    pub fn new(name: &str) -> Atom {
+        if let Ok(index) = STATIC_ATOMS.binary_search(&name) {
+            return Atom(Repr::Static(STATIC_ATOMS[index]));
+        }
+        let mut dynamic = DYNAMIC_ATOMS.lock().unwrap();
+        let interned = match dynamic.get(name) {
+            Some(interned) => interned,
+            None => dynamic.insert(name),
+        };
+        Atom(Repr::Dynamic(interned))
+    }
+
+    /// The atom for `name` if there is one already, without making one. No
+    /// set of atoms can hold a name that has none.
+    pub fn find(name: &str) -> Option<Atom> {
+        if let Ok(index) = STATIC_ATOMS.binary_search(&name) {
+            return Some(Atom(Repr::Static(STATIC_ATOMS[index])));
+        }
+        let dynamic = DYNAMIC_ATOMS.lock().unwrap();
+        dynamic
+            .get(name)
+            .map(|interned| Atom(Repr::Dynamic(interned)))
+    }
+
+    /// The atom for `name` with ASCII letters lowercased, as HTML tag and
+    /// attribute names are.
+    pub fn lowercase(name: &str) -> Atom {
+        if name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            Atom::new(&name.to_ascii_lowercase())
+        } else {
+            Atom::new(name)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            Repr::Static(name) => name,
+            Repr::Dynamic(name) => name,
+        }
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Atom) -> bool {
+        match (&self.0, &other.0) {
+            (Repr::Static(a), Repr::Static(b)) => ptr::eq(*a, *b),
+            (Repr::Dynamic(a), Repr::Dynamic(b)) => Arc::ptr_eq(a, b),
+            // A name is never both.
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Atom {}
+
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Repr::Static(name) => name.as_ptr().hash(state),
+            Repr::Dynamic(name) => name.as_ptr().hash(state),
+        }
+    }
+}
+
+impl Drop for Atom {
+    fn drop(&mut self) {
+        let Repr::Dynamic(interned) = &self.0 else {
+            return;
+        };
+        // Only the last atom for a name takes the lock, and checks again
+        // under it since `new` may have handed out another meanwhile.
+        if Arc::strong_count(interned) == 1 {
+            let mut dynamic = DYNAMIC_ATOMS.lock().unwrap();
+            if Arc::strong_count(interned) == 1 {
+                dynamic.names.remove(&**interned);
+            }
+        }
+    }
+}
+
+impl PartialOrd for Atom {
+    fn partial_cmp(&self, other: &Atom) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Atom {
+    fn cmp(&self, other: &Atom) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialEq<str> for Atom {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Atom {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Deref for Atom {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Atom {
+    fn from(name: &str) -> Atom {
+        Atom::new(name)
+    }
+}
+
+impl From<String> for Atom {
+    fn from(name: String) -> Atom {
+        Atom::new(&name)
+    }
+}
+
+impl From<&String> for Atom {
+    fn from(name: &String) -> Atom {
+        Atom::new(name)
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_atoms_are_sorted() {
+        assert!(STATIC_ATOMS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_interning() {
+        assert!(ptr::eq(
+            Atom::new("div").as_str(),
+            Atom::new("div").as_str()
+        ));
+        assert_eq!(Atom::new("my-widget"), Atom::from("my-widget".to_string()));
+        assert_ne!(Atom::new("my-widget"), Atom::new("my-widgets"));
+        assert_eq!(Atom::new("Div"), "Div");
+        assert_eq!(Atom::lowercase("DIV"), Atom::new("div"));
+        assert_eq!(Atom::lowercase("My-Widget"), Atom::new("my-widget"));
+        assert_eq!(
+            format!("{} {:?}", Atom::new("p"), Atom::new("p")),
+            "p \"p\""
+        );
+        let widget = Atom::new("my-widget");
+        assert!(ptr::eq(widget.as_str(), Atom::new("my-widget").as_str()));
+        assert_eq!(Atom::find("my-widget"), Some(widget));
+        assert_eq!(Atom::find("div"), Some(Atom::new("div")));
+    }
+
+    #[test]
+    fn test_dynamic_atoms_are_freed() {
+        let hash = |atom: &Atom| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            atom.hash(&mut hasher);
+            hasher.finish()
+        };
+        let interned = |name: &str| DYNAMIC_ATOMS.lock().unwrap().names.contains_key(name);
+        let atom = Atom::new("freed-widget");
+        let other = Atom::new("freed-widget");
+        assert_eq!(hash(&atom), hash(&other));
+        let Repr::Dynamic(name) = &atom.0 else {
+            panic!("{atom:?} is static");
+        };
+        let name = Arc::downgrade(name);
+        drop(other);
+        assert!(name.upgrade().is_some());
+        assert!(interned("freed-widget"));
+        drop(atom);
+        assert!(name.upgrade().is_none());
+        assert!(!interned("freed-widget"));
+        assert_eq!(Atom::find("freed-widget"), None);
+    }
+}
@@ -1,29 +1,702 @@
+use std::fmt;
+
+use crate::{
+    atom::Atom,
+    source::{SourceLocation, Span},
+    style,
+};
+
+#[derive(Debug, PartialEq)]
 pub struct StyleSheet {
     pub rules: Vec<Rule>,
+    pub origin: Origin,
+    pub layers: LayerOrder,
+    pub namespaces: Namespaces,
+}
+
+/// The namespace prefixes declared by a sheet's `@namespace` rules. They only
+/// apply to the sheet itself, not to the sheets it imports.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Namespaces {
+    /// The namespace of compound selectors without a prefix.
+    pub default: Option<Atom>,
+    /// Prefixes and the namespaces they stand for, in declaration order.
+    pub prefixes: Vec<(Atom, Atom)>,
+}
+
+impl Namespaces {
+    /// Declare `prefix`, or the default namespace when `None`, replacing an
+    /// earlier declaration.
+    pub fn declare(&mut self, prefix: Option<Atom>, url: Atom) {
+        match prefix {
+            Some(prefix) => {
+                self.prefixes.retain(|(declared, _)| *declared != prefix);
+                self.prefixes.push((prefix, url));
+            }
+            None => self.default = Some(url),
+        }
+    }
+
+    pub fn get(&self, prefix: &Atom) -> Option<&Atom> {
+        self.prefixes
+            .iter()
+            .find(|(declared, _)| declared == prefix)
+            .map(|(_, url)| url)
+    }
+}
+
+/// Fetches the style sheets named by `@import` rules.
+pub trait Loader {
+    /// Resolve `url` against `base`, the location of the importing sheet,
+    /// and return the resolved location with the source found there, or
+    /// `None` if it can't be loaded.
+    fn load(&mut self, url: &str, base: &str) -> Option<(String, String)>;
+}
+
+/// The cascade layers of a style sheet, in the order they rank for normal
+/// declarations, lowest first. Sibling layers rank in the order they were
+/// first declared and a layer's own rules rank above its sub-layers.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct LayerOrder(Vec<Vec<String>>);
+
+impl LayerOrder {
+    /// Declare layer `name`, along with its parent layers, if it is new.
+    pub fn declare(&mut self, name: &[String]) {
+        for len in 1..=name.len() {
+            let (layer, parent) = (&name[..len], &name[..len - 1]);
+            if self.0.iter().any(|known| known == layer) {
+                continue;
+            }
+            // Parents come after their sub-layers.
+            let position = self
+                .0
+                .iter()
+                .position(|known| !parent.is_empty() && known == parent)
+                .unwrap_or(self.0.len());
+            self.0.insert(position, layer.to_vec());
+        }
+    }
+
+    /// How `name` ranks for normal declarations; higher wins. Unlayered
+    /// rules, with an empty name, rank above every layer.
+    pub fn rank(&self, name: &[String]) -> usize {
+        self.0
+            .iter()
+            .position(|known| known == name)
+            .unwrap_or(self.0.len())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &[String]> {
+        self.0.iter().map(Vec::as_slice)
+    }
+}
+
+/// Where a style sheet comes from, which decides its weight in the cascade.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Origin {
+    UserAgent,
+    User,
+    Author,
+}
+
+impl Origin {
+    /// Precedence of a declaration from this origin. Important declarations
+    /// beat normal ones and reverse the order of the origins.
+    pub fn cascade_rank(self, important: bool) -> u8 {
+        match (important, self) {
+            (false, Origin::UserAgent) => 0,
+            (false, Origin::User) => 1,
+            (false, Origin::Author) => 2,
+            (true, Origin::Author) => 3,
+            (true, Origin::User) => 4,
+            (true, Origin::UserAgent) => 5,
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Origin::UserAgent => "user-agent",
+            Origin::User => "user",
+            Origin::Author => "author",
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    /// The conditional at-rules the rule is nested in, outermost first. The
+    /// rule only applies while all of them hold.
+    pub conditions: Vec<Condition>,
+    /// The cascade layer, such as `["components", "buttons"]` for
+    /// `components.buttons`, or empty when unlayered. Anonymous layers get
+    /// names like `<anonymous-1>` that can't clash with an identifier.
+    pub layer: Vec<String>,
+    /// Where the rule was written, from its selectors to its `}`, in the
+    /// sheet it came from.
+    pub span: Span,
+}
+
+// Where a rule was written doesn't change what it means.
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.selectors == other.selectors
+            && self.declarations == other.declarations
+            && self.conditions == other.conditions
+            && self.layer == other.layer
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+    /// `@media <queries> { ... }`
+    Media(MediaQueryList),
+    /// `@supports <condition> { ... }`
+    Supports(SupportsCondition),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SupportsCondition {
+    /// `(name: value)`, with the value kept as tokens.
+    Declaration(String, Vec<Token>),
+    /// `selector(...)`, with the selector kept as tokens.
+    Selector(Vec<Token>),
+    Not(Box<SupportsCondition>),
+    And(Vec<SupportsCondition>),
+    Or(Vec<SupportsCondition>),
+    /// A term this parser doesn't know, including its parentheses or
+    /// function name. It never holds.
+    Unknown(Vec<Token>),
+}
+
+impl SupportsCondition {
+    /// Parse the prelude of an `@supports` rule. A prelude that doesn't
+    /// parse becomes `Unknown`, so the rule never applies.
+    pub fn parse(tokens: &[Token]) -> SupportsCondition {
+        let mut parser = ConditionParser { tokens, pos: 0 };
+        match parser.parse_supports_condition() {
+            Some(condition) if parser.at_end() => condition,
+            _ => SupportsCondition::Unknown(trim_tokens(tokens).to_vec()),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Comma-separated media queries, which hold when any one of them does.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaQueryList(pub Vec<MediaQuery>);
+
+/// `[not | only] <media-type> [and <condition>]`, or a bare condition.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaQuery {
+    pub negated: bool,
+    /// Lowercased, and `all` when the query doesn't name one.
+    pub media_type: String,
+    pub condition: Option<MediaCondition>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MediaCondition {
+    Feature(MediaFeature),
+    Not(Box<MediaCondition>),
+    And(Vec<MediaCondition>),
+    Or(Vec<MediaCondition>),
+    /// Parenthesized tokens that aren't a condition or feature this parser
+    /// knows. They never match.
+    Unknown(Vec<Token>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MediaFeature {
+    /// `(name)`: whether the feature has a value other than zero or `none`.
+    Boolean(String),
+    /// `(name: value)`
+    Plain(String, Value),
+    /// `(name >= value)`, `(value < name <= value)` and so on. `min-` and
+    /// `max-` features are parsed as ranges too.
+    Range {
+        name: String,
+        lower: Option<MediaBound>,
+        upper: Option<MediaBound>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaBound {
+    pub value: Value,
+    pub inclusive: bool,
+}
+
+impl MediaQueryList {
+    /// Parse the prelude of an `@media` rule. A query that doesn't parse
+    /// becomes `not all`, so it never matches but leaves the others intact.
+    pub fn parse(tokens: &[Token]) -> MediaQueryList {
+        let mut queries = Vec::new();
+        let mut start = 0;
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                Token::Function(_) | Token::OpenParen => depth += 1,
+                Token::CloseParen => depth -= 1,
+                Token::Comma if depth == 0 => {
+                    queries.push(MediaQuery::parse(&tokens[start..i]));
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if start > 0 || tokens.iter().any(|token| *token != Token::Whitespace) {
+            queries.push(MediaQuery::parse(&tokens[start..]));
+        }
+        MediaQueryList(queries)
+    }
+}
+
+impl MediaQuery {
+    fn parse(tokens: &[Token]) -> MediaQuery {
+        let mut parser = ConditionParser { tokens, pos: 0 };
+        parser.parse_query().unwrap_or(MediaQuery {
+            negated: true,
+            media_type: "all".to_string(),
+            condition: None,
+        })
+    }
+}
+
+/// A recursive descent parser over the tokens of a media query or an
+/// `@supports` condition.
+struct ConditionParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+impl Comparison {
+    /// The comparison with its operands swapped: `a < b` is `b > a`.
+    fn flip(self) -> Comparison {
+        match self {
+            Comparison::Less => Comparison::Greater,
+            Comparison::LessOrEqual => Comparison::GreaterOrEqual,
+            Comparison::Greater => Comparison::Less,
+            Comparison::GreaterOrEqual => Comparison::LessOrEqual,
+            Comparison::Equal => Comparison::Equal,
+        }
+    }
+
+    /// The bounds of `name <comparison> value`, as `(lower, upper)`.
+    fn bounds(self, value: Value) -> (Option<MediaBound>, Option<MediaBound>) {
+        let bound = |inclusive| {
+            Some(MediaBound {
+                value: value.clone(),
+                inclusive,
+            })
+        };
+        match self {
+            Comparison::Less => (None, bound(false)),
+            Comparison::LessOrEqual => (None, bound(true)),
+            Comparison::Greater => (bound(false), None),
+            Comparison::GreaterOrEqual => (bound(true), None),
+            Comparison::Equal => (bound(true), bound(true)),
+        }
+    }
+}
+
+impl ConditionParser<'_> {
+    fn next(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.next() == Some(&Token::Whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.pos == self.tokens.len()
+    }
+
+    /// Consume the identifier `keyword`, ignoring case, if it comes next.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        match self.next() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_query(&mut self) -> Option<MediaQuery> {
+        self.skip_whitespace();
+        // `not (...)` negates a condition, `not screen` a whole query.
+        let starts_with_type = match self.next() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("not") => {
+                let after = trim_tokens(&self.tokens[self.pos + 1..]).first();
+                after != Some(&Token::OpenParen)
+            }
+            Some(Token::Ident(_)) => true,
+            _ => false,
+        };
+        if !starts_with_type {
+            let condition = self.parse_condition(true)?;
+            return self.at_end().then(|| MediaQuery {
+                negated: false,
+                media_type: "all".to_string(),
+                condition: Some(condition),
+            });
+        }
+
+        let negated = self.keyword("not");
+        if !negated {
+            self.keyword("only");
+        }
+        self.skip_whitespace();
+        let media_type = match self.next()? {
+            Token::Ident(ident) => ident.to_ascii_lowercase(),
+            _ => return None,
+        };
+        if matches!(&*media_type, "not" | "only" | "and" | "or") {
+            return None;
+        }
+        self.pos += 1;
+        let condition = if self.keyword("and") {
+            Some(self.parse_condition(false)?)
+        } else {
+            None
+        };
+        self.at_end().then_some(MediaQuery {
+            negated,
+            media_type,
+            condition,
+        })
+    }
+
+    /// `not <in-parens>`, or `<in-parens>` joined by `and` or, when
+    /// `allow_or` is set, by `or`. The two can't be mixed without
+    /// parentheses.
+    fn parse_condition(&mut self, allow_or: bool) -> Option<MediaCondition> {
+        if self.keyword("not") {
+            return Some(MediaCondition::Not(Box::new(self.parse_in_parens()?)));
+        }
+        let mut conditions = vec![self.parse_in_parens()?];
+        let mut joiner = None;
+        loop {
+            let start = self.pos;
+            let next = if self.keyword("and") {
+                "and"
+            } else if allow_or && self.keyword("or") {
+                "or"
+            } else {
+                self.pos = start;
+                break;
+            };
+            if joiner.get_or_insert(next) != &next {
+                return None;
+            }
+            conditions.push(self.parse_in_parens()?);
+        }
+        Some(match joiner {
+            None => conditions.pop()?,
+            Some("and") => MediaCondition::And(conditions),
+            Some(_) => MediaCondition::Or(conditions),
+        })
+    }
+
+    fn parse_in_parens(&mut self) -> Option<MediaCondition> {
+        self.skip_whitespace();
+        if self.next() != Some(&Token::OpenParen) {
+            return None;
+        }
+        let end = function_end(self.tokens, self.pos)?;
+        let inner = &self.tokens[self.pos + 1..end - 1];
+        self.pos = end;
+
+        let mut nested = ConditionParser {
+            tokens: inner,
+            pos: 0,
+        };
+        if let Some(condition) = nested.parse_condition(true) {
+            if nested.at_end() {
+                return Some(condition);
+            }
+        }
+        Some(match parse_media_feature(inner) {
+            Some(feature) => MediaCondition::Feature(feature),
+            None => MediaCondition::Unknown(inner.to_vec()),
+        })
+    }
+}
+
+impl ConditionParser<'_> {
+    /// Like `parse_condition`, for `@supports`, where `and` and `or` can
+    /// always appear.
+    fn parse_supports_condition(&mut self) -> Option<SupportsCondition> {
+        if self.keyword("not") {
+            return Some(SupportsCondition::Not(Box::new(
+                self.parse_supports_in_parens()?,
+            )));
+        }
+        let mut conditions = vec![self.parse_supports_in_parens()?];
+        let mut joiner = None;
+        loop {
+            let start = self.pos;
+            let next = if self.keyword("and") {
+                "and"
+            } else if self.keyword("or") {
+                "or"
+            } else {
+                self.pos = start;
+                break;
+            };
+            if joiner.get_or_insert(next) != &next {
+                return None;
+            }
+            conditions.push(self.parse_supports_in_parens()?);
+        }
+        Some(match joiner {
+            None => conditions.pop()?,
+            Some("and") => SupportsCondition::And(conditions),
+            Some(_) => SupportsCondition::Or(conditions),
+        })
+    }
+
+    fn parse_supports_in_parens(&mut self) -> Option<SupportsCondition> {
+        self.skip_whitespace();
+        let is_selector = match self.next()? {
+            Token::OpenParen => false,
+            Token::Function(name) => name.eq_ignore_ascii_case("selector"),
+            _ => return None,
+        };
+        let start = self.pos;
+        let end = function_end(self.tokens, start)?;
+        let inner = &self.tokens[start + 1..end - 1];
+        self.pos = end;
+
+        if is_selector {
+            return Some(SupportsCondition::Selector(trim_tokens(inner).to_vec()));
+        }
+        if self.tokens[start] == Token::OpenParen {
+            let mut nested = ConditionParser {
+                tokens: inner,
+                pos: 0,
+            };
+            if let Some(condition) = nested.parse_supports_condition() {
+                if nested.at_end() {
+                    return Some(condition);
+                }
+            }
+            if let [Token::Ident(name), rest @ ..] = trim_tokens(inner) {
+                if let [Token::Delim(':'), value @ ..] = trim_tokens(rest) {
+                    return Some(SupportsCondition::Declaration(
+                        name.to_ascii_lowercase(),
+                        trim_tokens(value).to_vec(),
+                    ));
+                }
+            }
+        }
+        Some(SupportsCondition::Unknown(self.tokens[start..end].to_vec()))
+    }
+}
+
+/// Parse the inside of `(width >= 600px)`, `(min-width: 600px)`,
+/// `(orientation)` and the like.
+fn parse_media_feature(tokens: &[Token]) -> Option<MediaFeature> {
+    let tokens = trim_tokens(tokens);
+    let mut operands = Vec::new();
+    let mut comparisons = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let comparison = match (&tokens[i], tokens.get(i + 1)) {
+            (Token::Delim('<'), Some(Token::Delim('='))) => Comparison::LessOrEqual,
+            (Token::Delim('>'), Some(Token::Delim('='))) => Comparison::GreaterOrEqual,
+            (Token::Delim('<'), _) => Comparison::Less,
+            (Token::Delim('>'), _) => Comparison::Greater,
+            (Token::Delim('='), _) => Comparison::Equal,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        operands.push(trim_tokens(&tokens[start..i]));
+        comparisons.push(comparison);
+        i += if matches!(
+            comparison,
+            Comparison::LessOrEqual | Comparison::GreaterOrEqual
+        ) {
+            2
+        } else {
+            1
+        };
+        start = i;
+    }
+    operands.push(trim_tokens(&tokens[start..]));
+
+    let feature_name = |tokens: &[Token]| match tokens {
+        [Token::Ident(name)] => Some(name.to_ascii_lowercase()),
+        _ => None,
+    };
+    match (&*operands, &*comparisons) {
+        ([operand], []) => match operand {
+            [Token::Ident(name)] => Some(MediaFeature::Boolean(name.to_ascii_lowercase())),
+            [Token::Ident(name), rest @ ..] => {
+                let rest = trim_tokens(rest);
+                if rest.first() != Some(&Token::Delim(':')) {
+                    return None;
+                }
+                let value = Value::from_tokens(&rest[1..])?;
+                let name = name.to_ascii_lowercase();
+                let range = |name: &str, comparison: Comparison| {
+                    let (lower, upper) = comparison.bounds(value.clone());
+                    MediaFeature::Range {
+                        name: name.to_string(),
+                        lower,
+                        upper,
+                    }
+                };
+                Some(if let Some(name) = name.strip_prefix("min-") {
+                    range(name, Comparison::GreaterOrEqual)
+                } else if let Some(name) = name.strip_prefix("max-") {
+                    range(name, Comparison::LessOrEqual)
+                } else {
+                    MediaFeature::Plain(name, value)
+                })
+            }
+            _ => None,
+        },
+        ([left, right], [comparison]) => {
+            let (name, comparison, value) = match feature_name(left) {
+                Some(name) => (name, *comparison, right),
+                None => (feature_name(right)?, comparison.flip(), left),
+            };
+            let (lower, upper) = comparison.bounds(Value::from_tokens(value)?);
+            Some(MediaFeature::Range { name, lower, upper })
+        }
+        ([low, name, high], [first, second]) => {
+            let name = feature_name(name)?;
+            if *first == Comparison::Equal || *second == Comparison::Equal {
+                return None;
+            }
+            let (lower_1, upper_1) = first.flip().bounds(Value::from_tokens(low)?);
+            let (lower_2, upper_2) = second.bounds(Value::from_tokens(high)?);
+            match (lower_1, upper_1, lower_2, upper_2) {
+                (Some(lower), None, None, Some(upper)) | (None, Some(upper), Some(lower), None) => {
+                    Some(MediaFeature::Range {
+                        name,
+                        lower: Some(lower),
+                        upper: Some(upper),
+                    })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A dotted layer name such as `components.buttons`.
+fn parse_layer_name(tokens: &[Token]) -> Option<Vec<String>> {
+    let tokens = trim_tokens(tokens);
+    let mut name = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Ident(ident) if i % 2 == 0 => name.push(ident.clone()),
+            Token::Delim('.') if i % 2 == 1 => {}
+            _ => return None,
+        }
+    }
+    (tokens.len() % 2 == 1).then_some(name)
+}
+
+fn trim_tokens(tokens: &[Token]) -> &[Token] {
+    let start = tokens
+        .iter()
+        .position(|token| *token != Token::Whitespace)
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|token| *token != Token::Whitespace)
+        .map_or(start, |i| i + 1);
+    &tokens[start..end]
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Selector {
     Simple(SimpleSelector),
+    /// Compound selectors joined by combinators, such as `#cart > li .price`.
+    /// The last compound is the element being matched; each combinator says
+    /// how it relates to the compound before it.
+    Complex(SimpleSelector, Vec<(Combinator, SimpleSelector)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combinator {
+    /// `a b`: anywhere below `a`.
+    Descendant,
+    /// `a > b`: a child of `a`.
+    Child,
+    /// `a + b`: the element right after `a`.
+    NextSibling,
+    /// `a ~ b`: any element after `a`.
+    SubsequentSibling,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SimpleSelector {
-    pub tag_name: Option<String>,
-    pub id: Option<String>,
-    pub class: Vec<String>,
+    /// Unset when the selector has no prefix and its sheet no default
+    /// namespace, so that it matches elements in any namespace.
+    pub namespace: Option<NamespaceConstraint>,
+    pub tag_name: Option<Atom>,
+    pub id: Option<Atom>,
+    pub class: Vec<Atom>,
+    pub attributes: Vec<AttributeSelector>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// The namespace part of a compound selector, written as `prefix|`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NamespaceConstraint {
+    /// `*|`: elements in any namespace.
+    Any,
+    /// `|`: elements in no namespace.
+    NoNamespace,
+    /// Elements in namespace `url`, from `prefix|` or, when `prefix` is
+    /// `None`, from the default namespace.
+    Url { prefix: Option<Atom>, url: Atom },
+}
+
+/// `[name]`, or `[name=value]` when `value` is set.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AttributeSelector {
+    pub name: Atom,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Declaration {
     pub name: String,
     pub value: Value,
+    pub important: bool,
+    pub span: Span,
+}
+
+// Where a declaration was written doesn't change what it means.
+impl PartialEq for Declaration {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.value == other.value && self.important == other.important
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -31,20 +704,919 @@ pub enum Value {
     Keyword(String),
     Length(f32, Unit),
     Color(Color),
+    /// A math function: `calc()`, `min()`, `max()` or `clamp()`.
+    Calc(CalcNode),
+    /// Several space-separated component values, such as `inline flex`.
+    List(Vec<Value>),
+    /// Tokens kept as written: the value of a custom property, or a value
+    /// using `var()` that can only be parsed once the variables are known.
+    Unparsed(Vec<Token>),
+}
+
+/// A CSS token, as preserved in `Value::Unparsed`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token {
+    Ident(String),
+    /// A function name, including its opening parenthesis.
+    Function(String),
+    Number(f32),
+    Percentage(f32),
+    Dimension(f32, String),
+    Hash(String),
+    String(String),
+    OpenParen,
+    CloseParen,
+    Comma,
+    Delim(char),
+    Whitespace,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => f.write_str(ident),
+            Token::Function(name) => write!(f, "{}(", name),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Percentage(number) => write!(f, "{}%", number),
+            Token::Dimension(number, unit) => write!(f, "{}{}", number, unit),
+            Token::Hash(hash) => write!(f, "#{}", hash),
+            Token::String(string) => write_string(f, string),
+            Token::OpenParen => f.write_str("("),
+            Token::CloseParen => f.write_str(")"),
+            Token::Comma => f.write_str(","),
+            Token::Delim(char) => write!(f, "{}", char),
+            Token::Whitespace => f.write_str(" "),
+        }
+    }
+}
+
+/// Quote a string with whichever quote it doesn't contain. The tokenizer
+/// doesn't read escapes, so a string with both kinds can't be written.
+fn write_string(f: &mut fmt::Formatter, string: &str) -> fmt::Result {
+    let quote = if string.contains('"') { '\'' } else { '"' };
+    write!(f, "{}{}{}", quote, string, quote)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Keyword(keyword) => f.write_str(keyword),
+            Value::Length(length, unit) => write!(f, "{}{}", length, unit),
+            Value::Calc(node @ (CalcNode::Min(_) | CalcNode::Max(_) | CalcNode::Clamp(..))) => {
+                write!(f, "{}", node)
+            }
+            Value::Calc(node) => write!(f, "calc({})", node),
+            Value::Color(color) => write!(f, "{}", color),
+            Value::List(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                Ok(())
+            }
+            Value::Unparsed(tokens) => {
+                for token in tokens {
+                    write!(f, "{}", token)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How `StyleSheet::to_css` lays out its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One declaration per line, indented two spaces per block.
+    Pretty,
+    /// Without whitespace the parser doesn't need.
+    Minified,
+}
+
+impl StyleSheet {
+    /// Write the style sheet out as CSS that parses back to an equal sheet.
+    ///
+    /// Parsing flattens at-rules into each rule's `layer` and `conditions`,
+    /// so the blocks are rebuilt around the rules: layers outermost, then
+    /// conditions in order, with consecutive rules sharing blocks. Named
+    /// layers are declared up front to keep their order. An anonymous layer
+    /// becomes a single `@layer` block, which round-trips when its rules are
+    /// contiguous and it comes after the named layers, as is the case for
+    /// sheets without imports.
+    pub fn to_css(&self, format: Format) -> String {
+        let mut writer = CssWriter {
+            format,
+            output: String::new(),
+            depth: 0,
+        };
+        if let Some(url) = &self.namespaces.default {
+            writer.statement(&format!("@namespace {}", Token::String(url.to_string())));
+        }
+        for (prefix, url) in &self.namespaces.prefixes {
+            writer.statement(&format!(
+                "@namespace {} {}",
+                prefix,
+                Token::String(url.to_string())
+            ));
+        }
+        let named: Vec<String> = self
+            .layers
+            .names()
+            .filter(|name| !name.iter().any(|part| is_anonymous_layer(part)))
+            .map(|name| name.join("."))
+            .collect();
+        if !named.is_empty() {
+            let separator = match format {
+                Format::Pretty => ", ",
+                Format::Minified => ",",
+            };
+            writer.statement(&format!("@layer {}", named.join(separator)));
+        }
+
+        // Open blocks as (key, header); a layer's key is its full name so
+        // that distinct anonymous layers never share a block.
+        let mut open: Vec<(String, String)> = Vec::new();
+        for rule in &self.rules {
+            let blocks: Vec<(String, String)> = (1..=rule.layer.len())
+                .map(|len| {
+                    let part = &rule.layer[len - 1];
+                    let header = if is_anonymous_layer(part) {
+                        "@layer".to_string()
+                    } else {
+                        format!("@layer {}", part)
+                    };
+                    (format!("@layer {}", rule.layer[..len].join(".")), header)
+                })
+                .chain(rule.conditions.iter().map(|condition| {
+                    let header = condition.to_string();
+                    (header.clone(), header)
+                }))
+                .collect();
+            let shared = open
+                .iter()
+                .zip(&blocks)
+                .take_while(|(open, block)| open == block)
+                .count();
+            while open.len() > shared {
+                open.pop();
+                writer.close();
+            }
+            for block in &blocks[shared..] {
+                writer.open(&block.1);
+                open.push(block.clone());
+            }
+            writer.rule(rule);
+        }
+        for _ in open {
+            writer.close();
+        }
+        writer.output
+    }
+}
+
+impl Rule {
+    /// The rule on its own, without the at-rules it is nested in.
+    pub fn to_css(&self, format: Format) -> String {
+        let mut writer = CssWriter {
+            format,
+            output: String::new(),
+            depth: 0,
+        };
+        writer.rule(self);
+        writer.output
+    }
+}
+
+/// A value written as briefly as possible while parsing to the same value:
+/// `0` for `0px`, `.5em` for `0.5em` and `#f00` for `#ff0000`.
+fn minified_value(value: &Value) -> String {
+    match *value {
+        Value::Length(0.0, Unit::Px) => "0".to_string(),
+        Value::Length(length, unit) => {
+            let number = length.to_string();
+            let number = match number.strip_prefix("0.") {
+                Some(fraction) => format!(".{}", fraction),
+                None => number.replacen("-0.", "-.", 1),
+            };
+            format!("{}{}", number, unit)
+        }
+        Value::Color(color) => {
+            let mut channels = vec![color.r, color.g, color.b];
+            if color.a != 255 {
+                channels.push(color.a);
+            }
+            let short = channels.iter().all(|channel| channel % 17 == 0);
+            let digits: String = channels
+                .iter()
+                .map(|&channel| match short {
+                    true => format!("{:x}", channel / 17),
+                    false => format!("{:02x}", channel),
+                })
+                .collect();
+            format!("#{}", digits)
+        }
+        Value::List(ref values) => values
+            .iter()
+            .map(minified_value)
+            .collect::<Vec<_>>()
+            .join(" "),
+        ref value => value.to_string(),
+    }
+}
+
+fn is_anonymous_layer(name: &str) -> bool {
+    name.starts_with("<anonymous-")
+}
+
+struct CssWriter {
+    format: Format,
+    output: String,
+    depth: usize,
+}
+
+impl CssWriter {
+    /// Start a line for a new statement, block or rule.
+    fn start(&mut self) {
+        if self.format == Format::Pretty {
+            if self.depth == 0 && !self.output.is_empty() {
+                self.output.push('\n');
+            }
+            self.indent(self.depth);
+        }
+    }
+
+    fn indent(&mut self, depth: usize) {
+        self.output.push_str(&"  ".repeat(depth));
+    }
+
+    fn end_line(&mut self) {
+        if self.format == Format::Pretty {
+            self.output.push('\n');
+        }
+    }
+
+    fn statement(&mut self, text: &str) {
+        self.start();
+        self.output.push_str(text);
+        self.output.push(';');
+        self.end_line();
+    }
+
+    fn open(&mut self, header: &str) {
+        self.start();
+        self.output.push_str(header);
+        if self.format == Format::Pretty {
+            self.output.push(' ');
+        }
+        self.output.push('{');
+        self.end_line();
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        if self.format == Format::Pretty {
+            self.indent(self.depth);
+        }
+        self.output.push('}');
+        self.end_line();
+    }
+
+    fn rule(&mut self, rule: &Rule) {
+        let pretty = self.format == Format::Pretty;
+        self.start();
+        let selectors: Vec<String> = rule.selectors.iter().map(Selector::to_string).collect();
+        self.output
+            .push_str(&selectors.join(if pretty { ", " } else { "," }));
+        self.output.push_str(if pretty { " {\n" } else { "{" });
+        for (i, declaration) in rule.declarations.iter().enumerate() {
+            if pretty {
+                self.indent(self.depth + 1);
+            } else if i > 0 {
+                self.output.push(';');
+            }
+            self.output.push_str(&declaration.name);
+            self.output.push_str(if pretty { ": " } else { ":" });
+            let value = match self.format {
+                Format::Pretty => declaration.value.to_string(),
+                Format::Minified => minified_value(&declaration.value),
+            };
+            self.output.push_str(&value);
+            if declaration.important {
+                self.output
+                    .push_str(if pretty { " !important" } else { "!important" });
+            }
+            if pretty {
+                self.output.push_str(";\n");
+            }
+        }
+        if pretty {
+            self.indent(self.depth);
+        }
+        self.output.push('}');
+        self.end_line();
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Media(queries) if queries.0.is_empty() => f.write_str("@media"),
+            Condition::Media(queries) => write!(f, "@media {}", queries),
+            Condition::Supports(condition) => write!(f, "@supports {}", condition),
+        }
+    }
+}
+
+impl fmt::Display for MediaQueryList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, query) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.negated, &*self.media_type, &self.condition) {
+            (false, "all", Some(condition)) => return write!(f, "{}", condition),
+            (true, _, _) => f.write_str("not ")?,
+            _ => {}
+        }
+        f.write_str(&self.media_type)?;
+        match self.condition {
+            // `or` needs parentheses after `and`.
+            Some(ref condition @ MediaCondition::Or(_)) => write!(f, " and ({})", condition),
+            Some(ref condition) => write!(f, " and {}", condition),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for MediaCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let in_parens = |f: &mut fmt::Formatter, condition: &MediaCondition| match condition {
+            MediaCondition::Not(_) | MediaCondition::And(_) | MediaCondition::Or(_) => {
+                write!(f, "({})", condition)
+            }
+            _ => write!(f, "{}", condition),
+        };
+        let join = |f: &mut fmt::Formatter, joiner: &str, conditions: &[MediaCondition]| {
+            for (i, condition) in conditions.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", joiner)?;
+                }
+                in_parens(f, condition)?;
+            }
+            Ok(())
+        };
+        match self {
+            MediaCondition::Feature(feature) => write!(f, "({})", feature),
+            MediaCondition::Not(condition) => {
+                f.write_str("not ")?;
+                in_parens(f, condition)
+            }
+            MediaCondition::And(conditions) => join(f, "and", conditions),
+            MediaCondition::Or(conditions) => join(f, "or", conditions),
+            MediaCondition::Unknown(tokens) => {
+                f.write_str("(")?;
+                for token in tokens {
+                    write!(f, "{}", token)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for MediaFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let comparison = |bound: &MediaBound, less: bool| match (less, bound.inclusive) {
+            (true, true) => "<=",
+            (true, false) => "<",
+            (false, true) => ">=",
+            (false, false) => ">",
+        };
+        match self {
+            MediaFeature::Boolean(name) => f.write_str(name),
+            MediaFeature::Plain(name, value) => write!(f, "{}: {}", name, value),
+            MediaFeature::Range {
+                name,
+                lower: Some(lower),
+                upper: Some(upper),
+            } if lower == upper && lower.inclusive => write!(f, "{} = {}", name, lower.value),
+            MediaFeature::Range {
+                name,
+                lower: Some(lower),
+                upper: Some(upper),
+            } => write!(
+                f,
+                "{} {} {} {} {}",
+                lower.value,
+                comparison(lower, true),
+                name,
+                comparison(upper, true),
+                upper.value
+            ),
+            MediaFeature::Range {
+                name,
+                lower: Some(bound),
+                upper: None,
+            } => write!(f, "{} {} {}", name, comparison(bound, false), bound.value),
+            MediaFeature::Range {
+                name,
+                lower: None,
+                upper: Some(bound),
+            } => write!(f, "{} {} {}", name, comparison(bound, true), bound.value),
+            MediaFeature::Range { name, .. } => f.write_str(name),
+        }
+    }
+}
+
+impl fmt::Display for SupportsCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens = |f: &mut fmt::Formatter, tokens: &[Token]| {
+            for token in tokens {
+                write!(f, "{}", token)?;
+            }
+            Ok(())
+        };
+        let in_parens = |f: &mut fmt::Formatter, condition: &SupportsCondition| match condition {
+            SupportsCondition::Not(_) | SupportsCondition::And(_) | SupportsCondition::Or(_) => {
+                write!(f, "({})", condition)
+            }
+            _ => write!(f, "{}", condition),
+        };
+        let join = |f: &mut fmt::Formatter, joiner: &str, conditions: &[SupportsCondition]| {
+            for (i, condition) in conditions.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", joiner)?;
+                }
+                in_parens(f, condition)?;
+            }
+            Ok(())
+        };
+        match self {
+            SupportsCondition::Declaration(name, value) => {
+                write!(f, "({}: ", name)?;
+                tokens(f, value)?;
+                f.write_str(")")
+            }
+            SupportsCondition::Selector(selector) => {
+                f.write_str("selector(")?;
+                tokens(f, selector)?;
+                f.write_str(")")
+            }
+            SupportsCondition::Not(condition) => {
+                f.write_str("not ")?;
+                in_parens(f, condition)
+            }
+            SupportsCondition::And(conditions) => join(f, "and", conditions),
+            SupportsCondition::Or(conditions) => join(f, "or", conditions),
+            SupportsCondition::Unknown(unknown) => tokens(f, unknown),
+        }
+    }
 }
 
 impl Value {
+    /// Parse a value from tokens, as needed after `var()` substitution.
+    /// Returns `None` when the tokens don't form a value.
+    pub fn from_tokens(tokens: &[Token]) -> Option<Value> {
+        let mut values = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let value = match tokens[i] {
+                Token::Whitespace => {
+                    i += 1;
+                    continue;
+                }
+                Token::Ident(ref keyword) => Value::Keyword(keyword.clone()),
+                Token::Number(number) => Value::Length(number, Unit::Px),
+                Token::Percentage(number) => Value::Length(number, Unit::Percent),
+                Token::Dimension(number, ref unit) => Value::Length(number, Unit::parse(unit)?),
+                Token::Hash(ref hash) => Value::Color(parse_hex_color(hash)?),
+                Token::Function(_) => {
+                    let end = function_end(tokens, i)?;
+                    values.push(Value::Calc(CalcNode::parse_function(&tokens[i..end])?));
+                    i = end;
+                    continue;
+                }
+                _ => return None,
+            };
+            values.push(value);
+            i += 1;
+        }
+        match values.len() {
+            0 => None,
+            1 => values.pop(),
+            _ => Some(Value::List(values)),
+        }
+    }
+
     pub fn to_px(&self) -> f32 {
         match *self {
             Value::Length(f, Unit::Px) => f,
             _ => 0.0,
         }
     }
+
+    /// The length in px once relative units and math functions are
+    /// resolved, or `None` for a non-length or an unresolvable percentage.
+    pub fn resolve(&self, context: &LengthContext) -> Option<f32> {
+        match *self {
+            Value::Length(length, unit) => context.length_px(length, unit),
+            Value::Calc(ref node) => node.resolve(context),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Unit {
+    Px,
+    Em,
+    Rem,
+    Percent,
+}
+
+impl Unit {
+    /// The unit named by a dimension suffix. A bare number counts as px.
+    pub fn parse(name: &str) -> Option<Unit> {
+        match &*name.to_ascii_lowercase() {
+            "" | "px" => Some(Unit::Px),
+            "em" => Some(Unit::Em),
+            "rem" => Some(Unit::Rem),
+            "%" => Some(Unit::Percent),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Unit::Px => "px",
+            Unit::Em => "em",
+            Unit::Rem => "rem",
+            Unit::Percent => "%",
+        })
+    }
+}
+
+/// The initial `font-size`, which `rem` and `em` fall back to.
+pub const DEFAULT_FONT_SIZE: f32 = 16.0;
+
+/// What relative lengths are resolved against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LengthContext {
+    /// The length percentages refer to, if it is known.
+    pub percent_base: Option<f32>,
+    pub font_size: f32,
+    pub root_font_size: f32,
+}
+
+impl Default for LengthContext {
+    fn default() -> LengthContext {
+        LengthContext {
+            percent_base: None,
+            font_size: DEFAULT_FONT_SIZE,
+            root_font_size: DEFAULT_FONT_SIZE,
+        }
+    }
+}
+
+impl LengthContext {
+    fn length_px(&self, length: f32, unit: Unit) -> Option<f32> {
+        match unit {
+            Unit::Px => Some(length),
+            Unit::Em => Some(length * self.font_size),
+            Unit::Rem => Some(length * self.root_font_size),
+            Unit::Percent => self.percent_base.map(|base| length * base / 100.0),
+        }
+    }
+}
+
+/// A node of a math function's expression tree. `a - b` is stored as
+/// `Sum([a, Negate(b)])` and `a / b` as `Product([a, Invert(b)])`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CalcNode {
+    Number(f32),
+    Length(f32, Unit),
+    Sum(Vec<CalcNode>),
+    Negate(Box<CalcNode>),
+    Product(Vec<CalcNode>),
+    Invert(Box<CalcNode>),
+    Min(Vec<CalcNode>),
+    Max(Vec<CalcNode>),
+    Clamp(Box<CalcNode>, Box<CalcNode>, Box<CalcNode>),
+}
+
+/// What a math expression evaluates to. Percentages count as lengths.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CalcType {
+    Number,
+    Length,
+}
+
+impl CalcNode {
+    /// Parse the tokens of a `calc()`, `min()`, `max()` or `clamp()`
+    /// function, from its `Function` token to its closing parenthesis.
+    /// Returns `None` if the syntax or the types don't check out.
+    pub fn parse_function(tokens: &[Token]) -> Option<CalcNode> {
+        let mut parser = CalcParser { tokens, pos: 0 };
+        let node = parser.parse_function()?;
+        if parser.pos != tokens.len() {
+            return None;
+        }
+        node.calc_type()?;
+        Some(node)
+    }
+
+    pub fn calc_type(&self) -> Option<CalcType> {
+        let same_type = |nodes: &mut dyn Iterator<Item = &CalcNode>| {
+            let types = nodes.map(CalcNode::calc_type).collect::<Option<Vec<_>>>()?;
+            let first = *types.first()?;
+            types.iter().all(|t| *t == first).then_some(first)
+        };
+        match self {
+            CalcNode::Number(_) => Some(CalcType::Number),
+            CalcNode::Length(..) => Some(CalcType::Length),
+            CalcNode::Negate(node) => node.calc_type(),
+            CalcNode::Invert(node) => match node.calc_type()? {
+                CalcType::Number => Some(CalcType::Number),
+                // Dividing by a length would produce an unsupported type.
+                CalcType::Length => None,
+            },
+            CalcNode::Sum(nodes) | CalcNode::Min(nodes) | CalcNode::Max(nodes) => {
+                same_type(&mut nodes.iter())
+            }
+            CalcNode::Clamp(min, value, max) => {
+                same_type(&mut [&**min, &**value, &**max].into_iter())
+            }
+            CalcNode::Product(nodes) => {
+                let types = nodes
+                    .iter()
+                    .map(CalcNode::calc_type)
+                    .collect::<Option<Vec<_>>>()?;
+                match types.iter().filter(|t| **t == CalcType::Length).count() {
+                    0 => Some(CalcType::Number),
+                    1 => Some(CalcType::Length),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Evaluate the expression, or `None` if it uses a percentage and the
+    /// context has no percentage base.
+    pub fn resolve(&self, context: &LengthContext) -> Option<f32> {
+        let resolve_all = |nodes: &[CalcNode]| {
+            nodes
+                .iter()
+                .map(|node| node.resolve(context))
+                .collect::<Option<Vec<_>>>()
+        };
+        match self {
+            CalcNode::Number(number) => Some(*number),
+            CalcNode::Length(length, unit) => context.length_px(*length, *unit),
+            CalcNode::Sum(nodes) => Some(resolve_all(nodes)?.iter().sum()),
+            CalcNode::Negate(node) => Some(-node.resolve(context)?),
+            CalcNode::Product(nodes) => Some(resolve_all(nodes)?.iter().product()),
+            CalcNode::Invert(node) => Some(1.0 / node.resolve(context)?),
+            CalcNode::Min(nodes) => resolve_all(nodes)?.into_iter().reduce(f32::min),
+            CalcNode::Max(nodes) => resolve_all(nodes)?.into_iter().reduce(f32::max),
+            CalcNode::Clamp(min, value, max) => {
+                let min = min.resolve(context)?;
+                let value = value.resolve(context)?;
+                let max = max.resolve(context)?;
+                // When the bounds cross, the minimum wins.
+                Some(value.min(max).max(min))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CalcNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, name: &str, nodes: &[CalcNode]| {
+            write!(f, "{}(", name)?;
+            for (i, node) in nodes.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", node)?;
+            }
+            f.write_str(")")
+        };
+        match self {
+            CalcNode::Number(number) => write!(f, "{}", number),
+            CalcNode::Length(length, unit) => write!(f, "{}{}", length, unit),
+            CalcNode::Sum(nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    match (i, node) {
+                        (0, CalcNode::Negate(node)) => write!(f, "-1 * {}", Parenthesized(node))?,
+                        // A nested sum keeps its parentheses, so it reparses
+                        // as the same tree.
+                        (0, node @ CalcNode::Sum(_)) => write!(f, "{}", Parenthesized(node))?,
+                        (0, node) => write!(f, "{}", node)?,
+                        (_, CalcNode::Negate(node)) => write!(f, " - {}", Parenthesized(node))?,
+                        (_, node @ CalcNode::Sum(_)) => write!(f, " + {}", Parenthesized(node))?,
+                        (_, node) => write!(f, " + {}", node)?,
+                    }
+                }
+                Ok(())
+            }
+            CalcNode::Negate(node) => write!(f, "-1 * {}", Parenthesized(node)),
+            CalcNode::Product(nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    match (i, node) {
+                        (0, CalcNode::Invert(node)) => write!(f, "1 / {}", Parenthesized(node))?,
+                        (0, node) => write!(f, "{}", Parenthesized(node))?,
+                        (_, CalcNode::Invert(node)) => write!(f, " / {}", Parenthesized(node))?,
+                        (_, node) => write!(f, " * {}", Parenthesized(node))?,
+                    }
+                }
+                Ok(())
+            }
+            CalcNode::Invert(node) => write!(f, "1 / {}", Parenthesized(node)),
+            CalcNode::Min(nodes) => list(f, "min", nodes),
+            CalcNode::Max(nodes) => list(f, "max", nodes),
+            CalcNode::Clamp(min, value, max) => {
+                write!(f, "clamp({}, {}, {})", min, value, max)
+            }
+        }
+    }
+}
+
+/// Writes a node wrapped in parentheses when it is a sum or product.
+struct Parenthesized<'a>(&'a CalcNode);
+
+impl fmt::Display for Parenthesized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            node @ (CalcNode::Sum(_)
+            | CalcNode::Product(_)
+            | CalcNode::Negate(_)
+            | CalcNode::Invert(_)) => write!(f, "({})", node),
+            node => write!(f, "{}", node),
+        }
+    }
+}
+
+struct CalcParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl CalcParser<'_> {
+    fn next(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.next() == Some(&Token::Whitespace) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn expect(&mut self, token: Token) -> Option<()> {
+        self.skip_whitespace();
+        (self.next() == Some(&token)).then(|| self.pos += 1)
+    }
+
+    fn parse_function(&mut self) -> Option<CalcNode> {
+        let name = match self.next()? {
+            Token::Function(name) => name.to_ascii_lowercase(),
+            _ => return None,
+        };
+        self.pos += 1;
+        let mut arguments = vec![self.parse_sum()?];
+        self.skip_whitespace();
+        while self.next() == Some(&Token::Comma) {
+            self.pos += 1;
+            arguments.push(self.parse_sum()?);
+            self.skip_whitespace();
+        }
+        self.expect(Token::CloseParen)?;
+
+        match (&*name, arguments.len()) {
+            ("calc", 1) => arguments.pop(),
+            ("min", _) => Some(CalcNode::Min(arguments)),
+            ("max", _) => Some(CalcNode::Max(arguments)),
+            ("clamp", 3) => {
+                let max = arguments.pop()?;
+                let value = arguments.pop()?;
+                let min = arguments.pop()?;
+                Some(CalcNode::Clamp(
+                    Box::new(min),
+                    Box::new(value),
+                    Box::new(max),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_sum(&mut self) -> Option<CalcNode> {
+        let mut terms = vec![self.parse_product()?];
+        loop {
+            let start = self.pos;
+            // `+` and `-` must have whitespace on both sides.
+            let spaced = self.skip_whitespace();
+            let negate = match self.next() {
+                Some(Token::Delim('+')) => false,
+                Some(Token::Delim('-')) => true,
+                _ => {
+                    self.pos = start;
+                    break;
+                }
+            };
+            self.pos += 1;
+            if !spaced || !self.skip_whitespace() {
+                return None;
+            }
+            let term = self.parse_product()?;
+            terms.push(match negate {
+                true => CalcNode::Negate(Box::new(term)),
+                false => term,
+            });
+        }
+        Some(match terms.len() {
+            1 => terms.pop()?,
+            _ => CalcNode::Sum(terms),
+        })
+    }
+
+    fn parse_product(&mut self) -> Option<CalcNode> {
+        let mut factors = vec![self.parse_value()?];
+        loop {
+            let start = self.pos;
+            self.skip_whitespace();
+            let invert = match self.next() {
+                Some(Token::Delim('*')) => false,
+                Some(Token::Delim('/')) => true,
+                _ => {
+                    self.pos = start;
+                    break;
+                }
+            };
+            self.pos += 1;
+            let factor = self.parse_value()?;
+            factors.push(match invert {
+                true => CalcNode::Invert(Box::new(factor)),
+                false => factor,
+            });
+        }
+        Some(match factors.len() {
+            1 => factors.pop()?,
+            _ => CalcNode::Product(factors),
+        })
+    }
+
+    fn parse_value(&mut self) -> Option<CalcNode> {
+        self.skip_whitespace();
+        let node = match self.next()? {
+            Token::Number(number) => CalcNode::Number(*number),
+            Token::Percentage(number) => CalcNode::Length(*number, Unit::Percent),
+            Token::Dimension(number, unit) => CalcNode::Length(*number, Unit::parse(unit)?),
+            Token::Function(_) => return self.parse_function(),
+            Token::OpenParen => {
+                self.pos += 1;
+                let node = self.parse_sum()?;
+                self.expect(Token::CloseParen)?;
+                return Some(node);
+            }
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(node)
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum Unit {
-    Px,
+/// The index just past the `)` closing the function that starts at `start`.
+fn function_end(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::Function(_) | Token::OpenParen => depth += 1,
+            Token::CloseParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -55,24 +1627,342 @@ pub struct Color {
     pub a: u8,
 }
 
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selector::Simple(simple) => write!(f, "{}", simple),
+            Selector::Complex(first, rest) => {
+                write!(f, "{}", first)?;
+                for (combinator, compound) in rest {
+                    write!(f, "{}{}", combinator, compound)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Combinator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Combinator::Descendant => " ",
+            Combinator::Child => " > ",
+            Combinator::NextSibling => " + ",
+            Combinator::SubsequentSibling => " ~ ",
+        })
+    }
+}
+
+impl fmt::Display for SimpleSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let simple = self;
+        let prefix = match simple.namespace {
+            Some(NamespaceConstraint::Any) => Some("*"),
+            Some(NamespaceConstraint::NoNamespace) => Some(""),
+            Some(NamespaceConstraint::Url {
+                prefix: Some(ref prefix),
+                ..
+            }) => Some(prefix.as_str()),
+            _ => None,
+        };
+        if let Some(prefix) = prefix {
+            write!(f, "{}|", prefix)?;
+        }
+        match simple.tag_name {
+            Some(ref tag_name) => f.write_str(tag_name)?,
+            None if prefix.is_some() => f.write_str("*")?,
+            None => {}
+        }
+        if let Some(ref id) = simple.id {
+            write!(f, "#{}", id)?;
+        }
+        for class in &simple.class {
+            write!(f, ".{}", class)?;
+        }
+        for attribute in &simple.attributes {
+            match attribute.value {
+                Some(ref value) => {
+                    write!(f, "[{}=", attribute.name)?;
+                    write_string(f, value)?;
+                    f.write_str("]")?;
+                }
+                None => write!(f, "[{}]", attribute.name)?,
+            }
+        }
+        if prefix.is_none()
+            && simple.tag_name.is_none()
+            && simple.id.is_none()
+            && simple.class.is_empty()
+            && simple.attributes.is_empty()
+        {
+            f.write_str("*")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the digits of `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`.
+fn parse_hex_color(hex: &str) -> Option<Color> {
+    if !hex.chars().all(|char| char.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channels: Vec<u8> = match hex.len() {
+        3 | 4 => hex
+            .chars()
+            .map(|digit| u8::from_str_radix(&digit.to_string().repeat(2), 16).ok())
+            .collect::<Option<_>>()?,
+        6 | 8 => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(Color {
+        r: channels[0],
+        g: channels[1],
+        b: channels[2],
+        a: channels.get(3).copied().unwrap_or(255),
+    })
+}
+
 pub type Specificity = (usize, usize, usize);
 
 impl Selector {
+    /// Parse a selector from tokens, as written in `@supports selector()`.
+    /// Returns `None` for anything but the selectors the style sheet parser
+    /// accepts.
+    pub fn from_tokens(tokens: &[Token]) -> Option<Selector> {
+        let tokens = trim_tokens(tokens);
+        let mut first = None;
+        let mut rest = Vec::new();
+        let mut combinator = None;
+        let mut i = 0;
+        while i < tokens.len() {
+            let end = i + compound_length(&tokens[i..]);
+            let compound = SimpleSelector::from_tokens(&tokens[i..end])?;
+            match combinator.take() {
+                Some(combinator) => rest.push((combinator, compound)),
+                None => first = Some(compound),
+            }
+
+            i = end;
+            let mut explicit = None;
+            while let Some(token) = tokens.get(i) {
+                match token {
+                    Token::Whitespace => combinator = Some(Combinator::Descendant),
+                    Token::Delim(char @ ('>' | '+' | '~')) if explicit.is_none() => {
+                        explicit = Some(match char {
+                            '>' => Combinator::Child,
+                            '+' => Combinator::NextSibling,
+                            _ => Combinator::SubsequentSibling,
+                        })
+                    }
+                    _ => break,
+                }
+                i += 1;
+            }
+            combinator = explicit.or(combinator);
+        }
+        let first = first?;
+        if combinator.is_some() {
+            None
+        } else if rest.is_empty() {
+            Some(Selector::Simple(first))
+        } else {
+            Some(Selector::Complex(first, rest))
+        }
+    }
+
+    /// The compound selector the matched element itself must match.
+    pub fn subject(&self) -> &SimpleSelector {
+        match self {
+            Selector::Simple(simple) => simple,
+            Selector::Complex(first, rest) => rest.last().map_or(first, |(_, last)| last),
+        }
+    }
+
+    /// Every compound selector, from left to right.
+    pub fn compounds(&self) -> impl Iterator<Item = &SimpleSelector> {
+        let (first, rest) = match self {
+            Selector::Simple(simple) => (simple, &[][..]),
+            Selector::Complex(first, rest) => (first, &rest[..]),
+        };
+        std::iter::once(first).chain(rest.iter().map(|(_, compound)| compound))
+    }
+
     pub fn specificity(&self) -> Specificity {
-        let Selector::Simple(ref simple) = *self;
-        let a = simple.id.iter().count();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().count();
-        (a, b, c)
+        self.compounds().fold((0, 0, 0), |(a, b, c), simple| {
+            (
+                a + simple.id.iter().count(),
+                b + simple.class.len() + simple.attributes.len(),
+                c + simple.tag_name.iter().count(),
+            )
+        })
+    }
+}
+
+/// The number of tokens before the first combinator or whitespace outside
+/// an attribute selector.
+fn compound_length(tokens: &[Token]) -> usize {
+    let mut in_brackets = false;
+    tokens
+        .iter()
+        .position(|token| match token {
+            Token::Delim('[') => {
+                in_brackets = true;
+                false
+            }
+            Token::Delim(']') => {
+                in_brackets = false;
+                false
+            }
+            Token::Whitespace | Token::Delim('>' | '+' | '~') => !in_brackets,
+            _ => false,
+        })
+        .unwrap_or(tokens.len())
+}
+
+impl SimpleSelector {
+    fn from_tokens(tokens: &[Token]) -> Option<SimpleSelector> {
+        let mut selector = SimpleSelector {
+            namespace: None,
+            tag_name: None,
+            id: None,
+            class: Vec::new(),
+            attributes: Vec::new(),
+        };
+        // Without `@namespace` rules, only `*|` and `|` can be resolved.
+        let mut i = match tokens {
+            [Token::Delim('*'), Token::Delim('|'), ..] => {
+                selector.namespace = Some(NamespaceConstraint::Any);
+                2
+            }
+            [Token::Delim('|'), ..] => {
+                selector.namespace = Some(NamespaceConstraint::NoNamespace);
+                1
+            }
+            [Token::Ident(_), Token::Delim('|'), ..] => return None,
+            _ => 0,
+        };
+        let type_at = i;
+        if type_at > 0 && !matches!(tokens.get(i), Some(Token::Ident(_) | Token::Delim('*'))) {
+            return None;
+        }
+        while i < tokens.len() {
+            match (&tokens[i], tokens.get(i + 1)) {
+                (Token::Ident(name), _) if i == type_at => {
+                    selector.tag_name = Some(Atom::lowercase(name))
+                }
+                (Token::Delim('*'), _) if i == type_at => {}
+                (Token::Hash(id), _) if selector.id.is_none() => selector.id = Some(Atom::new(id)),
+                (Token::Delim('.'), Some(Token::Ident(class))) => {
+                    selector.class.push(Atom::new(class));
+                    i += 1;
+                }
+                (Token::Delim('['), _) => {
+                    let end = i + tokens[i..]
+                        .iter()
+                        .position(|token| *token == Token::Delim(']'))?;
+                    let inner: Vec<&Token> = tokens[i + 1..end]
+                        .iter()
+                        .filter(|token| **token != Token::Whitespace)
+                        .collect();
+                    let attribute = match *inner {
+                        [Token::Ident(name)] => AttributeSelector {
+                            name: Atom::lowercase(name),
+                            value: None,
+                        },
+                        [Token::Ident(name), Token::Delim('='), Token::Ident(value) | Token::String(value)] => {
+                            AttributeSelector {
+                                name: Atom::lowercase(name),
+                                value: Some(value.clone()),
+                            }
+                        }
+                        _ => return None,
+                    };
+                    selector.attributes.push(attribute);
+                    i = end;
+                }
+                _ => return None,
+            }
+            i += 1;
+        }
+        (!tokens.is_empty()).then_some(selector)
     }
 }
 
 pub struct Parser {
     pub pos: usize,
     pub input: String,
+    line_starts: Vec<usize>,
+    /// Where the sheet was loaded from, which its `@import`s resolve against.
+    url: String,
+    loader: Option<Box<dyn Loader>>,
+    /// Locations of the sheets importing this one, to break import cycles.
+    importers: Vec<String>,
+    layers: LayerOrder,
+    /// The cascade layer rules are currently parsed into.
+    layer: Vec<String>,
+    anonymous_layers: usize,
+    /// `@import` is ignored once any other rule has been seen.
+    imports_allowed: bool,
+    namespaces: Namespaces,
+    /// `@namespace` is ignored after any rule other than `@import`,
+    /// `@namespace` or an `@layer` statement.
+    namespaces_allowed: bool,
 }
 
 impl Parser {
+    pub fn new(input: String) -> Parser {
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Parser {
+            pos: 0,
+            input,
+            line_starts,
+            url: String::new(),
+            loader: None,
+            importers: Vec::new(),
+            layers: LayerOrder::default(),
+            layer: Vec::new(),
+            anonymous_layers: 0,
+            imports_allowed: true,
+            namespaces: Namespaces::default(),
+            namespaces_allowed: true,
+        }
+    }
+
+    /// A parser for a sheet loaded from `url` that fetches its `@import`s
+    /// through `loader`.
+    pub fn with_loader(input: String, url: String, loader: Box<dyn Loader>) -> Parser {
+        Parser {
+            url,
+            loader: Some(loader),
+            ..Parser::new(input)
+        }
+    }
+
+    fn location(&self) -> SourceLocation {
+        let line = self.line_starts.partition_point(|&start| start <= self.pos);
+        let line_start = self.line_starts[line - 1];
+        SourceLocation {
+            offset: self.pos,
+            line,
+            column: self.input[line_start..self.pos].chars().count() + 1,
+        }
+    }
+
     fn next_char(&self) -> char {
         self.input[self.pos..].chars().next().unwrap()
     }
@@ -104,22 +1994,302 @@ impl Parser {
         self.consume_while(char::is_whitespace);
     }
 
+    /// Parse rules up to the end of the input or the `}` closing the block
+    /// they are in.
     pub fn parse_rules(&mut self) -> Vec<Rule> {
         let mut rules = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.eof() {
+            if self.eof() || self.next_char() == '}' {
                 break;
             }
-            rules.push(self.parse_rule())
+            if self.next_char() == '@' {
+                rules.extend(self.parse_at_rule());
+            } else {
+                self.imports_allowed = false;
+                self.namespaces_allowed = false;
+                rules.extend(self.parse_rule())
+            }
+        }
+        rules
+    }
+
+    /// Parse a style rule, or skip it if a selector is invalid.
+    pub fn parse_rule(&mut self) -> Option<Rule> {
+        let start = self.location();
+        let selectors = self.parse_selectors();
+        let declarations = self.parse_declarations();
+        Some(Rule {
+            selectors: selectors?,
+            declarations,
+            conditions: Vec::new(),
+            layer: self.layer.clone(),
+            span: Span {
+                start,
+                end: self.location(),
+            },
+        })
+    }
+
+    /// Parse an at-rule into the style rules inside it, each tagged with the
+    /// at-rule's condition or layer. Unknown at-rules are skipped.
+    fn parse_at_rule(&mut self) -> Vec<Rule> {
+        assert!(self.consume_char() == '@');
+        let name = self.parse_name().to_ascii_lowercase();
+        self.consume_whitespace();
+        if name == "import" {
+            return self.parse_import();
+        }
+        if name == "namespace" {
+            self.parse_namespace();
+            return Vec::new();
+        }
+        let prelude = self.parse_prelude();
+        if self.eof() || self.consume_char() == ';' {
+            if name == "layer" {
+                self.declare_layers(&prelude);
+            }
+            return Vec::new();
+        }
+        self.imports_allowed = false;
+        self.namespaces_allowed = false;
+
+        let condition = match &*name {
+            "media" => Some(Condition::Media(MediaQueryList::parse(&prelude))),
+            "supports" => Some(Condition::Supports(SupportsCondition::parse(&prelude))),
+            "layer" => None,
+            _ => {
+                self.skip_block();
+                return Vec::new();
+            }
+        };
+        let outer_layer = self.layer.clone();
+        if name == "layer" {
+            match self.layer_name(&prelude) {
+                Some(name) => {
+                    self.layer.extend(name);
+                    self.layers.declare(&self.layer);
+                }
+                None => {
+                    self.skip_block();
+                    return Vec::new();
+                }
+            }
+        }
+        let mut rules = self.parse_rules();
+        self.layer = outer_layer;
+        if !self.eof() {
+            assert!(self.consume_char() == '}');
+        }
+        if let Some(condition) = condition {
+            for rule in &mut rules {
+                rule.conditions.insert(0, condition.clone());
+            }
+        }
+        rules
+    }
+
+    /// `@import <url> [layer | layer(<name>)] [<media queries>];`, which
+    /// stands for the rules of the imported sheet.
+    fn parse_import(&mut self) -> Vec<Rule> {
+        let url = self.parse_url();
+        let prelude = self.parse_prelude();
+        if !self.eof() && self.consume_char() == '{' {
+            self.skip_block();
+            return Vec::new();
+        }
+        let url = match url {
+            Some(url) if self.imports_allowed => url,
+            _ => return Vec::new(),
+        };
+
+        let mut tokens = trim_tokens(&prelude);
+        let layer = match tokens.first() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("layer") => {
+                tokens = &tokens[1..];
+                Some(vec![self.anonymous_layer()])
+            }
+            Some(Token::Function(name)) if name.eq_ignore_ascii_case("layer") => {
+                let end = match function_end(tokens, 0) {
+                    Some(end) => end,
+                    None => return Vec::new(),
+                };
+                let name = match parse_layer_name(&tokens[1..end - 1]) {
+                    Some(name) => name,
+                    None => return Vec::new(),
+                };
+                tokens = &tokens[end..];
+                Some(name)
+            }
+            _ => None,
+        };
+        let media = (!trim_tokens(tokens).is_empty())
+            .then(|| Condition::Media(MediaQueryList::parse(tokens)));
+
+        let mut rules = self.import(&url, layer.unwrap_or_default());
+        if let Some(media) = media {
+            for rule in &mut rules {
+                rule.conditions.insert(0, media.clone());
+            }
         }
         rules
     }
 
-    pub fn parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_selectors(),
-            declarations: self.parse_declarations(),
+    /// `@namespace [<prefix>] <url>;`, which declares a namespace for the
+    /// selectors of the rules after it.
+    fn parse_namespace(&mut self) {
+        let prefix = match self.eof() || matches!(self.next_char(), '"' | '\'') {
+            false if !self.start_with_url() => {
+                let prefix = self.parse_name();
+                self.consume_whitespace();
+                Some(Atom::new(&prefix))
+            }
+            _ => None,
+        };
+        let url = self.parse_url();
+        let prelude = self.parse_prelude();
+        if !self.eof() && self.consume_char() == '{' {
+            self.skip_block();
+            return;
+        }
+        match url {
+            Some(url) if self.namespaces_allowed && trim_tokens(&prelude).is_empty() => {
+                self.imports_allowed = false;
+                self.namespaces.declare(prefix, Atom::new(&url));
+            }
+            _ => {}
+        }
+    }
+
+    /// Load and parse the sheet at `url` into sub-layer `layer` of the
+    /// current one. Sheets that can't be loaded or that would import
+    /// themselves give no rules.
+    fn import(&mut self, url: &str, layer: Vec<String>) -> Vec<Rule> {
+        let mut loader = match self.loader.take() {
+            Some(loader) => loader,
+            None => return Vec::new(),
+        };
+        let (location, source) = match loader.load(url, &self.url) {
+            Some(loaded) => loaded,
+            None => {
+                self.loader = Some(loader);
+                return Vec::new();
+            }
+        };
+        if location == self.url || self.importers.contains(&location) {
+            self.loader = Some(loader);
+            return Vec::new();
+        }
+
+        let mut importers = self.importers.clone();
+        importers.push(self.url.clone());
+        let mut child = Parser {
+            importers,
+            layers: std::mem::take(&mut self.layers),
+            layer: self.layer.iter().cloned().chain(layer).collect(),
+            anonymous_layers: self.anonymous_layers,
+            ..Parser::with_loader(source, location, loader)
+        };
+        if child.layer.len() > self.layer.len() {
+            child.layers.declare(&child.layer);
+        }
+        let rules = child.parse_rules();
+
+        self.loader = child.loader.take();
+        self.layers = std::mem::take(&mut child.layers);
+        self.anonymous_layers = child.anonymous_layers;
+        rules
+    }
+
+    /// The URL of an `@import`, written as a string or with `url()`.
+    fn parse_url(&mut self) -> Option<String> {
+        if self.eof() {
+            return None;
+        }
+        if matches!(self.next_char(), '"' | '\'') {
+            return match self.parse_token() {
+                Token::String(url) => Some(url),
+                _ => None,
+            };
+        }
+        if !self.start_with_url() {
+            return None;
+        }
+        self.pos += 4;
+        self.consume_whitespace();
+        let url = match self.next_char() {
+            '"' | '\'' => match self.parse_token() {
+                Token::String(url) => url,
+                _ => return None,
+            },
+            _ => self.consume_while(|char| char != ')' && !char.is_whitespace()),
+        };
+        self.consume_whitespace();
+        if self.eof() || self.consume_char() != ')' {
+            return None;
+        }
+        Some(url)
+    }
+
+    fn start_with_url(&self) -> bool {
+        self.input[self.pos..]
+            .get(..4)
+            .is_some_and(|start| start.eq_ignore_ascii_case("url("))
+    }
+
+    /// `@layer a, b.c;` declares layers without adding rules to them.
+    fn declare_layers(&mut self, prelude: &[Token]) {
+        for name in prelude.split(|token| *token == Token::Comma) {
+            if let Some(name) = parse_layer_name(name) {
+                let name: Vec<String> = self.layer.iter().cloned().chain(name).collect();
+                self.layers.declare(&name);
+            }
+        }
+    }
+
+    /// The name in an `@layer` block's prelude, or a new anonymous layer's
+    /// if it has none.
+    fn layer_name(&mut self, prelude: &[Token]) -> Option<Vec<String>> {
+        if trim_tokens(prelude).is_empty() {
+            Some(vec![self.anonymous_layer()])
+        } else {
+            parse_layer_name(prelude)
+        }
+    }
+
+    fn anonymous_layer(&mut self) -> String {
+        self.anonymous_layers += 1;
+        format!("<anonymous-{}>", self.anonymous_layers)
+    }
+
+    /// Tokens up to the `{` or `;` ending an at-rule's prelude.
+    fn parse_prelude(&mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        while !self.eof() {
+            if depth == 0 && matches!(self.next_char(), '{' | ';') {
+                break;
+            }
+            let token = self.parse_token();
+            match token {
+                Token::Function(_) | Token::OpenParen => depth += 1,
+                Token::CloseParen => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+        tokens
+    }
+
+    /// Skip the rest of a block whose `{` was just consumed.
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while !self.eof() && depth > 0 {
+            match self.consume_char() {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
         }
     }
 
@@ -133,10 +2303,11 @@ impl Parser {
             .unwrap()
     }
 
-    fn parse_selectors(&mut self) -> Vec<Selector> {
+    /// Parse a selector list, which is invalid if any selector in it is.
+    fn parse_selectors(&mut self) -> Option<Vec<Selector>> {
         let mut selectors = Vec::new();
         loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+            selectors.push(self.parse_selector());
             self.consume_whitespace();
             match self.next_char() {
                 ',' => {
@@ -144,38 +2315,150 @@ impl Parser {
                     self.consume_whitespace();
                 }
                 '{' => break,
-                _ => panic!("unexpected char in selector"),
+                _ => panic!("unexpected char in selector at {}", self.location()),
+            }
+        }
+        selectors.into_iter().collect()
+    }
+
+    fn parse_selector(&mut self) -> Option<Selector> {
+        let first = self.parse_simple_selector();
+        let mut rest = Vec::new();
+        while !self.eof() {
+            let whitespace = self.next_char().is_whitespace();
+            self.consume_whitespace();
+            let combinator = match self.next_char() {
+                ',' | '{' => break,
+                '>' => Combinator::Child,
+                '+' => Combinator::NextSibling,
+                '~' => Combinator::SubsequentSibling,
+                _ if whitespace => Combinator::Descendant,
+                _ => panic!("unexpected char in selector at {}", self.location()),
+            };
+            if combinator != Combinator::Descendant {
+                self.consume_char();
+                self.consume_whitespace();
             }
+            rest.push((combinator, self.parse_simple_selector()));
+        }
+        let first = first?;
+        let rest = rest
+            .into_iter()
+            .map(|(combinator, compound)| Some((combinator, compound?)))
+            .collect::<Option<Vec<_>>>()?;
+        if rest.is_empty() {
+            Some(Selector::Simple(first))
+        } else {
+            Some(Selector::Complex(first, rest))
         }
-        selectors
     }
 
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
+    /// Parse a compound selector. Returns `None` if its namespace prefix
+    /// wasn't declared.
+    fn parse_simple_selector(&mut self) -> Option<SimpleSelector> {
         let mut selector = SimpleSelector {
+            namespace: self
+                .namespaces
+                .default
+                .clone()
+                .map(|url| NamespaceConstraint::Url { prefix: None, url }),
             id: None,
             class: Vec::new(),
             tag_name: None,
+            attributes: Vec::new(),
         };
+        let mut declared = true;
+        if !self.eof()
+            && matches!(self.next_char(), '*' | '|' | 'a'..='z' | 'A'..='Z' | '0'..='9' | '-')
+        {
+            let mut name = self.parse_element_name();
+            if !self.eof() && self.next_char() == '|' {
+                self.consume_char();
+                selector.namespace = match name.as_deref() {
+                    None => Some(NamespaceConstraint::Any),
+                    Some("") => Some(NamespaceConstraint::NoNamespace),
+                    Some(prefix) => {
+                        let prefix = Atom::new(prefix);
+                        let url = self.namespaces.get(&prefix).cloned();
+                        declared = url.is_some();
+                        url.map(|url| NamespaceConstraint::Url {
+                            prefix: Some(prefix),
+                            url,
+                        })
+                    }
+                };
+                name = self.parse_element_name();
+            }
+            if name.as_deref() == Some("") {
+                panic!("unexpected char in selector at {}", self.location());
+            }
+            selector.tag_name = name.map(|name| Atom::lowercase(&name));
+        }
         while !self.eof() {
-            self.consume_whitespace();
             match self.next_char() {
                 '#' => {
                     self.consume_char();
-                    selector.id = Some(self.parse_name());
+                    selector.id = Some(Atom::new(&self.parse_name()));
                 }
                 '.' => {
                     self.consume_char();
-                    selector.class.push(self.parse_name());
+                    selector.class.push(Atom::new(&self.parse_name()));
+                }
+                '[' => {
+                    selector.attributes.push(self.parse_attribute_selector());
                 }
-                ',' | '{' => {
+                ',' | '{' | '>' | '+' | '~' => {
                     break;
                 }
-                _ => {
-                    selector.tag_name = Some(self.parse_name());
+                char if char.is_whitespace() => {
+                    break;
+                }
+                _ => panic!("unexpected char in selector at {}", self.location()),
+            }
+        }
+        declared.then_some(selector)
+    }
+
+    /// An element name, or `None` for `*`.
+    fn parse_element_name(&mut self) -> Option<String> {
+        if !self.eof() && self.next_char() == '*' {
+            self.consume_char();
+            None
+        } else {
+            Some(self.parse_name())
+        }
+    }
+
+    fn parse_attribute_selector(&mut self) -> AttributeSelector {
+        assert!(self.consume_char() == '[');
+        self.consume_whitespace();
+        let name = Atom::lowercase(&self.parse_name());
+        self.consume_whitespace();
+        let value = match self.consume_char() {
+            ']' => return AttributeSelector { name, value: None },
+            '=' => {
+                self.consume_whitespace();
+                match self.next_char() {
+                    '"' | '\'' => {
+                        let open_quote = self.consume_char();
+                        let value = self.consume_while(|char| char != open_quote);
+                        assert!(self.consume_char() == open_quote);
+                        value
+                    }
+                    _ => self.parse_name(),
                 }
             }
+            _ => panic!(
+                "unexpected char in attribute selector at {}",
+                self.location()
+            ),
+        };
+        self.consume_whitespace();
+        assert!(self.consume_char() == ']');
+        AttributeSelector {
+            name,
+            value: Some(value),
         }
-        selector
     }
 
     fn parse_declarations(&mut self) -> Vec<Declaration> {
@@ -186,65 +2469,296 @@ impl Parser {
             if self.next_char() == '}' {
                 break;
             }
-            declarations.push(self.parse_declaration());
+            declarations.extend(self.parse_declaration());
             match self.next_char() {
                 ';' => {
                     self.consume_char();
                 }
-                _ => panic!("unexpected char in declaration"),
+                // The last declaration doesn't need a semicolon.
+                '}' => {}
+                _ => panic!("unexpected char in declaration at {}", self.location()),
+            }
+        }
+        assert!(self.consume_char() == '}');
+        declarations
+    }
+
+    /// Parse one declaration, or skip it and return `None` if its value is
+    /// invalid.
+    fn parse_declaration(&mut self) -> Option<Declaration> {
+        let start = self.location();
+        let name = self.parse_name();
+        assert!(self.consume_char() == ':');
+        self.consume_whitespace();
+        if name.starts_with("--") || self.value_uses_var() {
+            let (value, important) = self.parse_unparsed_value();
+            return Some(Declaration {
+                name,
+                value,
+                important,
+                span: Span {
+                    start,
+                    end: self.location(),
+                },
+            });
+        }
+        let mut values = Vec::new();
+        let mut important = false;
+        loop {
+            self.consume_whitespace();
+            match self.next_char() {
+                ';' | '}' => break,
+                '!' => {
+                    self.consume_char();
+                    self.consume_whitespace();
+                    if !self.parse_name().eq_ignore_ascii_case("important") {
+                        return self.skip_declaration();
+                    }
+                    important = true;
+                }
+                _ => {
+                    let start = self.pos;
+                    let value = self.parse_value();
+                    // Strings, commas and slashes aren't values this parser
+                    // knows, so the declaration is dropped.
+                    if self.pos == start {
+                        return self.skip_declaration();
+                    }
+                    values.push(value);
+                }
+            }
+        }
+        let mut values = values.into_iter().collect::<Option<Vec<_>>>()?;
+        let value = match values.len() {
+            0 => return None,
+            1 => values.remove(0),
+            _ => Value::List(values),
+        };
+        if name == "display" && style::Display::from_value(&value).is_none() {
+            return None;
+        }
+        Some(Declaration {
+            name,
+            value,
+            important,
+            span: Span {
+                start,
+                end: self.location(),
+            },
+        })
+    }
+
+    /// Skip the rest of an invalid declaration, up to the `;` or `}` ending
+    /// it.
+    fn skip_declaration(&mut self) -> Option<Declaration> {
+        self.consume_while(|char| !matches!(char, ';' | '}'));
+        None
+    }
+
+    fn value_uses_var(&self) -> bool {
+        let rest = &self.input[self.pos..];
+        let end = rest.find([';', '}']).unwrap_or(rest.len());
+        rest[..end].contains("var(")
+    }
+
+    /// Consume a declaration value as tokens, up to the `;` or `}` ending it,
+    /// splitting off a trailing `!important`.
+    fn parse_unparsed_value(&mut self) -> (Value, bool) {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        while !self.eof() {
+            if depth == 0 && matches!(self.next_char(), ';' | '}') {
+                break;
+            }
+            let token = self.parse_token();
+            match token {
+                Token::Function(_) | Token::OpenParen => depth += 1,
+                Token::CloseParen => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+
+        let trim = |tokens: &mut Vec<Token>| {
+            while tokens.last() == Some(&Token::Whitespace) {
+                tokens.pop();
+            }
+        };
+        trim(&mut tokens);
+        let important = match tokens.last() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("important") => {
+                let bang = tokens[..tokens.len() - 1]
+                    .iter()
+                    .rposition(|token| *token != Token::Whitespace);
+                match bang {
+                    Some(i) if tokens[i] == Token::Delim('!') => {
+                        tokens.truncate(i);
+                        trim(&mut tokens);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        (Value::Unparsed(tokens), important)
+    }
+
+    fn parse_token(&mut self) -> Token {
+        let char = self.next_char();
+        let next = self.input[self.pos..].chars().nth(1);
+        let after_next = self.input[self.pos..].chars().nth(2);
+        let signed_number = matches!(next, Some('0'..='9'))
+            || next == Some('.') && matches!(after_next, Some('0'..='9'));
+        let fraction = matches!(next, Some('0'..='9'));
+        let dashed_ident = matches!(next, Some('a'..='z' | 'A'..='Z' | '_' | '-'));
+        match char {
+            _ if char.is_whitespace() => {
+                self.consume_whitespace();
+                Token::Whitespace
+            }
+            '0'..='9' => self.parse_numeric_token(),
+            '.' if fraction => self.parse_numeric_token(),
+            '+' | '-' if signed_number => self.parse_numeric_token(),
+            '-' if !dashed_ident => Token::Delim(self.consume_char()),
+            'a'..='z' | 'A'..='Z' | '_' | '-' => {
+                let name = self.parse_name();
+                if !self.eof() && self.next_char() == '(' {
+                    self.consume_char();
+                    Token::Function(name)
+                } else {
+                    Token::Ident(name)
+                }
+            }
+            '#' => {
+                self.consume_char();
+                Token::Hash(self.parse_name())
+            }
+            '"' | '\'' => {
+                let open_quote = self.consume_char();
+                let string = self.consume_while(|char| char != open_quote);
+                if !self.eof() {
+                    self.consume_char();
+                }
+                Token::String(string)
+            }
+            '(' => {
+                self.consume_char();
+                Token::OpenParen
+            }
+            ')' => {
+                self.consume_char();
+                Token::CloseParen
             }
+            ',' => {
+                self.consume_char();
+                Token::Comma
+            }
+            _ => Token::Delim(self.consume_char()),
         }
-        assert!(self.consume_char() == '}');
-        declarations
     }
 
-    fn parse_declaration(&mut self) -> Declaration {
-        let name = self.parse_name();
-        assert!(self.consume_char() == ':');
-        self.consume_whitespace();
-        let value = self.parse_value();
-        Declaration { name, value }
+    fn parse_numeric_token(&mut self) -> Token {
+        let sign = match self.next_char() {
+            '+' | '-' => self.consume_char(),
+            _ => '+',
+        };
+        let number = self.parse_number();
+        let number = if sign == '-' { -number } else { number };
+        if !self.eof() && self.next_char() == '%' {
+            self.consume_char();
+            return Token::Percentage(number);
+        }
+        let unit = self.consume_while(|char| char.is_ascii_alphabetic());
+        if unit.is_empty() {
+            Token::Number(number)
+        } else {
+            Token::Dimension(number, unit)
+        }
     }
 
-    fn parse_value(&mut self) -> Value {
+    fn parse_value(&mut self) -> Option<Value> {
+        let signed_number = matches!(self.input[self.pos..].chars().nth(1), Some('0'..='9' | '.'));
         match self.next_char() {
-            '0'..='9' => {
-                let length = self.parse_number();
-                self.consume_while(|char| char != ';');
-                Value::Length(length, Unit::Px)
-            }
+            '0'..='9' | '.' => self.parse_length(),
+            '+' | '-' if signed_number => self.parse_length(),
             '#' => {
                 self.consume_char();
-                Value::Color(self.parse_color())
+                parse_hex_color(&self.parse_name()).map(Value::Color)
+            }
+            _ if self.starts_with_function() => {
+                let start = self.pos;
+                let mut tokens = vec![self.parse_token()];
+                let mut depth = 1;
+                while depth > 0 && !self.eof() && !matches!(self.next_char(), ';' | '}') {
+                    let token = self.parse_token();
+                    match token {
+                        Token::Function(_) | Token::OpenParen => depth += 1,
+                        Token::CloseParen => depth -= 1,
+                        _ => {}
+                    }
+                    tokens.push(token);
+                }
+                if depth > 0 {
+                    self.pos = start;
+                    self.consume_while(|char| !matches!(char, ';' | '}'));
+                    return None;
+                }
+                CalcNode::parse_function(&tokens).map(Value::Calc)
             }
-            _ => Value::Keyword(self.parse_name()),
+            _ => Some(Value::Keyword(self.parse_name())),
         }
     }
 
-    fn parse_color(&mut self) -> Color {
-        Color {
-            r: self.parse_hex_pair(),
-            g: self.parse_hex_pair(),
-            b: self.parse_hex_pair(),
-            a: 255,
-        }
+    fn starts_with_function(&self) -> bool {
+        let rest = &self.input[self.pos..];
+        let name_len = rest
+            .find(|char: char| !(char.is_ascii_alphanumeric() || char == '-'))
+            .unwrap_or(rest.len());
+        name_len > 0 && rest[name_len..].starts_with('(')
     }
 
-    fn parse_hex_pair(&mut self) -> u8 {
-        let pair_str = &self.input[self.pos..self.pos + 2];
-        self.pos += 2;
-        u8::from_str_radix(pair_str, 16).unwrap()
+    fn parse_length(&mut self) -> Option<Value> {
+        match self.parse_numeric_token() {
+            Token::Number(number) => Some(Value::Length(number, Unit::Px)),
+            Token::Percentage(number) => Some(Value::Length(number, Unit::Percent)),
+            Token::Dimension(number, unit) => Some(Value::Length(number, Unit::parse(&unit)?)),
+            _ => None,
+        }
     }
 }
 
 pub fn parse(source: String) -> StyleSheet {
-    let mut parser = Parser {
-        pos: 0,
-        input: source,
-    };
+    parse_with(Parser::new(source))
+}
+
+/// Parse a comma-separated list of selectors, such as the argument of
+/// `querySelectorAll`. Returns `None` if any of them is invalid.
+pub fn parse_selector_list(source: &str) -> Option<Vec<Selector>> {
+    let mut parser = Parser::new(source.to_string());
+    let mut tokens = Vec::new();
+    while !parser.eof() {
+        tokens.push(parser.parse_token());
+    }
+    tokens
+        .split(|token| *token == Token::Comma)
+        .map(Selector::from_tokens)
+        .collect()
+}
+
+/// Parse the style sheet found at `url`, fetching its `@import`s through
+/// `loader`.
+pub fn parse_with_loader(source: String, url: String, loader: impl Loader + 'static) -> StyleSheet {
+    parse_with(Parser::with_loader(source, url, Box::new(loader)))
+}
 
+fn parse_with(mut parser: Parser) -> StyleSheet {
+    let rules = parser.parse_rules();
     StyleSheet {
-        rules: parser.parse_rules(),
+        rules,
+        origin: Origin::Author,
+        layers: parser.layers,
+        namespaces: parser.namespaces,
     }
 }
 
@@ -253,10 +2767,7 @@ mod tests {
     use super::*;
 
     fn get_parser(source: &str) -> Parser {
-        Parser {
-            pos: 0,
-            input: source.to_string(),
-        }
+        Parser::new(source.to_string())
     }
 
     #[test]
@@ -285,44 +2796,210 @@ mod tests {
         println!("{:?}", Parser::parse_rule(&mut get_parser(source)));
     }
 
+    #[test]
+    fn test_rule_and_declaration_spans() {
+        let source = "p { color: #000000; }\n@media print {\n  h1 { margin: 1px !important }\n}";
+        let style_sheet = parse(source.to_string());
+        let text = |span: Span| &source[span.range()];
+        assert_eq!(text(style_sheet.rules[0].span), "p { color: #000000; }");
+        let h1 = &style_sheet.rules[1];
+        assert_eq!(text(h1.span), "h1 { margin: 1px !important }");
+        assert_eq!(h1.span.start.to_string(), "3:3");
+        assert_eq!(
+            text(h1.declarations[0].span).trim(),
+            "margin: 1px !important"
+        );
+        // Rules written in different places are still the same rule.
+        assert_eq!(
+            parse("h1 { margin: 1px }".to_string()).rules[0],
+            parse(" h1{margin:1px}".to_string()).rules[0]
+        );
+    }
+
     #[test]
     fn test_parse_simple_selectors() {
         let source = "h1, h2 {";
         let expected = vec![
             Selector::Simple(SimpleSelector {
+                namespace: None,
                 id: None,
                 class: Vec::new(),
-                tag_name: Some("h1".to_string()),
+                tag_name: Some(Atom::new("h1")),
+                attributes: Vec::new(),
             }),
             Selector::Simple(SimpleSelector {
+                namespace: None,
                 id: None,
                 class: Vec::new(),
-                tag_name: Some("h2".to_string()),
+                tag_name: Some(Atom::new("h2")),
+                attributes: Vec::new(),
             }),
         ];
-        assert_eq!(Parser::parse_selectors(&mut get_parser(source)), expected);
+        assert_eq!(
+            Parser::parse_selectors(&mut get_parser(source)).unwrap(),
+            expected
+        );
     }
 
     #[test]
     fn test_parse_class_selectors() {
         let source = ".fruit.apple {";
         let expected = vec![Selector::Simple(SimpleSelector {
+            namespace: None,
             id: None,
-            class: vec!["fruit".to_string(), "apple".to_string()],
+            class: vec![Atom::new("fruit"), Atom::new("apple")],
             tag_name: None,
+            attributes: Vec::new(),
         })];
-        assert_eq!(Parser::parse_selectors(&mut get_parser(source)), expected);
+        assert_eq!(
+            Parser::parse_selectors(&mut get_parser(source)).unwrap(),
+            expected
+        );
     }
 
     #[test]
     fn test_parse_id_selectors() {
         let source = "#id {";
         let expected = vec![Selector::Simple(SimpleSelector {
-            id: Some("id".to_string()),
+            namespace: None,
+            id: Some(Atom::new("id")),
             class: Vec::new(),
             tag_name: None,
+            attributes: Vec::new(),
         })];
-        assert_eq!(Parser::parse_selectors(&mut get_parser(source)), expected);
+        assert_eq!(
+            Parser::parse_selectors(&mut get_parser(source)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_attribute_selectors() {
+        let source = "a[href][target='_blank'], [lang=en] {";
+        let selectors = Parser::parse_selectors(&mut get_parser(source)).unwrap();
+        assert_eq!(
+            selectors[0],
+            Selector::Simple(SimpleSelector {
+                namespace: None,
+                id: None,
+                class: Vec::new(),
+                tag_name: Some(Atom::new("a")),
+                attributes: vec![
+                    AttributeSelector {
+                        name: Atom::new("href"),
+                        value: None
+                    },
+                    AttributeSelector {
+                        name: Atom::new("target"),
+                        value: Some("_blank".to_string())
+                    }
+                ],
+            })
+        );
+        assert_eq!(selectors[0].specificity(), (0, 2, 1));
+        assert_eq!(selectors[1].to_string(), "[lang=\"en\"]");
+    }
+
+    #[test]
+    fn test_parse_complex_selectors() {
+        let source = "ul li.item > a, h1 + p ~ div, .a .b {";
+        let selectors = Parser::parse_selectors(&mut get_parser(source)).unwrap();
+        let compound = |tag_name: &str| SimpleSelector {
+            namespace: None,
+            id: None,
+            class: Vec::new(),
+            tag_name: Some(Atom::new(tag_name)),
+            attributes: Vec::new(),
+        };
+        assert_eq!(
+            selectors[0],
+            Selector::Complex(
+                compound("ul"),
+                vec![
+                    (
+                        Combinator::Descendant,
+                        SimpleSelector {
+                            namespace: None,
+                            class: vec![Atom::new("item")],
+                            ..compound("li")
+                        }
+                    ),
+                    (Combinator::Child, compound("a")),
+                ]
+            )
+        );
+        assert_eq!(selectors[0].specificity(), (0, 1, 3));
+        assert_eq!(selectors[0].subject(), &compound("a"));
+        assert_eq!(selectors[1].to_string(), "h1 + p ~ div");
+        assert_eq!(selectors[2].specificity(), (0, 2, 0));
+    }
+
+    #[test]
+    fn test_parse_selector_list() {
+        let selectors = parse_selector_list("#cart .price, li+.a").unwrap();
+        assert_eq!(selectors.len(), 2);
+        assert_eq!(selectors[0].to_string(), "#cart .price");
+        assert_eq!(selectors[1].to_string(), "li + .a");
+        assert_eq!(parse_selector_list("p,"), None);
+        assert_eq!(parse_selector_list("p {"), None);
+        assert_eq!(parse_selector_list(""), None);
+
+        let selectors = parse_selector_list("*|p, |p, *|*").unwrap();
+        assert_eq!(
+            selectors[0].subject().namespace,
+            Some(NamespaceConstraint::Any)
+        );
+        assert_eq!(
+            selectors[1].subject().namespace,
+            Some(NamespaceConstraint::NoNamespace)
+        );
+        assert_eq!(selectors[2].to_string(), "*|*");
+        // Prefixes can't be resolved without `@namespace` rules.
+        assert_eq!(parse_selector_list("svg|rect"), None);
+        assert_eq!(parse_selector_list("*|.a"), None);
+    }
+
+    #[test]
+    fn test_parse_namespaces() {
+        let style_sheet = parse(
+            "@namespace url(http://www.w3.org/1999/xhtml);
+             @namespace svg \"http://www.w3.org/2000/svg\";
+             svg|rect, *|p, |q, svg|*, div { width: 1px; }
+             math|mi { width: 2px; }
+             @namespace late url(x);
+             late|a { width: 3px; }"
+                .to_string(),
+        );
+        assert_eq!(
+            style_sheet.namespaces.default,
+            Some(Atom::new("http://www.w3.org/1999/xhtml"))
+        );
+        assert_eq!(
+            style_sheet.namespaces.get(&Atom::new("svg")),
+            Some(&Atom::new("http://www.w3.org/2000/svg"))
+        );
+        assert_eq!(style_sheet.namespaces.get(&Atom::new("late")), None);
+        // Rules with an undeclared prefix are dropped.
+        assert_eq!(style_sheet.rules.len(), 1);
+
+        let selectors = &style_sheet.rules[0].selectors;
+        let written: Vec<_> = selectors.iter().map(Selector::to_string).collect();
+        assert_eq!(written, ["svg|rect", "*|p", "|q", "svg|*", "div"]);
+        assert_eq!(
+            selectors[4].subject().namespace,
+            Some(NamespaceConstraint::Url {
+                prefix: None,
+                url: Atom::new("http://www.w3.org/1999/xhtml"),
+            })
+        );
+        assert_eq!(selectors[0].specificity(), (0, 0, 1));
+
+        let output = style_sheet.to_css(Format::Minified);
+        assert!(output.starts_with(
+            "@namespace \"http://www.w3.org/1999/xhtml\";\
+             @namespace svg \"http://www.w3.org/2000/svg\";"
+        ));
+        assert_eq!(parse(output), style_sheet);
     }
 
     #[test]
@@ -339,19 +3016,136 @@ mod tests {
         let source = "margin: auto;";
         assert_eq!(
             Parser::parse_declaration(&mut get_parser(source)),
-            Declaration {
+            Some(Declaration {
                 name: "margin".to_string(),
-                value: Value::Keyword("auto".to_string())
+                value: Value::Keyword("auto".to_string()),
+                important: false,
+                span: Span::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_important_declaration() {
+        let source = "{
+            margin: 10px !important;
+            display: block ! IMPORTANT;
+          }";
+        let declarations = Parser::parse_declarations(&mut get_parser(source));
+        assert_eq!(declarations[0].value, Value::Length(10.0, Unit::Px));
+        assert!(declarations[0].important);
+        assert_eq!(declarations[1].value, Value::Keyword("block".to_string()));
+        assert!(declarations[1].important);
+        assert_eq!(
+            declarations[1].span.start,
+            SourceLocation {
+                offset: 51,
+                line: 3,
+                column: 13
             }
         );
     }
 
+    #[test]
+    fn test_parse_unknown_bang_drops_the_declaration() {
+        let source = "{ color: red !foo; margin: 1px ! important }";
+        let declarations = Parser::parse_declarations(&mut get_parser(source));
+        assert_eq!(declarations.len(), 1);
+        assert_eq!(declarations[0].name, "margin");
+        assert!(declarations[0].important);
+    }
+
+    #[test]
+    fn test_unsupported_values_drop_the_declaration() {
+        let style_sheet = parse(
+            "p { font-family: \"Arial\"; margin: 1px; }\
+             p { font-family: a, b; margin: 2px }\
+             p { margin: 1px / 2px; color: #ff0000 }"
+                .to_string(),
+        );
+        let names: Vec<Vec<&str>> = style_sheet
+            .rules
+            .iter()
+            .map(|rule| rule.declarations.iter().map(|d| d.name.as_str()).collect())
+            .collect();
+        assert_eq!(names, [["margin"], ["margin"], ["color"]]);
+    }
+
+    #[test]
+    fn test_parse_multiple_keywords_declaration() {
+        let source = "display: inline flow-root;";
+        assert_eq!(
+            Parser::parse_declaration(&mut get_parser(source)),
+            Some(Declaration {
+                name: "display".to_string(),
+                value: Value::List(vec![
+                    Value::Keyword("inline".to_string()),
+                    Value::Keyword("flow-root".to_string())
+                ]),
+                important: false,
+                span: Span::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_custom_property() {
+        let source = "{
+            --spacing: calc( 2 * 4px ) !important;
+            --brand-color:#ff0000;
+            margin: var(--spacing, 8px) 1em;
+            --empty:;
+          }";
+        let declarations = Parser::parse_declarations(&mut get_parser(source));
+        assert_eq!(declarations[0].name, "--spacing");
+        assert_eq!(
+            declarations[0].value,
+            Value::Unparsed(vec![
+                Token::Function("calc".to_string()),
+                Token::Whitespace,
+                Token::Number(2.0),
+                Token::Whitespace,
+                Token::Delim('*'),
+                Token::Whitespace,
+                Token::Dimension(4.0, "px".to_string()),
+                Token::Whitespace,
+                Token::CloseParen,
+            ])
+        );
+        assert!(declarations[0].important);
+        assert_eq!(
+            declarations[1].value,
+            Value::Unparsed(vec![Token::Hash("ff0000".to_string())])
+        );
+        assert_eq!(declarations[2].value.to_string(), "var(--spacing, 8px) 1em");
+        assert_eq!(declarations[3].value, Value::Unparsed(vec![]));
+    }
+
+    #[test]
+    fn test_value_from_tokens() {
+        let tokens = vec![
+            Token::Dimension(8.0, "px".to_string()),
+            Token::Whitespace,
+            Token::Ident("auto".to_string()),
+        ];
+        assert_eq!(
+            Value::from_tokens(&tokens),
+            Some(Value::List(vec![
+                Value::Length(8.0, Unit::Px),
+                Value::Keyword("auto".to_string())
+            ]))
+        );
+        assert_eq!(Value::from_tokens(&[Token::Hash("zz".to_string())]), None);
+        assert_eq!(Value::from_tokens(&[Token::Whitespace]), None);
+        assert_eq!(Value::from_tokens(&[Token::Comma]), None);
+    }
+
     #[test]
     fn test_parse_keyword_value() {
         let source = "auto;";
         assert_eq!(
             Parser::parse_value(&mut get_parser(source)),
-            Value::Keyword("auto".to_string())
+            Some(Value::Keyword("auto".to_string()))
         );
     }
 
@@ -360,7 +3154,7 @@ mod tests {
         let source = "10px";
         assert_eq!(
             Parser::parse_value(&mut get_parser(source)),
-            Value::Length(10.0, Unit::Px)
+            Some(Value::Length(10.0, Unit::Px))
         );
     }
 
@@ -369,12 +3163,638 @@ mod tests {
         let source = "#812dd3";
         assert_eq!(
             Parser::parse_value(&mut get_parser(source)),
-            Value::Color(Color {
+            Some(Value::Color(Color {
                 r: 129,
                 g: 45,
                 b: 211,
                 a: 255
+            }))
+        );
+        let color = |source: &str| match Parser::parse_value(&mut get_parser(source)) {
+            Some(Value::Color(color)) => Some((color.r, color.g, color.b, color.a)),
+            _ => None,
+        };
+        assert_eq!(color("#f0a"), Some((255, 0, 170, 255)));
+        assert_eq!(color("#f0a8"), Some((255, 0, 170, 136)));
+        assert_eq!(color("#ff00aa80"), Some((255, 0, 170, 128)));
+        assert_eq!(color("#ff00a"), None);
+        assert_eq!(color("#ggg"), None);
+    }
+
+    #[test]
+    fn test_minified_values() {
+        let source = "p { margin: 0px -0.5em 0.25% 10px; color: #aabbcc; background: #12345680; }";
+        let style_sheet = parse(source.to_string());
+        let output = style_sheet.to_css(Format::Minified);
+        assert_eq!(
+            output,
+            "p{margin:0 -.5em .25% 10px;color:#abc;background:#12345680}"
+        );
+        assert_eq!(parse(output), style_sheet);
+    }
+
+    #[test]
+    fn test_parse_relative_units() {
+        let source = "{ margin: -1.5em 50% 2rem 0; }";
+        let declarations = Parser::parse_declarations(&mut get_parser(source));
+        assert_eq!(
+            declarations[0].value,
+            Value::List(vec![
+                Value::Length(-1.5, Unit::Em),
+                Value::Length(50.0, Unit::Percent),
+                Value::Length(2.0, Unit::Rem),
+                Value::Length(0.0, Unit::Px),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_math_functions() {
+        let source = "calc(100% - 2em)";
+        let value = Parser::parse_value(&mut get_parser(source)).unwrap();
+        assert_eq!(
+            value,
+            Value::Calc(CalcNode::Sum(vec![
+                CalcNode::Length(100.0, Unit::Percent),
+                CalcNode::Negate(Box::new(CalcNode::Length(2.0, Unit::Em))),
+            ]))
+        );
+        let context = LengthContext {
+            percent_base: Some(300.0),
+            font_size: 10.0,
+            root_font_size: 16.0,
+        };
+        assert_eq!(value.resolve(&context), Some(280.0));
+        assert_eq!(value.resolve(&LengthContext::default()), None);
+
+        let source = "clamp(10px, calc((1rem + 4px) * 2 / 4), max(2em, 20%))";
+        let value = Parser::parse_value(&mut get_parser(source)).unwrap();
+        assert_eq!(value.resolve(&context), Some(10.0));
+        let context = LengthContext {
+            root_font_size: 60.0,
+            ..context
+        };
+        assert_eq!(value.resolve(&context), Some(32.0));
+        assert_eq!(
+            value.resolve(&LengthContext {
+                percent_base: Some(100.0),
+                ..context
+            }),
+            Some(20.0)
+        );
+        assert_eq!(
+            value.to_string(),
+            "clamp(10px, (1rem + 4px) * 2 / 4, max(2em, 20%))"
+        );
+    }
+
+    #[test]
+    fn test_math_function_type_checking() {
+        let valid = [
+            "calc(2 * 3px)",
+            "calc(3px / 2)",
+            "min(1px, 2em, 3%)",
+            "calc(-1px)",
+        ];
+        for source in valid {
+            assert!(
+                Parser::parse_value(&mut get_parser(source)).is_some(),
+                "{}",
+                source
+            );
+        }
+        let invalid = [
+            "calc(1px + 2)",
+            "calc(2px * 3px)",
+            "calc(2 / 1px)",
+            "calc(1px -2px)",
+            "calc(1px- 2px)",
+            "clamp(1px, 2px)",
+            "min(1px, 2)",
+            "calc(1px",
+            "calc(1kg)",
+        ];
+        for source in invalid {
+            assert_eq!(
+                Parser::parse_value(&mut get_parser(source)),
+                None,
+                "{}",
+                source
+            );
+        }
+        let declarations =
+            Parser::parse_declarations(&mut get_parser("{ width: calc(1px + 2); height: 1px; }"));
+        assert_eq!(declarations.len(), 1);
+        assert_eq!(declarations[0].name, "height");
+    }
+
+    #[test]
+    fn test_parse_media_rules() {
+        let source = "
+        h1 { margin: auto; }
+        @media screen and (min-width: 600px) {
+          h1 { margin: 0; }
+          @media (prefers-color-scheme: dark) { p { color: #000000; } }
+        }
+        @font-face { font-family: x; }
+        @media print { h2 { margin: 1px; } }
+        h3 { margin: 2px; }
+        ";
+        let rules = Parser::parse_rules(&mut get_parser(source));
+        assert_eq!(rules.len(), 5);
+        assert!(rules[0].conditions.is_empty());
+        assert_eq!(rules[1].conditions.len(), 1);
+        assert_eq!(rules[2].conditions.len(), 2);
+        assert_eq!(rules[2].conditions[0], rules[1].conditions[0]);
+        assert_eq!(
+            rules[2].conditions[1],
+            Condition::Media(MediaQueryList(vec![MediaQuery {
+                negated: false,
+                media_type: "all".to_string(),
+                condition: Some(MediaCondition::Feature(MediaFeature::Plain(
+                    "prefers-color-scheme".to_string(),
+                    Value::Keyword("dark".to_string())
+                ))),
+            }]))
+        );
+        assert_eq!(rules[4].selectors[0].to_string(), "h3");
+        assert!(rules[4].conditions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_media_queries() {
+        let parse = |source: &str| {
+            let mut parser = get_parser(source);
+            MediaQueryList::parse(&parser.parse_prelude())
+        };
+        let px = |length| MediaBound {
+            value: Value::Length(length, Unit::Px),
+            inclusive: true,
+        };
+        let range = |lower, upper| {
+            Some(MediaCondition::Feature(MediaFeature::Range {
+                name: "width".to_string(),
+                lower,
+                upper,
+            }))
+        };
+        let query = |condition| MediaQuery {
+            negated: false,
+            media_type: "all".to_string(),
+            condition,
+        };
+
+        assert_eq!(
+            parse("(min-width: 400px)"),
+            MediaQueryList(vec![query(range(Some(px(400.0)), None))])
+        );
+        assert_eq!(parse("(width >= 400px)"), parse("(min-width: 400px)"));
+        assert_eq!(parse("(400px <= width)"), parse("(min-width: 400px)"));
+        assert_eq!(
+            parse("(400px < width <= 700px)"),
+            MediaQueryList(vec![query(range(
+                Some(MediaBound {
+                    inclusive: false,
+                    ..px(400.0)
+                }),
+                Some(px(700.0))
+            ))])
+        );
+        assert_eq!(
+            parse("(700px >= width > 400px)"),
+            parse("(400px < width <= 700px)")
+        );
+        assert_eq!(
+            parse("not print, ONLY screen and (orientation: landscape)"),
+            MediaQueryList(vec![
+                MediaQuery {
+                    negated: true,
+                    media_type: "print".to_string(),
+                    condition: None,
+                },
+                MediaQuery {
+                    negated: false,
+                    media_type: "screen".to_string(),
+                    condition: Some(MediaCondition::Feature(MediaFeature::Plain(
+                        "orientation".to_string(),
+                        Value::Keyword("landscape".to_string())
+                    ))),
+                },
+            ])
+        );
+        assert_eq!(
+            parse("not ((width) or (height))").0[0].condition,
+            Some(MediaCondition::Not(Box::new(MediaCondition::Or(vec![
+                MediaCondition::Feature(MediaFeature::Boolean("width".to_string())),
+                MediaCondition::Feature(MediaFeature::Boolean("height".to_string())),
+            ]))))
+        );
+        assert_eq!(
+            parse("(width: 10kg)").0[0].condition,
+            Some(MediaCondition::Unknown(vec![
+                Token::Ident("width".to_string()),
+                Token::Delim(':'),
+                Token::Whitespace,
+                Token::Dimension(10.0, "kg".to_string()),
+            ]))
+        );
+
+        let never = MediaQuery {
+            negated: true,
+            media_type: "all".to_string(),
+            condition: None,
+        };
+        // Mixing `and` and `or` needs parentheses; the bad query alone is dropped.
+        assert_eq!(
+            parse("(width) and (height) or (color), print"),
+            MediaQueryList(vec![
+                never.clone(),
+                MediaQuery {
+                    negated: false,
+                    media_type: "print".to_string(),
+                    condition: None,
+                },
+            ])
+        );
+        assert_eq!(parse("screen or (width)"), MediaQueryList(vec![never]));
+        assert_eq!(parse(""), MediaQueryList(Vec::new()));
+    }
+
+    struct MapLoader(Vec<(&'static str, &'static str)>);
+
+    impl Loader for MapLoader {
+        fn load(&mut self, url: &str, _base: &str) -> Option<(String, String)> {
+            let (url, source) = self.0.iter().find(|(name, _)| *name == url)?;
+            Some((url.to_string(), source.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_parse_imports() {
+        let loader = MapLoader(vec![
+            ("a.css", "@import 'b.css' print; a { margin: 1px; }"),
+            ("b.css", "@import url(\"a.css\"); b { margin: 2px; }"),
+            ("c.css", "c { margin: 3px; }"),
+        ]);
+        let source = "@import url(a.css) layer;
+            @import 'missing.css';
+            @import 'c.css' layer(x.y) screen;
+            h1 { margin: 0; }
+            @import 'c.css';"
+            .to_string();
+        let style_sheet = parse_with_loader(source, "main.css".to_string(), loader);
+
+        let summary: Vec<(String, Vec<String>, usize)> = style_sheet
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.selectors[0].to_string(),
+                    rule.layer.clone(),
+                    rule.conditions.len(),
+                )
             })
+            .collect();
+        let layer = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("b".to_string(), layer(&["<anonymous-1>"]), 1),
+                ("a".to_string(), layer(&["<anonymous-1>"]), 0),
+                ("c".to_string(), layer(&["x", "y"]), 1),
+                ("h1".to_string(), Vec::new(), 0),
+            ]
+        );
+        assert_eq!(
+            style_sheet.rules[0].conditions[0],
+            Condition::Media(MediaQueryList(vec![MediaQuery {
+                negated: false,
+                media_type: "print".to_string(),
+                condition: None,
+            }]))
+        );
+    }
+
+    #[test]
+    fn test_parse_layers() {
+        let source = "
+        @layer reset, base;
+        @layer components { @layer buttons { .b { margin: 0; } } .c { margin: 0; } }
+        @layer base.fonts { p { margin: 0; } }
+        @layer { q { margin: 0; } }
+        @layer reset { h2 { margin: 0; } }
+        ";
+        let style_sheet = parse(source.to_string());
+        let layers: Vec<String> = style_sheet
+            .layers
+            .names()
+            .map(|name| name.join("."))
+            .collect();
+        assert_eq!(
+            layers,
+            vec![
+                "reset",
+                "base.fonts",
+                "base",
+                "components.buttons",
+                "components",
+                "<anonymous-1>"
+            ]
+        );
+        let rule_layers: Vec<String> = style_sheet
+            .rules
+            .iter()
+            .map(|rule| rule.layer.join("."))
+            .collect();
+        assert_eq!(
+            rule_layers,
+            vec![
+                "components.buttons",
+                "components",
+                "base.fonts",
+                "<anonymous-1>",
+                "reset"
+            ]
+        );
+        assert_eq!(style_sheet.layers.rank(&[]), 6);
+    }
+
+    #[test]
+    fn test_parse_supports() {
+        let source = "@supports (display: grid) and (not (gap: 1px)) { div { margin: 0; } }
+            @supports selector(p.a) or foo(bar) { p { margin: 0; } }
+            @supports display: grid { q { margin: 0; } }";
+        let rules = Parser::parse_rules(&mut get_parser(source));
+        assert_eq!(rules.len(), 3);
+
+        let ident = |name: &str| Token::Ident(name.to_string());
+        assert_eq!(
+            rules[0].conditions,
+            vec![Condition::Supports(SupportsCondition::And(vec![
+                SupportsCondition::Declaration("display".to_string(), vec![ident("grid")]),
+                SupportsCondition::Not(Box::new(SupportsCondition::Declaration(
+                    "gap".to_string(),
+                    vec![Token::Dimension(1.0, "px".to_string())]
+                ))),
+            ]))]
+        );
+        assert_eq!(
+            rules[1].conditions,
+            vec![Condition::Supports(SupportsCondition::Or(vec![
+                SupportsCondition::Selector(vec![ident("p"), Token::Delim('.'), ident("a")]),
+                SupportsCondition::Unknown(vec![
+                    Token::Function("foo".to_string()),
+                    ident("bar"),
+                    Token::CloseParen
+                ]),
+            ]))]
+        );
+        assert!(matches!(
+            rules[2].conditions[0],
+            Condition::Supports(SupportsCondition::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_selector_from_tokens() {
+        let tokens = |source: &str| {
+            let mut parser = get_parser(source);
+            parser.parse_prelude()
+        };
+        let selector = Selector::from_tokens(&tokens("div#main.a.b[data-x=open][hidden]")).unwrap();
+        assert_eq!(
+            selector.to_string(),
+            "div#main.a.b[data-x=\"open\"][hidden]"
+        );
+        assert!(Selector::from_tokens(&tokens("*")).is_some());
+        assert_eq!(
+            Selector::from_tokens(&tokens("#cart>li  .price~ [data-x = y] + *"))
+                .unwrap()
+                .to_string(),
+            "#cart > li .price ~ [data-x=\"y\"] + *"
+        );
+        assert_eq!(Selector::from_tokens(&tokens("div > > p")), None);
+        assert_eq!(Selector::from_tokens(&tokens("div >")), None);
+        assert_eq!(Selector::from_tokens(&tokens("+ div")), None);
+        assert_eq!(Selector::from_tokens(&tokens("a:hover")), None);
+        assert_eq!(Selector::from_tokens(&tokens("")), None);
+    }
+
+    #[test]
+    fn test_to_css() {
+        let source = "@layer base, theme;
+            h1, .title { margin: 0 auto; color: #ff0000 !important; }
+            @layer theme { @media screen and (min-width: 600px) {
+                p { --gap: calc(1px + 2em); }
+                div { width: calc(100% - var(--gap)); }
+            } }
+            a[href='x'] { display: inline-block; }";
+        let style_sheet = parse(source.to_string());
+        assert_eq!(
+            style_sheet.to_css(Format::Pretty),
+            "@layer base, theme;
+
+h1, .title {
+  margin: 0px auto;
+  color: #ff0000 !important;
+}
+
+@layer theme {
+  @media screen and (width >= 600px) {
+    p {
+      --gap: calc(1px + 2em);
+    }
+    div {
+      width: calc(100% - var(--gap));
+    }
+  }
+}
+
+a[href=\"x\"] {
+  display: inline-block;
+}
+"
+        );
+        assert_eq!(
+            style_sheet.to_css(Format::Minified),
+            "@layer base,theme;h1,.title{margin:0 auto;color:#f00!important}\
+             @layer theme{@media screen and (width >= 600px){p{--gap:calc(1px + 2em)}\
+             div{width:calc(100% - var(--gap))}}}a[href=\"x\"]{display:inline-block}"
+        );
+        assert_eq!(
+            style_sheet.rules[0].to_css(Format::Minified),
+            "h1,.title{margin:0 auto;color:#f00!important}"
         );
     }
+
+    #[test]
+    fn test_to_css_round_trips_at_rules() {
+        let source = "@layer a.b;
+            * { margin: 0; }
+            @supports (display: grid) and (not selector(p > q)) { @media not print, (400px < width <= 700px) { p { margin: 1px } } }
+            @media ((width) or (not (height))) and (orientation: portrait) { q { margin: calc((1px + 2px) + 3px) } }
+            @media screen and ((width) or (height)), (width: 10kg) { q { margin: 2px } }
+            @layer a { @layer b { p { margin: 0 } } r { margin: 0 } }
+            @layer { s { --content: \"it's\" } @media print { t { margin: 0 } } }
+            @layer { u { margin: 0 } }";
+        let style_sheet = parse(source.to_string());
+        for format in [Format::Pretty, Format::Minified] {
+            let output = style_sheet.to_css(format);
+            assert_eq!(parse(output.clone()), style_sheet, "{}", output);
+        }
+    }
+
+    mod round_trip {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn name() -> impl Strategy<Value = String> {
+            prop::sample::select(vec!["a", "p", "div", "main", "x-y", "h1"])
+                .prop_map(str::to_string)
+        }
+
+        fn selector() -> impl Strategy<Value = String> {
+            (
+                compound(),
+                prop::collection::vec(
+                    (
+                        prop::sample::select(vec![" ", " > ", "+", " ~ "]),
+                        compound(),
+                    ),
+                    0..3,
+                ),
+            )
+                .prop_map(|(first, rest)| {
+                    rest.into_iter()
+                        .fold(first, |selector, (combinator, compound)| {
+                            selector + combinator + &compound
+                        })
+                })
+        }
+
+        fn compound() -> impl Strategy<Value = String> {
+            (
+                prop::sample::select(vec!["", "", "*|", "|", "svg|"]),
+                prop::option::of(name()),
+                prop::option::of(name()),
+                prop::collection::vec(name(), 0..3),
+                prop::collection::vec((name(), prop::option::of(name())), 0..2),
+            )
+                .prop_map(|(prefix, tag, id, classes, attributes)| {
+                    let mut selector = match tag {
+                        Some(tag) => prefix.to_string() + &tag,
+                        None if !prefix.is_empty() => prefix.to_string() + "*",
+                        None => String::new(),
+                    };
+                    if let Some(id) = id {
+                        selector += &format!("#{}", id);
+                    }
+                    for class in classes {
+                        selector += &format!(".{}", class);
+                    }
+                    for (name, value) in attributes {
+                        selector += &match value {
+                            Some(value) => format!("[{}='{}']", name, value),
+                            None => format!("[{}]", name),
+                        };
+                    }
+                    if selector.is_empty() {
+                        selector.push('*');
+                    }
+                    selector
+                })
+        }
+
+        fn length() -> impl Strategy<Value = String> {
+            (
+                -500i32..500,
+                prop::sample::select(vec!["px", "em", "rem", "%", ""]),
+            )
+                .prop_map(|(tenths, unit)| format!("{}{}", tenths as f32 / 10.0, unit))
+        }
+
+        fn value() -> impl Strategy<Value = String> {
+            prop_oneof![
+                length(),
+                prop::sample::select(vec!["auto", "block", "inline flex", "none"])
+                    .prop_map(str::to_string),
+                (0u32..0xffffff).prop_map(|color| format!("#{:06x}", color)),
+                (0u32..0xfff).prop_map(|color| format!("#{:03x}", color)),
+                (length(), length(), 1i32..5).prop_map(|(a, b, n)| format!(
+                    "calc(({} + {}) * {} - min({}, 1px))",
+                    a, b, n, a
+                )),
+                (length(), length()).prop_map(|(a, b)| format!("{} {}", a, b)),
+                name().prop_map(|name| format!("var(--{}, 1px)", name)),
+            ]
+        }
+
+        fn rule() -> impl Strategy<Value = String> {
+            let declaration = (
+                prop_oneof![name(), name().prop_map(|name| format!("--{}", name))],
+                value(),
+                any::<bool>(),
+            )
+                .prop_map(|(name, value, important)| {
+                    let important = if important { " !important" } else { "" };
+                    format!("{}: {}{};", name, value, important)
+                });
+            (
+                prop::collection::vec(selector(), 1..3),
+                prop::collection::vec(declaration, 0..4),
+            )
+                .prop_map(|(selectors, declarations)| {
+                    format!("{} {{ {} }}", selectors.join(", "), declarations.join(" "))
+                })
+        }
+
+        fn items() -> impl Strategy<Value = String> {
+            let media = prop::sample::select(vec![
+                "screen",
+                "not print",
+                "only screen and (min-width: 600px), print",
+                "(400px <= width < 700px)",
+                "(width) or (not (orientation: portrait))",
+                "screen and ((width) or (height))",
+                "(hover: hover)",
+            ]);
+            let supports = prop::sample::select(vec![
+                "(display: grid)",
+                "not (gap: 1px)",
+                "(width: 1px) and (not selector(p > q))",
+                "selector(.a) or foo(bar)",
+            ]);
+            let layer = prop::sample::select(vec!["reset", "base", "base.fonts", "theme"]);
+            let leaf = prop_oneof![
+                rule(),
+                prop::collection::vec(layer.clone(), 1..3)
+                    .prop_map(|names| format!("@layer {};", names.join(", "))),
+            ];
+            leaf.prop_recursive(3, 24, 4, move |inner| {
+                let block = prop::collection::vec(inner, 0..4).prop_map(|items| items.join("\n"));
+                prop_oneof![
+                    (media.clone(), block.clone())
+                        .prop_map(|(media, block)| format!("@media {} {{ {} }}", media, block)),
+                    (supports.clone(), block.clone()).prop_map(|(supports, block)| {
+                        format!("@supports {} {{ {} }}", supports, block)
+                    }),
+                    (layer.clone(), block.clone())
+                        .prop_map(|(layer, block)| format!("@layer {} {{ {} }}", layer, block)),
+                    block,
+                ]
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn parse_of_serialized_sheet_is_unchanged(source in items()) {
+                let style_sheet =
+                    parse(format!("@namespace svg url(http://www.w3.org/2000/svg);\n{}", source));
+                for format in [Format::Pretty, Format::Minified] {
+                    let output = style_sheet.to_css(format);
+                    prop_assert_eq!(&parse(output), &style_sheet);
+                }
+            }
+        }
+    }
 }
@@ -1,57 +1,1294 @@
-use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
 
+use crate::{atom::Atom, css, source::Span, style};
+
+/// A handle to a node of a `Tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// A DOM tree stored as an arena of nodes that refer to their parent,
+/// siblings and children by `NodeId`. The root is the first node.
 #[derive(Debug, PartialEq, Eq)]
+pub struct Tree {
+    nodes: Vec<Node>,
+    records: Vec<MutationRecord>,
+}
+
+#[derive(Debug)]
 pub struct Node {
     pub node_type: NodeType,
-    pub children: Vec<Node>,
+    parent: Option<NodeId>,
+    previous_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    /// Where the node was parsed from, or `None` if it wasn't.
+    span: Option<Span>,
+}
+
+// Where a node was parsed from doesn't change what it is.
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.node_type == other.node_type
+            && self.parent == other.parent
+            && self.previous_sibling == other.previous_sibling
+            && self.next_sibling == other.next_sibling
+            && self.first_child == other.first_child
+            && self.last_child == other.last_child
+    }
 }
 
+impl Eq for Node {}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum NodeType {
     Text(String),
     Element(ElementData),
-    Comment,
+    Comment(String),
+    /// The root of a `Document`, holding its top-level nodes.
+    Document,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct ElementData {
-    pub tag_name: String,
+    pub namespace: Namespace,
+    pub tag_name: Atom,
     pub attributes: AttrMap,
 }
 
 impl ElementData {
-    pub fn id(&self) -> Option<&String> {
-        self.attributes.get("id")
+    /// Whether this is the HTML element `tag_name`, rather than an SVG or
+    /// MathML element of the same name.
+    pub fn is_html(&self, tag_name: &str) -> bool {
+        self.namespace == Namespace::Html && self.tag_name == tag_name
+    }
+
+    pub fn id(&self) -> Option<&Atom> {
+        self.attributes.id.as_ref()
+    }
+
+    /// The element's classes, in order and without duplicates.
+    pub fn classes(&self) -> &[Atom] {
+        &self.attributes.classes
+    }
+
+    /// The element's classes as a list to edit.
+    pub fn class_list(&self) -> ClassList {
+        ClassList::new(self.attributes.get("class").map_or("", String::as_str))
+    }
+}
+
+/// The namespace of an element: HTML, or foreign content embedded in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    #[default]
+    Html,
+    Svg,
+    MathMl,
+}
+
+impl Namespace {
+    pub fn url(self) -> &'static str {
+        match self {
+            Namespace::Html => "http://www.w3.org/1999/xhtml",
+            Namespace::Svg => "http://www.w3.org/2000/svg",
+            Namespace::MathMl => "http://www.w3.org/1998/Math/MathML",
+        }
+    }
+
+    pub fn from_url(url: &str) -> Option<Namespace> {
+        [Namespace::Html, Namespace::Svg, Namespace::MathMl]
+            .into_iter()
+            .find(|namespace| namespace.url() == url)
+    }
+}
+
+/// The attributes of an element, in the order they were first set. The `id`
+/// and the classes are also kept as atoms, for selector matching.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttrMap {
+    entries: Vec<(Atom, String)>,
+    id: Option<Atom>,
+    classes: Vec<Atom>,
+}
+
+impl AttrMap {
+    pub fn new() -> AttrMap {
+        AttrMap::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// `get` for a name already interned, such as one from a selector,
+    /// compared by pointer.
+    pub fn get_atom(&self, name: &Atom) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set attribute `name`, returning its old value. A new attribute goes
+    /// last; an existing one keeps its place.
+    pub fn insert(&mut self, name: impl Into<Atom>, value: String) -> Option<String> {
+        let name = name.into();
+        self.update_atoms(&name, Some(&value));
+        match self.entries.iter_mut().find(|(key, _)| *key == name) {
+            Some((_, old)) => Some(std::mem::replace(old, value)),
+            None => {
+                self.entries.push((name, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let index = self.entries.iter().position(|(key, _)| *key == name)?;
+        let (name, value) = self.entries.remove(index);
+        self.update_atoms(&name, None);
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn update_atoms(&mut self, name: &Atom, value: Option<&str>) {
+        if name == "id" {
+            self.id = value.map(Atom::new);
+        } else if name == "class" {
+            let classes = ClassList::new(value.unwrap_or(""));
+            self.classes = classes.iter().map(Atom::new).collect();
+        }
+    }
+}
+
+/// The classes of a `class` attribute, in the manner of a DOM `DOMTokenList`:
+/// split on ASCII whitespace, in order, without duplicates. Set it back with
+/// `Tree::set_attribute(id, "class", &list.to_string())`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassList {
+    tokens: Vec<String>,
+}
+
+impl ClassList {
+    pub fn new(value: &str) -> ClassList {
+        let mut list = ClassList::default();
+        for token in value.split_ascii_whitespace() {
+            list.add(token);
+        }
+        list
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.tokens.iter().any(|t| t == token)
+    }
+
+    /// Add `token` at the end, unless it is already in the list.
+    pub fn add(&mut self, token: &str) {
+        if !self.contains(token) {
+            self.tokens.push(token.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, token: &str) {
+        self.tokens.retain(|t| t != token);
+    }
+
+    /// Remove `token` if it is in the list and add it otherwise. Returns
+    /// whether it is in the list afterwards.
+    pub fn toggle(&mut self, token: &str) -> bool {
+        if self.contains(token) {
+            self.remove(token);
+            false
+        } else {
+            self.add(token);
+            true
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.tokens.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl fmt::Display for ClassList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tokens.join(" "))
+    }
+}
+
+/// An owned subtree, made with `element`, `text` and `comment`, to build a
+/// `Tree` from.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NodeBuilder {
+    pub node_type: NodeType,
+    pub children: Vec<NodeBuilder>,
+}
+
+impl NodeBuilder {
+    /// A tree with this node as its root.
+    pub fn build(self) -> Tree {
+        let mut tree = Tree {
+            nodes: Vec::new(),
+            records: Vec::new(),
+        };
+        tree.create(self);
+        tree
+    }
+}
+
+/// A change made through the mutation methods of `Tree`, in the manner of a
+/// DOM `MutationRecord`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationRecord {
+    /// Children of `target` were added or removed between `previous_sibling`
+    /// and `next_sibling`.
+    ChildList {
+        target: NodeId,
+        added: Vec<NodeId>,
+        removed: Vec<NodeId>,
+        previous_sibling: Option<NodeId>,
+        next_sibling: Option<NodeId>,
+    },
+    /// Attribute `name` of `target` was set or removed.
+    Attributes {
+        target: NodeId,
+        name: String,
+        old_value: Option<String>,
+    },
+    /// The text of `target` was changed.
+    CharacterData { target: NodeId, old_value: String },
+}
+
+/// Why a mutation of a `Tree` was refused. The tree is left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MutationError {
+    /// The node would become a child of a node that can't have children, or
+    /// of itself or one of its descendants.
+    HierarchyRequest,
+    /// The reference node isn't a child of the given parent.
+    NotFound,
+    /// The node doesn't have the kind of data being changed.
+    InvalidNodeType,
+}
+
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MutationError::HierarchyRequest => "the node can't be inserted there",
+            MutationError::NotFound => "the reference node is not a child of the parent",
+            MutationError::InvalidNodeType => "the node has no such data",
+        })
+    }
+}
+
+impl Tree {
+    pub fn root(&self) -> NodeRef<'_> {
+        self.get(NodeId(0))
+    }
+
+    pub fn get(&self, id: NodeId) -> NodeRef<'_> {
+        NodeRef { tree: self, id }
+    }
+
+    /// Add `node` and its descendants to the arena without a parent, ready
+    /// to be inserted.
+    pub fn create(&mut self, node: NodeBuilder) -> NodeId {
+        self.nodes.push(Node {
+            node_type: node.node_type,
+            parent: None,
+            previous_sibling: None,
+            next_sibling: None,
+            first_child: None,
+            last_child: None,
+            span: None,
+        });
+        let id = NodeId(self.nodes.len() - 1);
+        for child in node.children {
+            let child = self.create(child);
+            self.link(id, child, None);
+        }
+        id
+    }
+
+    /// Create `node` as the last child of `parent` without recording a
+    /// mutation, as the parser adds nodes.
+    pub(crate) fn push_child(&mut self, parent: NodeId, node: NodeBuilder) -> NodeId {
+        let child = self.create(node);
+        self.link(parent, child, None);
+        child
+    }
+
+    /// Record where node `id` was parsed from.
+    pub(crate) fn set_span(&mut self, id: NodeId, span: Span) {
+        self.nodes[id.0].span = Some(span);
+    }
+
+    /// The records of the mutations made since the last call.
+    pub fn take_records(&mut self) -> Vec<MutationRecord> {
+        std::mem::take(&mut self.records)
+    }
+
+    /// Make `child` the last child of `parent`, moving it from its current
+    /// parent if it has one.
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), MutationError> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Make `child` a child of `parent` just before `reference`, or last when
+    /// `reference` is `None`, moving it from its current parent if it has one.
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<(), MutationError> {
+        self.check_insert(parent, child)?;
+        if reference.is_some_and(|reference| self.nodes[reference.0].parent != Some(parent)) {
+            return Err(MutationError::NotFound);
+        }
+        let reference = match reference {
+            Some(reference) if reference == child => self.nodes[child.0].next_sibling,
+            _ => reference,
+        };
+
+        if self.nodes[child.0].parent.is_some() {
+            self.remove(child)?;
+        }
+        self.link(parent, child, reference);
+        self.records.push(MutationRecord::ChildList {
+            target: parent,
+            added: vec![child],
+            removed: Vec::new(),
+            previous_sibling: self.nodes[child.0].previous_sibling,
+            next_sibling: reference,
+        });
+        Ok(())
+    }
+
+    /// Take `child` out of its parent. It stays in the arena and can be
+    /// inserted again.
+    pub fn remove(&mut self, child: NodeId) -> Result<(), MutationError> {
+        let node = &self.nodes[child.0];
+        let parent = node.parent.ok_or(MutationError::NotFound)?;
+        let record = MutationRecord::ChildList {
+            target: parent,
+            added: Vec::new(),
+            removed: vec![child],
+            previous_sibling: node.previous_sibling,
+            next_sibling: node.next_sibling,
+        };
+        self.unlink(child);
+        self.records.push(record);
+        Ok(())
+    }
+
+    /// Put `new` in the place of `old`, a child of `parent`, moving `new`
+    /// from its current parent if it has one.
+    pub fn replace_child(
+        &mut self,
+        parent: NodeId,
+        new: NodeId,
+        old: NodeId,
+    ) -> Result<(), MutationError> {
+        self.check_insert(parent, new)?;
+        if self.nodes[old.0].parent != Some(parent) {
+            return Err(MutationError::NotFound);
+        }
+        if new == old {
+            return Ok(());
+        }
+
+        let mut next_sibling = self.nodes[old.0].next_sibling;
+        if next_sibling == Some(new) {
+            next_sibling = self.nodes[new.0].next_sibling;
+        }
+        if self.nodes[new.0].parent.is_some() {
+            self.remove(new)?;
+        }
+        let previous_sibling = self.nodes[old.0].previous_sibling;
+        self.unlink(old);
+        self.link(parent, new, next_sibling);
+        self.records.push(MutationRecord::ChildList {
+            target: parent,
+            added: vec![new],
+            removed: vec![old],
+            previous_sibling,
+            next_sibling,
+        });
+        Ok(())
+    }
+
+    /// Set attribute `name` of element `id`, returning its old value.
+    pub fn set_attribute(
+        &mut self,
+        id: NodeId,
+        name: &str,
+        value: &str,
+    ) -> Result<Option<String>, MutationError> {
+        let old_value = self
+            .element_mut(id)?
+            .attributes
+            .insert(name.to_string(), value.to_string());
+        self.records.push(MutationRecord::Attributes {
+            target: id,
+            name: name.to_string(),
+            old_value: old_value.clone(),
+        });
+        Ok(old_value)
+    }
+
+    /// Remove attribute `name` of element `id`, returning its old value.
+    /// Nothing is recorded when the attribute isn't there.
+    pub fn remove_attribute(
+        &mut self,
+        id: NodeId,
+        name: &str,
+    ) -> Result<Option<String>, MutationError> {
+        let old_value = self.element_mut(id)?.attributes.remove(name);
+        if old_value.is_some() {
+            self.records.push(MutationRecord::Attributes {
+                target: id,
+                name: name.to_string(),
+                old_value: old_value.clone(),
+            });
+        }
+        Ok(old_value)
+    }
+
+    /// Change the data of text node `id`, or replace the children of element
+    /// `id` with a single text node, like setting `textContent`.
+    pub fn set_text(&mut self, id: NodeId, data: &str) -> Result<(), MutationError> {
+        match self.nodes[id.0].node_type {
+            NodeType::Text(ref mut text) => {
+                let old_value = std::mem::replace(text, data.to_string());
+                self.records.push(MutationRecord::CharacterData {
+                    target: id,
+                    old_value,
+                });
+            }
+            NodeType::Element(_) => {
+                let removed: Vec<NodeId> = self.get(id).children().map(NodeRef::id).collect();
+                for &child in &removed {
+                    self.unlink(child);
+                }
+                let mut added = Vec::new();
+                if !data.is_empty() {
+                    let child = self.create(text(data.to_string()));
+                    self.link(id, child, None);
+                    added.push(child);
+                }
+                if !removed.is_empty() || !added.is_empty() {
+                    self.records.push(MutationRecord::ChildList {
+                        target: id,
+                        added,
+                        removed,
+                        previous_sibling: None,
+                        next_sibling: None,
+                    });
+                }
+            }
+            NodeType::Comment(_) | NodeType::Document => {
+                return Err(MutationError::InvalidNodeType)
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge adjacent text nodes below `id` into the first of them and
+    /// remove empty ones, like `Node.normalize()`.
+    pub fn normalize(&mut self, id: NodeId) {
+        let parents: Vec<NodeId> = self
+            .get(id)
+            .descendants()
+            .filter(|node| node.first_child().is_some())
+            .map(NodeRef::id)
+            .collect();
+        for parent in parents {
+            let children: Vec<NodeId> = self.get(parent).children().map(NodeRef::id).collect();
+            let mut previous_text: Option<NodeId> = None;
+            for child in children {
+                let data = match self.nodes[child.0].node_type {
+                    NodeType::Text(ref data) => data.clone(),
+                    _ => {
+                        previous_text = None;
+                        continue;
+                    }
+                };
+                if data.is_empty() {
+                    self.remove(child).expect("a child has a parent");
+                } else if let Some(previous) = previous_text {
+                    if let NodeType::Text(ref mut text) = self.nodes[previous.0].node_type {
+                        let old_value = text.clone();
+                        text.push_str(&data);
+                        self.records.push(MutationRecord::CharacterData {
+                            target: previous,
+                            old_value,
+                        });
+                    }
+                    self.remove(child).expect("a child has a parent");
+                } else {
+                    previous_text = Some(child);
+                }
+            }
+        }
+    }
+
+    fn element_mut(&mut self, id: NodeId) -> Result<&mut ElementData, MutationError> {
+        match self.nodes[id.0].node_type {
+            NodeType::Element(ref mut elem) => Ok(elem),
+            _ => Err(MutationError::InvalidNodeType),
+        }
+    }
+
+    fn check_insert(&self, parent: NodeId, child: NodeId) -> Result<(), MutationError> {
+        let parent_ref = self.get(parent);
+        let is_inclusive_ancestor =
+            parent == child || parent_ref.ancestors().any(|ancestor| ancestor.id == child);
+        let can_have_children = matches!(
+            parent_ref.node_type,
+            NodeType::Element(_) | NodeType::Document
+        );
+        if !can_have_children || is_inclusive_ancestor {
+            return Err(MutationError::HierarchyRequest);
+        }
+        Ok(())
+    }
+
+    /// Insert `child`, which has no parent, before `reference` or last.
+    fn link(&mut self, parent: NodeId, child: NodeId, reference: Option<NodeId>) {
+        let previous = match reference {
+            Some(reference) => self.nodes[reference.0].previous_sibling,
+            None => self.nodes[parent.0].last_child,
+        };
+        let node = &mut self.nodes[child.0];
+        node.parent = Some(parent);
+        node.previous_sibling = previous;
+        node.next_sibling = reference;
+        match previous {
+            Some(previous) => self.nodes[previous.0].next_sibling = Some(child),
+            None => self.nodes[parent.0].first_child = Some(child),
+        }
+        match reference {
+            Some(reference) => self.nodes[reference.0].previous_sibling = Some(child),
+            None => self.nodes[parent.0].last_child = Some(child),
+        }
+    }
+
+    fn unlink(&mut self, child: NodeId) {
+        let node = &mut self.nodes[child.0];
+        let (Some(parent), previous, next) = (
+            node.parent.take(),
+            node.previous_sibling.take(),
+            node.next_sibling.take(),
+        ) else {
+            return;
+        };
+        match previous {
+            Some(previous) => self.nodes[previous.0].next_sibling = next,
+            None => self.nodes[parent.0].first_child = next,
+        }
+        match next {
+            Some(next) => self.nodes[next.0].previous_sibling = previous,
+            None => self.nodes[parent.0].last_child = previous,
+        }
+    }
+}
+
+/// A node together with the tree it belongs to, for walking the tree from
+/// it. Dereferences to the `Node`.
+#[derive(Clone, Copy)]
+pub struct NodeRef<'a> {
+    tree: &'a Tree,
+    id: NodeId,
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn id(self) -> NodeId {
+        self.id
+    }
+
+    pub fn tree(self) -> &'a Tree {
+        self.tree
+    }
+
+    pub fn node(self) -> &'a Node {
+        &self.tree.nodes[self.id.0]
+    }
+
+    /// Where the node was parsed from: for an element, from its start tag
+    /// to its end tag. `None` for nodes made other than by parsing.
+    pub fn span(self) -> Option<Span> {
+        self.node().span
+    }
+
+    pub fn element(self) -> Option<&'a ElementData> {
+        match self.node().node_type {
+            NodeType::Element(ref elem) => Some(elem),
+            _ => None,
+        }
+    }
+
+    pub fn parent(self) -> Option<NodeRef<'a>> {
+        self.link(self.node().parent)
+    }
+
+    pub fn previous_sibling(self) -> Option<NodeRef<'a>> {
+        self.link(self.node().previous_sibling)
+    }
+
+    pub fn next_sibling(self) -> Option<NodeRef<'a>> {
+        self.link(self.node().next_sibling)
+    }
+
+    pub fn first_child(self) -> Option<NodeRef<'a>> {
+        self.link(self.node().first_child)
+    }
+
+    pub fn last_child(self) -> Option<NodeRef<'a>> {
+        self.link(self.node().last_child)
+    }
+
+    fn link(self, id: Option<NodeId>) -> Option<NodeRef<'a>> {
+        id.map(|id| self.tree.get(id))
+    }
+
+    pub fn children(self) -> impl Iterator<Item = NodeRef<'a>> {
+        std::iter::successors(self.first_child(), |child| child.next_sibling())
+    }
+
+    /// The parent, grandparent and so on up to the root.
+    pub fn ancestors(self) -> impl Iterator<Item = NodeRef<'a>> {
+        std::iter::successors(self.parent(), |ancestor| ancestor.parent())
+    }
+
+    /// This node and everything below it, in document order.
+    pub fn descendants(self) -> impl Iterator<Item = NodeRef<'a>> {
+        std::iter::successors(Some(self), move |node| node.following_within(self))
+    }
+
+    /// The node after this one in document order, without leaving the
+    /// subtree of `root`.
+    fn following_within(self, root: NodeRef<'a>) -> Option<NodeRef<'a>> {
+        if let Some(child) = self.first_child() {
+            return Some(child);
+        }
+        let mut node = self;
+        while node != root {
+            if let Some(sibling) = node.next_sibling() {
+                return Some(sibling);
+            }
+            node = node.parent()?;
+        }
+        None
+    }
+
+    /// Find an element by `#id`, or by a path of tag names from this node
+    /// down such as `html/div/p[2]`, where `[n]` picks the n-th child with
+    /// that tag (1-based). Returns the element's ancestors from this node
+    /// down, followed by the element itself.
+    pub fn lineage(self, target: &str) -> Option<Vec<NodeRef<'a>>> {
+        match target.strip_prefix('#') {
+            Some(id) => self.lineage_by_id(id),
+            None => self.lineage_by_path(target),
+        }
+    }
+
+    fn lineage_by_id(self, id: &str) -> Option<Vec<NodeRef<'a>>> {
+        let node = self.descendants().find(|node| {
+            node.element()
+                .and_then(|elem| elem.id())
+                .is_some_and(|atom| atom == id)
+        })?;
+        let mut lineage = vec![node];
+        if node != self {
+            lineage.extend(node.ancestors().take_while(|&ancestor| ancestor != self));
+            lineage.push(self);
+        }
+        lineage.reverse();
+        Some(lineage)
+    }
+
+    fn lineage_by_path(self, path: &str) -> Option<Vec<NodeRef<'a>>> {
+        let mut steps = path.split('/').filter(|step| !step.is_empty());
+        let (tag_name, _) = parse_path_step(steps.next()?)?;
+        if self.element()?.tag_name != tag_name {
+            return None;
+        }
+
+        let mut lineage = vec![self];
+        for step in steps {
+            let (tag_name, index) = parse_path_step(step)?;
+            let node = lineage
+                .last()?
+                .children()
+                .filter(|child| child.element().map(|elem| &*elem.tag_name) == Some(tag_name))
+                .nth(index.checked_sub(1)?)?;
+            lineage.push(node);
+        }
+        Some(lineage)
+    }
+
+    /// The first element below this node that matches `selectors`, a
+    /// comma-separated list such as `#cart .price`.
+    pub fn query_selector(self, selectors: &str) -> Result<Option<NodeRef<'a>>, InvalidSelector> {
+        let selectors = parse_selectors(selectors)?;
+        Ok(self
+            .descendants()
+            .skip(1)
+            .find(|&node| matches_any(node, &selectors)))
+    }
+
+    /// Every element below this node that matches `selectors`, in document
+    /// order.
+    pub fn query_selector_all(self, selectors: &str) -> Result<Vec<NodeRef<'a>>, InvalidSelector> {
+        let selectors = parse_selectors(selectors)?;
+        Ok(self
+            .descendants()
+            .skip(1)
+            .filter(|&node| matches_any(node, &selectors))
+            .collect())
+    }
+
+    /// Whether this node is an element matching `selectors`.
+    pub fn matches(self, selectors: &str) -> Result<bool, InvalidSelector> {
+        Ok(matches_any(self, &parse_selectors(selectors)?))
+    }
+
+    /// This node or its nearest ancestor that matches `selectors`.
+    pub fn closest(self, selectors: &str) -> Result<Option<NodeRef<'a>>, InvalidSelector> {
+        let selectors = parse_selectors(selectors)?;
+        Ok(std::iter::once(self)
+            .chain(self.ancestors())
+            .find(|&node| matches_any(node, &selectors)))
+    }
+}
+
+/// A selector string passed to a query method that doesn't parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSelector(pub String);
+
+impl fmt::Display for InvalidSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid selector: {}", self.0)
+    }
+}
+
+fn parse_selectors(selectors: &str) -> Result<Vec<css::Selector>, InvalidSelector> {
+    css::parse_selector_list(selectors).ok_or_else(|| InvalidSelector(selectors.to_string()))
+}
+
+fn matches_any(node: NodeRef, selectors: &[css::Selector]) -> bool {
+    selectors
+        .iter()
+        .any(|selector| style::matches(node, selector))
+}
+
+impl Deref for NodeRef<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        self.node()
+    }
+}
+
+impl PartialEq for NodeRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.tree, other.tree) && self.id == other.id
+    }
+}
+
+impl Eq for NodeRef<'_> {}
+
+impl fmt::Debug for NodeRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NodeRef")
+            .field("id", &self.id)
+            .field("node_type", &self.node_type)
+            .field("span", &self.span())
+            .finish()
+    }
+}
+
+/// A parsed page: its top-level nodes under a `NodeType::Document` root,
+/// with the doctype that came before them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Document {
+    pub tree: Tree,
+    pub doctype: Option<Doctype>,
+    pub quirks_mode: QuirksMode,
+}
+
+/// `<!DOCTYPE name PUBLIC "public_id" "system_id">`. The ids are `None`
+/// when left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+/// How closely a document asks to follow the standards, decided by its
+/// doctype.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+/// Public identifiers of old doctypes that put a document in quirks mode.
+const QUIRKY_PUBLIC_ID_PREFIXES: [&str; 24] = [
+    "+//silmaril//dtd html pro v0r11 19970101//",
+    "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+    "-//ietf//dtd html 2.0",
+    "-//ietf//dtd html 3",
+    "-//ietf//dtd html level ",
+    "-//ietf//dtd html strict",
+    "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer ",
+    "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//",
+    "-//o'reilly and associates//dtd html ",
+    "-//softquad software//dtd hotmetal pro ",
+    "-//softquad//dtd hotmetal pro ",
+    "-//spyglass//dtd html 2.0 extended//",
+    "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//",
+    "-//w3c//dtd html 3",
+    "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//",
+    "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//",
+    "-//webtechs//dtd mozilla html",
+];
+
+impl Doctype {
+    /// The mode a document with this doctype is rendered in.
+    pub fn quirks_mode(&self) -> QuirksMode {
+        let public_id = self.public_id.as_deref().unwrap_or("").to_ascii_lowercase();
+        let system_id = self.system_id.as_deref().map(str::to_ascii_lowercase);
+        let html4_transitional = [
+            "-//w3c//dtd html 4.01 frameset//",
+            "-//w3c//dtd html 4.01 transitional//",
+        ]
+        .iter()
+        .any(|prefix| public_id.starts_with(prefix));
+        let quirky = !self.name.eq_ignore_ascii_case("html")
+            || [
+                "-//w3o//dtd w3 html strict 3.0//en//",
+                "-/w3c/dtd html 4.0 transitional/en",
+                "html",
+            ]
+            .contains(&public_id.as_str())
+            || QUIRKY_PUBLIC_ID_PREFIXES
+                .iter()
+                .any(|prefix| public_id.starts_with(prefix))
+            || system_id.as_deref()
+                == Some("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+            || html4_transitional && system_id.is_none();
+        let limited = [
+            "-//w3c//dtd xhtml 1.0 frameset//",
+            "-//w3c//dtd xhtml 1.0 transitional//",
+        ]
+        .iter()
+        .any(|prefix| public_id.starts_with(prefix))
+            || html4_transitional;
+        if quirky {
+            QuirksMode::Quirks
+        } else if limited {
+            QuirksMode::LimitedQuirks
+        } else {
+            QuirksMode::NoQuirks
+        }
+    }
+}
+
+impl Document {
+    /// A document holding `children` at the top level. Without a doctype it
+    /// is in quirks mode.
+    pub fn new(doctype: Option<Doctype>, children: Vec<NodeBuilder>) -> Document {
+        let tree = NodeBuilder {
+            node_type: NodeType::Document,
+            children,
+        }
+        .build();
+        Document::from_tree(doctype, tree)
+    }
+
+    /// A document for `tree`, whose root must be a `NodeType::Document`.
+    pub fn from_tree(doctype: Option<Doctype>, tree: Tree) -> Document {
+        let quirks_mode = doctype
+            .as_ref()
+            .map_or(QuirksMode::Quirks, Doctype::quirks_mode);
+        Document {
+            tree,
+            doctype,
+            quirks_mode,
+        }
+    }
+
+    /// The document node, parent of the top-level nodes.
+    pub fn root(&self) -> NodeRef<'_> {
+        self.tree.root()
+    }
+
+    /// The top-level element, usually `<html>`.
+    pub fn document_element(&self) -> Option<NodeRef<'_>> {
+        self.root().children().find(|node| node.element().is_some())
+    }
+
+    /// The text of the first `<title>`, with whitespace collapsed.
+    pub fn title(&self) -> Option<String> {
+        let title = self.first_element("title")?;
+        let text: String = title
+            .children()
+            .filter_map(|child| match &child.node().node_type {
+                NodeType::Text(data) => Some(data.as_str()),
+                _ => None,
+            })
+            .collect();
+        Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    /// The `href` of the first `<base>` that has one, which relative URLs in
+    /// the document resolve against.
+    pub fn base_url(&self) -> Option<&str> {
+        self.root()
+            .descendants()
+            .filter_map(|node| node.element())
+            .filter(|elem| elem.is_html("base"))
+            .find_map(|elem| elem.attributes.get("href"))
+            .map(String::as_str)
+    }
+
+    /// The character encoding declared by `<meta charset>`, or by the
+    /// `charset=` of a `<meta http-equiv="content-type">`, lowercased.
+    pub fn charset(&self) -> Option<String> {
+        self.root()
+            .descendants()
+            .filter_map(|node| node.element())
+            .filter(|elem| elem.is_html("meta"))
+            .find_map(|elem| {
+                if let Some(charset) = elem.attributes.get("charset") {
+                    return Some(charset.trim().to_ascii_lowercase());
+                }
+                let http_equiv = elem.attributes.get("http-equiv")?;
+                if !http_equiv.eq_ignore_ascii_case("content-type") {
+                    return None;
+                }
+                let content = elem.attributes.get("content")?.to_ascii_lowercase();
+                let (_, charset) = content.split_once("charset=")?;
+                let quotes = |char: char| char == '"' || char == '\'' || char.is_whitespace();
+                Some(charset.trim_matches(quotes).to_string())
+            })
     }
 
-    pub fn classes(&self) -> HashSet<&str> {
-        match self.attributes.get("class") {
-            Some(class_list) => class_list.split(' ').collect(),
-            None => HashSet::new(),
+    /// The `lang` of the document element.
+    pub fn lang(&self) -> Option<&str> {
+        self.document_element()?
+            .element()?
+            .attributes
+            .get("lang")
+            .map(String::as_str)
+    }
+
+    /// Serialize the document as HTML, starting with its doctype.
+    pub fn to_html(&self, format: Format) -> String {
+        let mut html = String::new();
+        if let Some(ref doctype) = self.doctype {
+            html.push_str(&format!("<!DOCTYPE {}>", doctype.name));
+            if format == Format::Pretty {
+                html.push('\n');
+            }
         }
+        html.push_str(&self.root().outer_html(format));
+        html
+    }
+
+    fn first_element(&self, tag_name: &str) -> Option<NodeRef<'_>> {
+        self.root()
+            .descendants()
+            .find(|node| node.element().is_some_and(|elem| elem.is_html(tag_name)))
     }
 }
 
-type AttrMap = HashMap<String, String>;
+/// How `NodeRef::outer_html` and `NodeRef::inner_html` lay out their output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The tree's text as it is, so the markup parses back to an equal tree.
+    Exact,
+    /// One node per line, indented two spaces per level, with text trimmed.
+    /// Elements holding only text stay on one line.
+    Pretty,
+}
 
-pub fn text(data: String) -> Node {
-    Node {
+/// Elements that have no end tag and never have children.
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose text is written without escaping.
+const RAW_TEXT_ELEMENTS: [&str; 6] = ["iframe", "noembed", "noframes", "script", "style", "xmp"];
+
+/// Elements whose text is read without tags, but with character references.
+const ESCAPABLE_RAW_TEXT_ELEMENTS: [&str; 2] = ["textarea", "title"];
+
+/// Elements whose first newline the parser drops, so it is written back.
+const LEADING_NEWLINE_ELEMENTS: [&str; 3] = ["listing", "pre", "textarea"];
+
+pub fn is_void_element(tag_name: &str) -> bool {
+    VOID_ELEMENTS.contains(&tag_name)
+}
+
+pub fn is_raw_text_element(tag_name: &str) -> bool {
+    RAW_TEXT_ELEMENTS.contains(&tag_name)
+}
+
+pub fn drops_leading_newline(tag_name: &str) -> bool {
+    LEADING_NEWLINE_ELEMENTS.contains(&tag_name)
+}
+
+pub fn is_escapable_raw_text_element(tag_name: &str) -> bool {
+    ESCAPABLE_RAW_TEXT_ELEMENTS.contains(&tag_name)
+}
+
+impl NodeRef<'_> {
+    /// Serialize this node and its descendants as HTML.
+    pub fn outer_html(self, format: Format) -> String {
+        let mut writer = HtmlWriter {
+            format,
+            output: String::new(),
+        };
+        writer.node(self, writer.line(0), false);
+        writer.output
+    }
+
+    /// Serialize the children of this node as HTML.
+    pub fn inner_html(self, format: Format) -> String {
+        let mut writer = HtmlWriter {
+            format,
+            output: String::new(),
+        };
+        writer.children(self, writer.line(0), false);
+        writer.output
+    }
+}
+
+struct HtmlWriter {
+    format: Format,
+    output: String,
+}
+
+impl HtmlWriter {
+    /// The depth to write a node on its own line at, or `None` to write it
+    /// inline.
+    fn line(&self, depth: usize) -> Option<usize> {
+        match self.format {
+            Format::Exact => None,
+            Format::Pretty => Some(depth),
+        }
+    }
+
+    fn start(&mut self, line: Option<usize>) {
+        if let Some(depth) = line {
+            self.output.push_str(&"  ".repeat(depth));
+        }
+    }
+
+    fn end_line(&mut self, line: Option<usize>) {
+        if line.is_some() {
+            self.output.push('\n');
+        }
+    }
+
+    fn node(&mut self, node: NodeRef, line: Option<usize>, raw_text: bool) {
+        match &node.node_type {
+            NodeType::Text(data) => {
+                let data = match self.format {
+                    Format::Pretty if !raw_text => data.trim(),
+                    _ => data,
+                };
+                if data.is_empty() {
+                    return;
+                }
+                self.start(line);
+                if raw_text {
+                    self.output.push_str(data);
+                } else {
+                    self.output.push_str(&escape(data, false));
+                }
+                self.end_line(line);
+            }
+            NodeType::Comment(data) => {
+                self.start(line);
+                self.output.push_str("<!--");
+                self.output.push_str(&escape_comment(data));
+                self.output.push_str("-->");
+                self.end_line(line);
+            }
+            NodeType::Document => self.children(node, line, false),
+            NodeType::Element(elem) => {
+                self.start(line);
+                self.output.push('<');
+                self.output.push_str(&elem.tag_name);
+                for (name, value) in elem.attributes.iter() {
+                    self.output.push(' ');
+                    self.output.push_str(name);
+                    self.output.push_str("=\"");
+                    self.output.push_str(&escape(value, true));
+                    self.output.push('"');
+                }
+                let html = elem.namespace == Namespace::Html;
+                if !html && node.first_child().is_none() {
+                    self.output.push_str("/>");
+                    self.end_line(line);
+                    return;
+                }
+                self.output.push('>');
+                if html && is_void_element(&elem.tag_name) {
+                    self.end_line(line);
+                    return;
+                }
+                // Parsing drops a newline just after the start tag, so one
+                // starting the text needs another in front to survive.
+                let leading_newline = matches!(
+                    node.first_child().map(|child| &child.node().node_type),
+                    Some(NodeType::Text(data)) if data.starts_with('\n')
+                );
+                if html
+                    && self.format == Format::Exact
+                    && drops_leading_newline(&elem.tag_name)
+                    && leading_newline
+                {
+                    self.output.push('\n');
+                }
+
+                let raw_text = html && is_raw_text_element(&elem.tag_name);
+                let text_only = node
+                    .children()
+                    .all(|child| matches!(child.node_type, NodeType::Text(_)));
+                match line {
+                    Some(depth) if !text_only => {
+                        self.end_line(line);
+                        self.children(node, Some(depth + 1), raw_text);
+                        self.start(line);
+                    }
+                    _ => self.children(node, None, raw_text),
+                }
+                self.output.push_str("</");
+                self.output.push_str(&elem.tag_name);
+                self.output.push('>');
+                self.end_line(line);
+            }
+        }
+    }
+
+    fn children(&mut self, node: NodeRef, line: Option<usize>, raw_text: bool) {
+        for child in node.children() {
+            self.node(child, line, raw_text);
+        }
+    }
+}
+
+/// Replace `&`, no-break spaces, and either `"` in attribute mode or `<` and
+/// `>` otherwise, with character references.
+fn escape(text: &str, attribute_mode: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '"' if attribute_mode => escaped.push_str("&quot;"),
+            '<' if !attribute_mode => escaped.push_str("&lt;"),
+            '>' if !attribute_mode => escaped.push_str("&gt;"),
+            _ => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// Comment text as it can be written between `<!--` and `-->`: anything
+/// that would end the comment early has its `>` escaped.
+fn escape_comment(data: &str) -> String {
+    data.replace("--!>", "--!&gt;").replace("-->", "--&gt;")
+}
+
+fn parse_path_step(step: &str) -> Option<(&str, usize)> {
+    match step.strip_suffix(']') {
+        Some(step) => {
+            let (tag_name, index) = step.split_once('[')?;
+            Some((tag_name, index.parse().ok()?))
+        }
+        None => Some((step, 1)),
+    }
+}
+
+pub fn text(data: String) -> NodeBuilder {
+    NodeBuilder {
         node_type: NodeType::Text(data),
         children: Vec::new(),
     }
 }
 
-pub fn comment() -> Node {
-    Node {
-        node_type: NodeType::Comment,
+pub fn comment(data: String) -> NodeBuilder {
+    NodeBuilder {
+        node_type: NodeType::Comment(data),
         children: Vec::new(),
     }
 }
 
-pub fn element(tag_name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
-    Node {
+pub fn element(
+    tag_name: impl Into<Atom>,
+    attrs: AttrMap,
+    children: Vec<NodeBuilder>,
+) -> NodeBuilder {
+    element_ns(Namespace::Html, tag_name, attrs, children)
+}
+
+/// An element in `namespace`, such as an SVG element inside HTML.
+pub fn element_ns(
+    namespace: Namespace,
+    tag_name: impl Into<Atom>,
+    attrs: AttrMap,
+    children: Vec<NodeBuilder>,
+) -> NodeBuilder {
+    NodeBuilder {
         node_type: NodeType::Element(ElementData {
-            tag_name,
+            namespace,
+            tag_name: tag_name.into(),
             attributes: attrs,
         }),
         children,
@@ -69,7 +1306,7 @@ mod tests {
         let data2 = "data".to_string();
         assert_eq!(
             text(data),
-            Node {
+            NodeBuilder {
                 node_type: NodeType::Text(data2),
                 children: vec![]
             }
@@ -79,25 +1316,464 @@ mod tests {
     #[test]
     fn test_comment() {
         assert_eq!(
-            comment(),
-            Node {
-                node_type: NodeType::Comment,
+            comment(" note ".to_string()),
+            NodeBuilder {
+                node_type: NodeType::Comment(" note ".to_string()),
                 children: vec![]
             }
         );
     }
 
+    #[test]
+    fn test_lineage() {
+        let mut attrs = AttrMap::new();
+        attrs.insert("id".to_string(), "target".to_string());
+        let tree = element(
+            "html".to_string(),
+            AttrMap::new(),
+            vec![
+                element("p".to_string(), AttrMap::new(), vec![]),
+                text("text".to_string()),
+                element("p".to_string(), attrs, vec![]),
+            ],
+        )
+        .build();
+        let root = tree.root();
+        let by_id = root.lineage("#target").unwrap();
+        assert_eq!(by_id.len(), 2);
+        assert_eq!(by_id[1], root.children().nth(2).unwrap());
+        let by_path = root.lineage("html/p[2]").unwrap();
+        assert_eq!(by_id, by_path);
+        assert_eq!(
+            root.lineage("html/p").unwrap()[1],
+            root.first_child().unwrap()
+        );
+        assert_eq!(by_id[1].lineage("#target"), Some(vec![by_id[1]]));
+        assert_eq!(root.lineage("html/p[3]"), None);
+        assert_eq!(root.lineage("body"), None);
+        assert_eq!(root.lineage("#missing"), None);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let tree = element(
+            "div".to_string(),
+            AttrMap::new(),
+            vec![
+                element(
+                    "p".to_string(),
+                    AttrMap::new(),
+                    vec![text("one".to_string())],
+                ),
+                comment("between".to_string()),
+                element(
+                    "p".to_string(),
+                    AttrMap::new(),
+                    vec![text("two".to_string())],
+                ),
+            ],
+        )
+        .build();
+        let root = tree.root();
+        let first = root.first_child().unwrap();
+        let middle = first.next_sibling().unwrap();
+        let last = root.last_child().unwrap();
+
+        assert_eq!(root.parent(), None);
+        assert_eq!(middle.node_type, NodeType::Comment("between".to_string()));
+        assert_eq!(middle.previous_sibling(), Some(first));
+        assert_eq!(middle.next_sibling(), Some(last));
+        assert_eq!(first.previous_sibling(), None);
+        assert_eq!(last.next_sibling(), None);
+        assert_eq!(root.children().collect::<Vec<_>>(), [first, middle, last]);
+
+        let two = last.first_child().unwrap();
+        assert_eq!(two.node_type, NodeType::Text("two".to_string()));
+        assert_eq!(two.ancestors().collect::<Vec<_>>(), [last, root]);
+        assert_eq!(tree.get(two.id()), two);
+
+        let order: Vec<_> = root.descendants().collect();
+        assert_eq!(order.len(), 6);
+        assert_eq!(order[..3], [root, first, first.first_child().unwrap()]);
+        assert_eq!(order[3..], [middle, last, two]);
+        assert_eq!(first.descendants().count(), 2);
+    }
+
+    fn paragraphs() -> Tree {
+        element(
+            "div".to_string(),
+            AttrMap::new(),
+            vec![
+                element("p".to_string(), AttrMap::new(), vec![text("a".to_string())]),
+                element("p".to_string(), AttrMap::new(), vec![text("b".to_string())]),
+            ],
+        )
+        .build()
+    }
+
+    fn child_ids(tree: &Tree, id: NodeId) -> Vec<NodeId> {
+        tree.get(id).children().map(NodeRef::id).collect()
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut tree = paragraphs();
+        let root = tree.root().id();
+        let [a, b] = child_ids(&tree, root)[..] else {
+            panic!("expected two children")
+        };
+        let span = tree.create(element("span".to_string(), AttrMap::new(), vec![]));
+
+        tree.insert_before(root, span, Some(b)).unwrap();
+        assert_eq!(child_ids(&tree, root), [a, span, b]);
+        tree.append_child(b, span).unwrap();
+        assert_eq!(child_ids(&tree, root), [a, b]);
+        assert_eq!(tree.get(span).parent().unwrap().id(), b);
+        assert_eq!(
+            tree.take_records(),
+            [
+                MutationRecord::ChildList {
+                    target: root,
+                    added: vec![span],
+                    removed: vec![],
+                    previous_sibling: Some(a),
+                    next_sibling: Some(b),
+                },
+                MutationRecord::ChildList {
+                    target: root,
+                    added: vec![],
+                    removed: vec![span],
+                    previous_sibling: Some(a),
+                    next_sibling: Some(b),
+                },
+                MutationRecord::ChildList {
+                    target: b,
+                    added: vec![span],
+                    removed: vec![],
+                    previous_sibling: tree.get(b).first_child().map(NodeRef::id),
+                    next_sibling: None,
+                },
+            ]
+        );
+
+        tree.replace_child(root, span, a).unwrap();
+        assert_eq!(child_ids(&tree, root), [span, b]);
+        assert_eq!(tree.get(a).parent(), None);
+        tree.remove(b).unwrap();
+        assert_eq!(child_ids(&tree, root), [span]);
+        assert_eq!(tree.root().last_child().unwrap().id(), span);
+        assert_eq!(tree.take_records().len(), 3);
+        assert_eq!(tree.remove(b), Err(MutationError::NotFound));
+
+        // A node can't be moved into itself or below itself, nor into text.
+        tree.append_child(span, a).unwrap();
+        assert_eq!(
+            tree.append_child(a, root),
+            Err(MutationError::HierarchyRequest)
+        );
+        assert_eq!(
+            tree.append_child(span, span),
+            Err(MutationError::HierarchyRequest)
+        );
+        let text_a = tree.get(a).first_child().unwrap().id();
+        assert_eq!(
+            tree.append_child(text_a, b),
+            Err(MutationError::HierarchyRequest)
+        );
+        assert_eq!(
+            tree.insert_before(root, b, Some(text_a)),
+            Err(MutationError::NotFound)
+        );
+        assert_eq!(tree.take_records().len(), 1);
+        assert_eq!(
+            tree.root().outer_html(Format::Exact),
+            "<div><span><p>a</p></span></div>"
+        );
+    }
+
+    #[test]
+    fn test_set_attribute_and_text() {
+        let mut tree = paragraphs();
+        let root = tree.root().id();
+        let a = tree.root().first_child().unwrap().id();
+        let text_a = tree.get(a).first_child().unwrap().id();
+
+        assert_eq!(tree.set_attribute(a, "class", "x"), Ok(None));
+        assert_eq!(
+            tree.set_attribute(a, "class", "y"),
+            Ok(Some("x".to_string()))
+        );
+        assert_eq!(tree.remove_attribute(a, "title"), Ok(None));
+        assert_eq!(tree.remove_attribute(a, "class"), Ok(Some("y".to_string())));
+        assert_eq!(
+            tree.set_attribute(text_a, "class", "x"),
+            Err(MutationError::InvalidNodeType)
+        );
+        tree.set_text(text_a, "c").unwrap();
+        assert_eq!(
+            tree.take_records(),
+            [
+                MutationRecord::Attributes {
+                    target: a,
+                    name: "class".to_string(),
+                    old_value: None,
+                },
+                MutationRecord::Attributes {
+                    target: a,
+                    name: "class".to_string(),
+                    old_value: Some("x".to_string()),
+                },
+                MutationRecord::Attributes {
+                    target: a,
+                    name: "class".to_string(),
+                    old_value: Some("y".to_string()),
+                },
+                MutationRecord::CharacterData {
+                    target: text_a,
+                    old_value: "a".to_string(),
+                },
+            ]
+        );
+
+        tree.set_text(root, "plain").unwrap();
+        assert_eq!(tree.root().outer_html(Format::Exact), "<div>plain</div>");
+        assert!(matches!(
+            &tree.take_records()[..],
+            [MutationRecord::ChildList { target, added, removed, .. }]
+                if *target == root && added.len() == 1 && removed.len() == 2
+        ));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut tree = paragraphs();
+        let root = tree.root().id();
+        let [a, b] = child_ids(&tree, root)[..] else {
+            panic!("expected two children")
+        };
+        for data in ["", "c", "d"] {
+            let node = tree.create(text(data.to_string()));
+            tree.insert_before(root, node, Some(b)).unwrap();
+        }
+        let note = tree.create(comment("note".to_string()));
+        tree.append_child(root, note).unwrap();
+        let e = tree.create(text("e".to_string()));
+        tree.append_child(root, e).unwrap();
+        tree.take_records();
+
+        tree.normalize(root);
+        assert_eq!(
+            tree.root().outer_html(Format::Exact),
+            "<div><p>a</p>cd<p>b</p><!--note-->e</div>"
+        );
+        assert_eq!(child_ids(&tree, root).len(), 5);
+        assert_eq!(tree.get(a).children().count(), 1);
+        // The empty node and "d" are removed after "c" takes its text.
+        assert_eq!(tree.take_records().len(), 3);
+    }
+
+    #[test]
+    fn test_query_selector() {
+        let tree = crate::html::parse(
+            "<div id='cart'><ul><li class='item'><span class='price'>1</span></li>\
+             <li class='item sale'><span class='price'>2</span></li></ul>\
+             <p class='price'>total</p></div>"
+                .to_string(),
+        );
+        let root = tree.document_element().unwrap();
+        let prices = root.query_selector_all("#cart .price").unwrap();
+        assert_eq!(prices.len(), 3);
+        assert!(prices.windows(2).all(|pair| pair[0].id() < pair[1].id()));
+        assert_eq!(root.query_selector_all("li > .price").unwrap(), prices[..2]);
+        assert_eq!(
+            root.query_selector_all(".sale .price, p").unwrap(),
+            prices[1..]
+        );
+        assert_eq!(root.query_selector("ul + .price").unwrap(), Some(prices[2]));
+        assert_eq!(root.query_selector("#cart").unwrap(), None);
+        assert_eq!(root.query_selector("table").unwrap(), None);
+
+        assert!(prices[1].matches("li.sale span").unwrap());
+        assert!(!prices[0].matches("li.sale span").unwrap());
+        let text = prices[1].first_child().unwrap();
+        assert!(!text.matches("*").unwrap());
+        assert_eq!(
+            text.closest("li").unwrap().map(|li| li.id()),
+            prices[1].parent().map(|li| li.id())
+        );
+        assert_eq!(prices[1].closest("span").unwrap(), Some(prices[1]));
+        assert_eq!(prices[0].closest("p").unwrap(), None);
+        assert_eq!(
+            root.query_selector_all("li:hover"),
+            Err(InvalidSelector("li:hover".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_comments_round_trip() {
+        let source = "<p>a<!--b-->c<!-- d - -- --></p>";
+        let document = crate::html::parse(source.to_string());
+        assert_eq!(document.root().inner_html(Format::Exact), source);
+
+        // Text that would end the comment early can't be written as is.
+        let tree = element("p", AttrMap::new(), vec![comment("a-->b--!>".to_string())]).build();
+        let html = tree.root().outer_html(Format::Exact);
+        assert_eq!(html, "<p><!--a--&gt;b--!&gt;--></p>");
+        let reparsed = crate::html::parse(html);
+        assert_eq!(reparsed.document_element().unwrap().children().count(), 1);
+    }
+
+    #[test]
+    fn test_outer_html() {
+        let mut attrs = AttrMap::new();
+        attrs.insert("title".to_string(), "\"a\" & <b>".to_string());
+        attrs.insert("class".to_string(), "note".to_string());
+        let tree = element(
+            "div".to_string(),
+            AttrMap::new(),
+            vec![
+                element(
+                    "p".to_string(),
+                    attrs,
+                    vec![text(" 1 < 2 & 3\u{a0}> 0 ".to_string())],
+                ),
+                comment("between".to_string()),
+                element("br".to_string(), AttrMap::new(), vec![]),
+                element(
+                    "style".to_string(),
+                    AttrMap::new(),
+                    vec![text("a > b { color: red }".to_string())],
+                ),
+                text("tail".to_string()),
+            ],
+        )
+        .build();
+        let root = tree.root();
+        assert_eq!(
+            root.outer_html(Format::Exact),
+            "<div><p title=\"&quot;a&quot; &amp; <b>\" class=\"note\"> 1 &lt; 2 &amp; 3&nbsp;&gt; 0 </p>\
+             <!--between--><br><style>a > b { color: red }</style>tail</div>"
+        );
+        assert_eq!(
+            root.outer_html(Format::Pretty),
+            "<div>
+  <p title=\"&quot;a&quot; &amp; <b>\" class=\"note\">1 &lt; 2 &amp; 3&nbsp;&gt; 0</p>
+  <!--between-->
+  <br>
+  <style>a > b { color: red }</style>
+  tail
+</div>
+"
+        );
+        assert_eq!(
+            root.inner_html(Format::Exact),
+            root.outer_html(Format::Exact)
+                .strip_prefix("<div>")
+                .and_then(|html| html.strip_suffix("</div>"))
+                .unwrap()
+        );
+        assert_eq!(
+            root.first_child().unwrap().inner_html(Format::Pretty),
+            "1 &lt; 2 &amp; 3&nbsp;&gt; 0\n"
+        );
+    }
+
+    #[test]
+    fn test_document_metadata() {
+        let document = crate::html::parse(
+            "<!doctype html><html lang='en-GB'><head>\
+             <meta http-equiv='Content-Type' content='text/html; charset=ISO-8859-1'>\
+             <title>  A\n  page </title><base href='https://example.com/docs/'>\
+             </head><body><title>other</title></body></html>"
+                .to_string(),
+        );
+        assert_eq!(document.title().as_deref(), Some("A page"));
+        assert_eq!(document.base_url(), Some("https://example.com/docs/"));
+        assert_eq!(document.charset().as_deref(), Some("iso-8859-1"));
+        assert_eq!(document.lang(), Some("en-GB"));
+        assert_eq!(
+            document.to_html(Format::Exact),
+            "<!DOCTYPE html><html lang=\"en-GB\"><head>\
+             <meta http-equiv=\"Content-Type\" content=\"text/html; charset=ISO-8859-1\">\
+             <title>  A\n  page </title><base href=\"https://example.com/docs/\">\
+             </head><body><title>other</title></body></html>"
+        );
+
+        let document = crate::html::parse("<meta charset=' UTF-8 '><p>text</p>".to_string());
+        assert_eq!(document.charset().as_deref(), Some("utf-8"));
+        assert_eq!(document.title(), None);
+        assert_eq!(document.base_url(), None);
+        assert_eq!(document.lang(), None);
+    }
+
+    #[test]
+    fn test_document_title_with_markup() {
+        // The text of a title is not parsed for tags, only for references.
+        let document =
+            crate::html::parse("<title>Tom &amp; <b>Jerry</b></title><p>x</p>".to_string());
+        assert_eq!(document.title().as_deref(), Some("Tom & <b>Jerry</b>"));
+        assert_eq!(
+            document.to_html(Format::Exact),
+            "<title>Tom &amp; &lt;b&gt;Jerry&lt;/b&gt;</title><p>x</p>"
+        );
+    }
+
+    #[test]
+    fn test_attr_map_keeps_order() {
+        let mut attributes = AttrMap::new();
+        attributes.insert("b".to_string(), "1".to_string());
+        attributes.insert("a".to_string(), "2".to_string());
+        assert_eq!(
+            attributes.insert("b".to_string(), "3".to_string()),
+            Some("1".to_string())
+        );
+        assert_eq!(
+            attributes.iter().collect::<Vec<_>>(),
+            [("b", "3"), ("a", "2")]
+        );
+        assert_eq!(attributes.remove("b"), Some("3".to_string()));
+        assert_eq!(attributes.remove("b"), None);
+        assert_eq!(attributes.len(), 1);
+    }
+
+    #[test]
+    fn test_class_list() {
+        let mut attributes = AttrMap::new();
+        attributes.insert("class".to_string(), " a\tb  a\nc ".to_string());
+        let elem = ElementData {
+            namespace: Namespace::Html,
+            tag_name: Atom::new("p"),
+            attributes,
+        };
+        assert_eq!(
+            elem.classes(),
+            [Atom::new("a"), Atom::new("b"), Atom::new("c")]
+        );
+
+        let mut classes = elem.class_list();
+        assert_eq!(classes.iter().collect::<Vec<_>>(), ["a", "b", "c"]);
+        classes.add("b");
+        classes.add("d");
+        classes.remove("a");
+        assert!(!classes.toggle("c"));
+        assert!(classes.toggle("e"));
+        assert!(classes.contains("e"));
+        assert_eq!(classes.to_string(), "b d e");
+        assert_eq!(ClassList::new(" \t "), ClassList::default());
+    }
+
     #[test]
     fn test_element() {
-        let mut attrs = HashMap::new();
+        let mut attrs = AttrMap::new();
         attrs.insert("id".to_string(), "1".to_string());
-        let mut attrs2 = HashMap::new();
+        let mut attrs2 = AttrMap::new();
         attrs2.insert("id".to_string(), "1".to_string());
         assert_eq!(
             element("h1".to_string(), attrs, vec![]),
-            Node {
+            NodeBuilder {
                 node_type: NodeType::Element(ElementData {
-                    tag_name: "h1".to_string(),
+                    namespace: Namespace::Html,
+                    tag_name: Atom::new("h1"),
                     attributes: attrs2
                 }),
                 children: vec![]
@@ -0,0 +1,282 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+
+/// How many bytes at the start of a page are searched for a `<meta>` giving
+/// its encoding.
+pub const PRESCAN_LEN: usize = 1024;
+
+/// The encoding of a page starting with `bytes`, and the length of the byte
+/// order mark to skip. A byte order mark wins, then a `<meta charset>` or
+/// `<meta http-equiv="content-type">` in the first `PRESCAN_LEN` bytes, and
+/// otherwise `default`.
+pub fn sniff_html(bytes: &[u8], default: &'static Encoding) -> (&'static Encoding, usize) {
+    if let Some(found) = Encoding::for_bom(bytes) {
+        return found;
+    }
+    let start = &bytes[..bytes.len().min(PRESCAN_LEN)];
+    (prescan(start).unwrap_or(default), 0)
+}
+
+/// Decode a whole page, finding its encoding with `sniff_html`.
+pub fn decode_html(bytes: &[u8], default: &'static Encoding) -> String {
+    let (encoding, bom_len) = sniff_html(bytes, default);
+    encoding
+        .decode_without_bom_handling(&bytes[bom_len..])
+        .0
+        .into_owned()
+}
+
+/// The encoding of a style sheet starting with `bytes`, and the length of
+/// the byte order mark to skip. A byte order mark wins, then an `@charset`
+/// rule at the very start, and otherwise `fallback`, the encoding of
+/// whatever refers to the sheet.
+pub fn sniff_css(bytes: &[u8], fallback: &'static Encoding) -> (&'static Encoding, usize) {
+    if let Some(found) = Encoding::for_bom(bytes) {
+        return found;
+    }
+    let label = bytes
+        .strip_prefix(b"@charset \"")
+        .and_then(|rest| {
+            let end = rest[..rest.len().min(PRESCAN_LEN)]
+                .windows(2)
+                .position(|pair| pair == b"\";")?;
+            Some(&rest[..end])
+        })
+        .and_then(Encoding::for_label);
+    let encoding = match label {
+        Some(encoding) if encoding == UTF_16BE || encoding == UTF_16LE => UTF_8,
+        Some(encoding) => encoding,
+        None => fallback,
+    };
+    (encoding, 0)
+}
+
+/// Decode a whole style sheet, finding its encoding with `sniff_css`.
+pub fn decode_css(bytes: &[u8], fallback: &'static Encoding) -> String {
+    let (encoding, bom_len) = sniff_css(bytes, fallback);
+    encoding
+        .decode_without_bom_handling(&bytes[bom_len..])
+        .0
+        .into_owned()
+}
+
+/// Look through the tags at the start of a page for a `<meta>` giving its
+/// encoding, skipping comments and the attributes of other tags.
+fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        if rest.starts_with(b"<!--") {
+            // The `--` of `<!--` may be the one ending the comment.
+            pos += 2 + find(&rest[2..], b"-->")? + 3;
+            continue;
+        }
+        if starts_with_ignore_case(rest, b"<meta")
+            && rest
+                .get(5)
+                .is_some_and(|&byte| is_whitespace(byte) || byte == b'/')
+        {
+            pos += 5;
+            if let Some(encoding) = meta_encoding(bytes, &mut pos) {
+                return Some(encoding);
+            }
+            continue;
+        }
+        let tag_start = match rest {
+            [b'<', b'/', byte, ..] | [b'<', byte, ..] if byte.is_ascii_alphabetic() => true,
+            [b'<', b'!' | b'/' | b'?', ..] => false,
+            _ => {
+                pos += 1;
+                continue;
+            }
+        };
+        if tag_start {
+            while pos < bytes.len() && !is_whitespace(bytes[pos]) && bytes[pos] != b'>' {
+                pos += 1;
+            }
+            while get_attribute(bytes, &mut pos).is_some() {}
+        } else {
+            pos += find(rest, b">")?;
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// The encoding the attributes of a `<meta>` tag at `pos` give, if any.
+fn meta_encoding(bytes: &[u8], pos: &mut usize) -> Option<&'static Encoding> {
+    let mut names = Vec::new();
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+    while let Some((name, value)) = get_attribute(bytes, pos) {
+        if names.contains(&name) {
+            continue;
+        }
+        match name.as_str() {
+            "http-equiv" if value == "content-type" => got_pragma = true,
+            "content" if charset.is_none() => {
+                if let Some(label) = charset_from_content(&value) {
+                    charset = Encoding::for_label(label.as_bytes());
+                    need_pragma = Some(true);
+                }
+            }
+            "charset" => {
+                charset = Encoding::for_label(value.as_bytes());
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+        names.push(name);
+    }
+    match (need_pragma?, charset?) {
+        (true, _) if !got_pragma => None,
+        (_, encoding) if encoding == UTF_16BE || encoding == UTF_16LE => Some(UTF_8),
+        (_, encoding) if encoding == X_USER_DEFINED => Some(WINDOWS_1252),
+        (_, encoding) => Some(encoding),
+    }
+}
+
+/// Read the attribute at `pos`, with its name and value lowercased, or
+/// `None` at the end of the tag or the bytes.
+fn get_attribute(bytes: &[u8], pos: &mut usize) -> Option<(String, String)> {
+    let at = |pos: usize| bytes.get(pos).copied();
+    while at(*pos).is_some_and(|byte| is_whitespace(byte) || byte == b'/') {
+        *pos += 1;
+    }
+    if at(*pos)? == b'>' {
+        return None;
+    }
+    let mut name = String::new();
+    loop {
+        match at(*pos)? {
+            b'=' if !name.is_empty() => break,
+            byte if is_whitespace(byte) => {
+                while at(*pos).is_some_and(is_whitespace) {
+                    *pos += 1;
+                }
+                if at(*pos)? != b'=' {
+                    return Some((name, String::new()));
+                }
+                break;
+            }
+            b'/' | b'>' => return Some((name, String::new())),
+            byte => name.push(byte.to_ascii_lowercase() as char),
+        }
+        *pos += 1;
+    }
+    // Skip the `=`.
+    *pos += 1;
+    while at(*pos).is_some_and(is_whitespace) {
+        *pos += 1;
+    }
+    let mut value = String::new();
+    match at(*pos)? {
+        quote @ (b'"' | b'\'') => {
+            *pos += 1;
+            while at(*pos)? != quote {
+                value.push(bytes[*pos].to_ascii_lowercase() as char);
+                *pos += 1;
+            }
+            *pos += 1;
+        }
+        b'>' => {}
+        _ => {
+            while at(*pos).is_some_and(|byte| !is_whitespace(byte) && byte != b'>') {
+                value.push(bytes[*pos].to_ascii_lowercase() as char);
+                *pos += 1;
+            }
+        }
+    }
+    Some((name, value))
+}
+
+/// The label after `charset=` in the `content` of a `<meta>` such as
+/// `text/html; charset=shift_jis`.
+fn charset_from_content(content: &str) -> Option<String> {
+    let mut rest = content;
+    loop {
+        let start = rest.find("charset")?;
+        rest = rest[start + "charset".len()..].trim_start();
+        if let Some(value) = rest.strip_prefix('=') {
+            rest = value.trim_start();
+            break;
+        }
+    }
+    let label = match rest.chars().next()? {
+        quote @ ('"' | '\'') => &rest[1..1 + rest[1..].find(quote)?],
+        _ => rest
+            .split(|char: char| char.is_whitespace() || char == ';')
+            .next()?,
+    };
+    Some(label.to_string()).filter(|label| !label.is_empty())
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::SHIFT_JIS;
+
+    #[test]
+    fn test_sniff_html() {
+        let sniff = |bytes: &[u8]| sniff_html(bytes, WINDOWS_1252);
+        assert_eq!(sniff(b"\xef\xbb\xbf<meta charset=shift_jis>"), (UTF_8, 3));
+        assert_eq!(sniff(b"<META Charset='Shift_JIS'>"), (SHIFT_JIS, 0));
+        assert_eq!(
+            sniff(
+                b"<!doctype html><!-- <meta charset=utf-8> --><html lang=\"a>b\">\
+                  <meta name=x content='text/html; charset=\"sjis\"' http-equiv=Content-Type>"
+            ),
+            (SHIFT_JIS, 0)
+        );
+        // `content` only counts along with `http-equiv`.
+        assert_eq!(
+            sniff(b"<meta content='text/html; charset=sjis'>"),
+            (WINDOWS_1252, 0)
+        );
+        assert_eq!(sniff(b"<meta charset=utf-16le>"), (UTF_8, 0));
+        assert_eq!(sniff(b"<meta charset=nonsense><p>"), (WINDOWS_1252, 0));
+        let late = [b" ".repeat(PRESCAN_LEN), b"<meta charset=utf-8>".to_vec()].concat();
+        assert_eq!(sniff(&late), (WINDOWS_1252, 0));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode_html(b"<p>caf\xe9</p>", WINDOWS_1252),
+            "<p>caf\u{e9}</p>"
+        );
+        assert_eq!(
+            decode_html(b"<meta charset=shift_jis><p>\x93\xfa\x96\x7b</p>", UTF_8),
+            "<meta charset=shift_jis><p>\u{65e5}\u{672c}</p>"
+        );
+        assert_eq!(
+            decode_css(
+                b"@charset \"windows-1252\"; p::after { content: '\xe9'; }",
+                UTF_8
+            ),
+            "@charset \"windows-1252\"; p::after { content: '\u{e9}'; }"
+        );
+        assert_eq!(
+            decode_css(b"\xef\xbb\xbf@charset \"shift_jis\"; p {}", WINDOWS_1252),
+            "@charset \"shift_jis\"; p {}"
+        );
+        // `@charset` has to be written exactly so to count.
+        assert_eq!(sniff_css(b"@CHARSET \"shift_jis\";", UTF_8), (UTF_8, 0));
+    }
+}
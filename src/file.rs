@@ -1,36 +1,287 @@
-use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use encoding_rs::{Decoder, Encoding, UTF_8, WINDOWS_1252};
+
+use crate::{css, dom, encoding, html};
 
 pub struct Config {
     pub html_filename: String,
     pub css_filename: String,
+    pub command: Command,
+    /// The encoding of pages that don't say what theirs is, set with
+    /// `--encoding=<label>`.
+    pub default_encoding: &'static Encoding,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Open the page in the terminal window.
+    Render,
+    /// Print why `property` has its value on the element found by `target`,
+    /// either `#id` or a tag path such as `html/div/p[2]`.
+    Explain { target: String, property: String },
+    /// Print the style sheet minified, without the rules the page doesn't use.
+    Minify,
 }
 
 impl Config {
-    pub fn new(mut args: env::Args) -> Result<Config, &'static str> {
+    pub fn new(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
         args.next();
-        let html_filename = match args.next() {
+        let mut default_encoding = WINDOWS_1252;
+        let mut positional = Vec::new();
+        for arg in args {
+            match arg.strip_prefix("--encoding=") {
+                Some(label) => {
+                    default_encoding = Encoding::for_label(label.as_bytes())
+                        .ok_or("Didn't recognize the encoding label")?;
+                }
+                None => positional.push(arg),
+            }
+        }
+        let mut args = positional.into_iter();
+        let mut html_filename = match args.next() {
             Some(arg) => arg,
             None => return Err("Didn't get a html file path"),
         };
+        let subcommand = match html_filename.as_str() {
+            "explain" | "minify" => Some(html_filename.clone()),
+            _ => None,
+        };
+        if subcommand.is_some() {
+            html_filename = match args.next() {
+                Some(arg) => arg,
+                None => return Err("Didn't get a html file path"),
+            };
+        }
         let css_filename = match args.next() {
             Some(arg) => arg,
             None => return Err("Didn't get a css file path"),
         };
 
+        let command = match subcommand.as_deref() {
+            Some("explain") => {
+                let target = match args.next() {
+                    Some(arg) => arg,
+                    None => return Err("Didn't get an element id or path to explain"),
+                };
+                let property = match args.next() {
+                    Some(arg) => arg,
+                    None => return Err("Didn't get a property name to explain"),
+                };
+                Command::Explain { target, property }
+            }
+            Some(_) => Command::Minify,
+            None => Command::Render,
+        };
+
         Ok(Config {
             html_filename,
             css_filename,
+            command,
+            default_encoding,
         })
     }
 }
 
-pub fn read_source(filename: String) -> String {
-    let mut str = String::new();
-    File::open(filename)
-        .unwrap()
-        .read_to_string(&mut str)
+/// Read and parse the page `filename` a chunk at a time, so the whole file
+/// needn't be held as text. Its encoding is sniffed from its start, falling
+/// back to `default_encoding`.
+pub fn load_document(
+    filename: &str,
+    default_encoding: &'static Encoding,
+) -> io::Result<dom::Document> {
+    let mut file = File::open(filename)?;
+    let mut start = Vec::new();
+    (&mut file)
+        .take(encoding::PRESCAN_LEN as u64)
+        .read_to_end(&mut start)?;
+    let (encoding, bom_len) = encoding::sniff_html(&start, default_encoding);
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut builder = html::TreeBuilder::new();
+    builder.feed(&decode_chunk(&mut decoder, &start[bom_len..], false));
+    let mut buffer = [0; 8192];
+    loop {
+        let read = file.read(&mut buffer)?;
+        builder.feed(&decode_chunk(&mut decoder, &buffer[..read], read == 0));
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Decode the next `bytes` of a file. A character split between reads is
+/// kept by `decoder` for the next one.
+fn decode_chunk(decoder: &mut Decoder, bytes: &[u8], last: bool) -> String {
+    let capacity = decoder.max_utf8_buffer_length(bytes.len()).unwrap();
+    let mut text = String::with_capacity(capacity);
+    // With that capacity, all of `bytes` is always decoded.
+    let _ = decoder.decode_to_string(bytes, &mut text, last);
+    text
+}
+
+/// Loads `@import`ed style sheets from the file system, resolving each URL
+/// against the directory of the sheet importing it.
+#[derive(Default)]
+pub struct FileLoader {
+    /// The encoding each sheet read was decoded in, by location, which is
+    /// what the sheets it imports fall back to.
+    encodings: HashMap<String, &'static Encoding>,
+}
+
+impl FileLoader {
+    /// Read the style sheet at `path`, decoded as `encoding::sniff_css`
+    /// finds with `fallback`, and return its location and source.
+    fn read(&mut self, path: &Path, fallback: &'static Encoding) -> io::Result<(String, String)> {
+        let bytes = fs::read(path)?;
+        let (encoding, _) = encoding::sniff_css(&bytes, fallback);
+        let location = fs::canonicalize(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .to_string_lossy()
+            .into_owned();
+        self.encodings.insert(location.clone(), encoding);
+        Ok((location, encoding::decode_css(&bytes, encoding)))
+    }
+}
+
+impl css::Loader for FileLoader {
+    fn load(&mut self, url: &str, base: &str) -> Option<(String, String)> {
+        let directory = Path::new(base).parent().unwrap_or_else(|| Path::new(""));
+        let fallback = self.encodings.get(base).copied().unwrap_or(UTF_8);
+        self.read(&directory.join(url), fallback).ok()
+    }
+}
+
+/// Read and parse the style sheet `filename` along with everything it
+/// imports. Sheets are UTF-8 unless a byte order mark or `@charset` says
+/// otherwise, and imported sheets fall back to the importer's encoding.
+pub fn load_style_sheet(filename: &str) -> io::Result<css::StyleSheet> {
+    let mut loader = FileLoader::default();
+    let (url, source) = loader.read(Path::new(filename), UTF_8)?;
+    Ok(css::parse_with_loader(source, url, loader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_style_sheet_resolves_imports_relative_to_the_importer() {
+        let root = std::env::temp_dir().join(format!("yoyo-imports-{}", std::process::id()));
+        fs::create_dir_all(root.join("parts")).unwrap();
+        fs::write(
+            root.join("main.css"),
+            "@import 'parts/base.css' layer(base); h1 { margin: 1px; }",
+        )
+        .unwrap();
+        fs::write(
+            root.join("parts/base.css"),
+            "@import url(colors.css); @import '../main.css'; p { margin: 2px; }",
+        )
         .unwrap();
-    str
+        fs::write(root.join("parts/colors.css"), "p { color: #ff0000; }").unwrap();
+
+        let style_sheet = load_style_sheet(&root.join("main.css").to_string_lossy()).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let rules: Vec<(String, Vec<String>)> = style_sheet
+            .rules
+            .iter()
+            .map(|rule| (rule.declarations[0].name.clone(), rule.layer.clone()))
+            .collect();
+        let base = vec!["base".to_string()];
+        assert_eq!(
+            rules,
+            vec![
+                ("color".to_string(), base.clone()),
+                ("margin".to_string(), base),
+                ("margin".to_string(), Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_imports_fall_back_to_the_importer_encoding() {
+        let root = std::env::temp_dir().join(format!("yoyo-charset-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("main.css"),
+            "@charset \"windows-1252\"; @import 'part.css';",
+        )
+        .unwrap();
+        fs::write(root.join("part.css"), b"p::after { content: '\xe9'; }").unwrap();
+
+        let mut loader = FileLoader::default();
+        let (url, _) = loader.read(&root.join("main.css"), UTF_8).unwrap();
+        let (_, source) = css::Loader::load(&mut loader, "part.css", &url).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(source, "p::after { content: '\u{e9}'; }");
+    }
+
+    #[test]
+    fn test_load_document_reads_in_chunks() {
+        let path = std::env::temp_dir().join(format!("yoyo-page-{}.html", std::process::id()));
+        // Long enough to take several reads, with characters split between them.
+        let source = format!("<html><p>{}</p></html>", "é".repeat(10_000));
+        fs::write(&path, &source).unwrap();
+        let document = load_document(&path.to_string_lossy(), UTF_8).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(document, html::parse(source));
+    }
+
+    #[test]
+    fn test_load_document_decodes_the_sniffed_encoding() {
+        let path = std::env::temp_dir().join(format!("yoyo-sjis-{}.html", std::process::id()));
+        fs::write(&path, b"<meta charset=shift_jis><p>\x93\xfa\x96\x7b</p>").unwrap();
+        let filename = path.to_string_lossy().into_owned();
+        let document = load_document(&filename, WINDOWS_1252).unwrap();
+        fs::write(&path, b"<p>caf\xe9</p>").unwrap();
+        let fallback = load_document(&filename, WINDOWS_1252).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let text = |document: &dom::Document| {
+            let p = document
+                .root()
+                .descendants()
+                .find(|node| node.matches("p").unwrap());
+            p.unwrap().inner_html(dom::Format::Exact)
+        };
+        assert_eq!(text(&document), "\u{65e5}\u{672c}");
+        assert_eq!(text(&fallback), "caf\u{e9}");
+    }
+
+    #[test]
+    fn test_loading_a_missing_file_fails() {
+        let path = std::env::temp_dir().join(format!("yoyo-missing-{}", std::process::id()));
+        let filename = path.to_string_lossy();
+        let error = load_document(&filename, UTF_8).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = load_style_sheet(&filename).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_config_encoding_option() {
+        let args = |args: &[&str]| {
+            Config::new(
+                std::iter::once("yoyo")
+                    .chain(args.iter().copied())
+                    .map(String::from),
+            )
+        };
+        let config = args(&["page.html", "--encoding=sjis", "style.css"]).unwrap();
+        assert_eq!(
+            (config.html_filename.as_str(), config.css_filename.as_str()),
+            ("page.html", "style.css")
+        );
+        assert_eq!(config.default_encoding, encoding_rs::SHIFT_JIS);
+        assert_eq!(
+            args(&["page.html", "style.css"]).unwrap().default_encoding,
+            WINDOWS_1252
+        );
+        assert!(args(&["--encoding=nonsense", "page.html", "style.css"]).is_err());
+    }
 }
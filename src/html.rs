@@ -1,22 +1,87 @@
-use std::collections::HashMap;
+use crate::{
+    atom::Atom,
+    dom,
+    source::{SourceLocation, Span},
+};
 
-use crate::dom;
+/// A piece of HTML as the tokenizer reads it. Names are lowercased, and
+/// character references in text and attribute values are decoded, except in
+/// the raw text of elements like `<script>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Doctype(dom::Doctype),
+    /// A start tag, with its attributes in source order. A repeated
+    /// attribute is dropped, so the first value wins.
+    StartTag {
+        name: Atom,
+        attributes: Vec<(Atom, String)>,
+        self_closing: bool,
+    },
+    EndTag {
+        name: Atom,
+    },
+    Text(String),
+    Comment(String),
+}
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Parser {
-    pub pos: usize,
-    pub input: String,
+/// Splits HTML into tokens as it arrives. Input is given a chunk at a time
+/// with `feed`, and `end` marks that no more is coming. Iterating gives the
+/// tokens complete so far, and `None` once it needs more input, so it can be
+/// iterated again after the next chunk.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tokenizer {
+    pos: usize,
+    input: String,
+    ended: bool,
+    /// The element whose raw text is being read, up to its end tag.
+    raw_text: Option<Atom>,
+    /// Whether the next tokens are in SVG or MathML content, where no
+    /// element has raw text.
+    foreign: bool,
+    /// How far past `pos` the text being read is known not to end, so text
+    /// arriving over many chunks is scanned once rather than once a chunk.
+    scanned: usize,
+    /// Where in the whole input `pos` is.
+    location: SourceLocation,
+    span: Span,
 }
 
-impl Parser {
+impl Tokenizer {
+    pub fn new() -> Tokenizer {
+        Default::default()
+    }
+
+    /// Add `chunk` to the input.
+    pub fn feed(&mut self, chunk: &str) {
+        self.input.drain(..self.pos);
+        self.pos = 0;
+        self.input.push_str(chunk);
+    }
+
+    /// Mark the end of the input, so what is left of it can be tokenized.
+    pub fn end(&mut self) {
+        self.ended = true;
+    }
+
+    /// Where the token last returned was in the input.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Set whether the coming tokens are in foreign content. The tree
+    /// builder knows this, the tokenizer doesn't.
+    pub fn set_foreign(&mut self, foreign: bool) {
+        self.foreign = foreign;
+    }
+
     fn next_char(&self) -> char {
         self.input[self.pos..].chars().next().unwrap()
     }
 
-    fn next_next_char(&self) -> char {
+    fn next_next_char(&self) -> Option<char> {
         let mut iter = self.input[self.pos..].chars();
         iter.next();
-        iter.next().unwrap()
+        iter.next()
     }
 
     fn start_with(&self, s: &str) -> bool {
@@ -28,10 +93,8 @@ impl Parser {
     }
 
     fn consume_char(&mut self) -> char {
-        let mut iter = self.input[self.pos..].char_indices();
-        let (_, current_char) = iter.next().unwrap();
-        let (next_pos, _) = iter.next().unwrap_or((1, '_'));
-        self.pos += next_pos;
+        let current_char = self.next_char();
+        self.pos += current_char.len_utf8();
         current_char
     }
 
@@ -50,148 +113,875 @@ impl Parser {
         self.consume_while(char::is_whitespace);
     }
 
-    fn parse_tag_name(&mut self) -> String {
-        self.consume_while(|char| matches!(char, 'a'..='z' | 'A'..='Z' | '0'..='9' ))
+    /// Parse a tag name, lowercased as HTML names are.
+    fn parse_tag_name(&mut self) -> Atom {
+        Atom::lowercase(
+            &self.consume_while(
+                |char| matches!(char, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | ':'),
+            ),
+        )
     }
 
-    fn parse_node(&mut self) -> dom::Node {
-        match self.next_char() {
-            '<' => match self.next_next_char() {
-                '!' => self.parse_comment(),
-                _ => self.parse_element(),
-            },
+    /// The next token, or `None` if the input so far ends inside it. At the
+    /// end of the input, an unfinished tag is dropped.
+    fn parse_token(&mut self) -> Option<Token> {
+        if let Some(tag_name) = self.raw_text.clone() {
+            return self.parse_raw_text(tag_name);
+        }
+        if self.eof() {
+            return None;
+        }
+        match (self.next_char(), self.next_next_char()) {
+            ('<', Some('!')) => self.parse_markup_declaration(),
+            ('<', Some('/')) => self.parse_end_tag(),
+            ('<', Some(char)) if char.is_ascii_alphabetic() => self.parse_start_tag(),
             _ => self.parse_text(),
         }
     }
 
-    fn parse_comment(&mut self) -> dom::Node {
-        assert!(self.consume_char() == '<');
-        self.consume_while(|char| char != '<');
-        dom::comment()
+    fn start_with_doctype(&self) -> bool {
+        self.input[self.pos..]
+            .get(.."<!doctype".len())
+            .is_some_and(|start| start.eq_ignore_ascii_case("<!doctype"))
+    }
+
+    /// Parse a comment, a doctype, or anything else starting with `<!`,
+    /// which is taken as a comment up to the next `>`.
+    fn parse_markup_declaration(&mut self) -> Option<Token> {
+        if self.start_with("<!--") {
+            return self.parse_comment();
+        }
+        if self.start_with_doctype() {
+            return self.parse_doctype();
+        }
+        let rest = &self.input[self.pos..];
+        let unfinished = rest.len() < "<!doctype".len()
+            && ("<!--".starts_with(rest) || "<!doctype".starts_with(&rest.to_ascii_lowercase()));
+        if unfinished && !self.ended {
+            return None;
+        }
+        self.pos += "<!".len();
+        self.parse_bogus_comment()
     }
 
-    fn parse_text(&mut self) -> dom::Node {
-        dom::text(self.consume_while(|char| char != '<'))
+    /// Parse `<!DOCTYPE name PUBLIC "public id" "system id">`, where the
+    /// ids or the whole `PUBLIC` or `SYSTEM` part may be left out.
+    fn parse_doctype(&mut self) -> Option<Token> {
+        if !self.ended && !self.input[self.pos..].contains('>') {
+            return None;
+        }
+        self.pos += "<!doctype".len();
+        self.consume_whitespace();
+        let name = self
+            .consume_while(|char| !char.is_whitespace() && char != '>')
+            .to_ascii_lowercase();
+        self.consume_whitespace();
+        let keyword = self.consume_while(|char| char.is_ascii_alphabetic());
+        let (public_id, system_id) = match keyword.to_ascii_lowercase().as_str() {
+            "public" => (self.parse_doctype_id(), self.parse_doctype_id()),
+            "system" => (None, self.parse_doctype_id()),
+            _ => (None, None),
+        };
+        self.consume_while(|char| char != '>');
+        if !self.eof() {
+            self.consume_char();
+        }
+        Some(Token::Doctype(dom::Doctype {
+            name,
+            public_id,
+            system_id,
+        }))
     }
 
-    fn parse_element(&mut self) -> dom::Node {
-        assert!(self.consume_char() == '<');
-        let tag_name = self.parse_tag_name();
-        let attrs = self.parse_attributes();
-        assert!(self.consume_char() == '>');
+    fn parse_doctype_id(&mut self) -> Option<String> {
+        self.consume_whitespace();
+        if self.eof() || !matches!(self.next_char(), '"' | '\'') {
+            return None;
+        }
+        let open_quote = self.consume_char();
+        let id = self.consume_while(|char| char != open_quote && char != '>');
+        if !self.eof() && self.next_char() == open_quote {
+            self.consume_char();
+        }
+        Some(id)
+    }
 
-        let children = self.parse_nodes();
+    /// Parse `<!-- data -->`. Without `-->`, the comment runs to the end of
+    /// the input.
+    fn parse_comment(&mut self) -> Option<Token> {
+        self.pos += "<!--".len();
+        let data = match self.input[self.pos..].find("-->") {
+            Some(len) => {
+                let data = self.input[self.pos..self.pos + len].to_string();
+                self.pos += len + "-->".len();
+                data
+            }
+            None if self.ended => {
+                let data = self.input[self.pos..].to_string();
+                self.pos = self.input.len();
+                data
+            }
+            None => return None,
+        };
+        Some(Token::Comment(data))
+    }
 
-        assert!(self.consume_char() == '<');
-        assert!(self.consume_char() == '/');
-        assert!(self.parse_tag_name() == tag_name);
-        assert!(self.consume_char() == '>');
+    fn parse_bogus_comment(&mut self) -> Option<Token> {
+        let data = self.consume_while(|char| char != '>');
+        if self.eof() && !self.ended {
+            return None;
+        }
+        if !self.eof() {
+            self.consume_char();
+        }
+        Some(Token::Comment(data))
+    }
+
+    /// Parse text up to the next tag. A `<` that starts no tag is text.
+    fn parse_text(&mut self) -> Option<Token> {
+        let rest = &self.input[self.pos..];
+        let mut from = self.scanned;
+        let mut end = None;
+        while let Some(i) = rest[from..].find('<') {
+            let i = from + i;
+            match rest[i + 1..].chars().next() {
+                Some(char) if char == '!' || char == '/' || char.is_ascii_alphabetic() => {
+                    end = Some(i);
+                    break;
+                }
+                // What follows the `<` decides.
+                None if !self.ended => {
+                    self.scanned = i;
+                    return None;
+                }
+                _ => from = i + 1,
+            }
+        }
+        let len = match end {
+            Some(len) => len,
+            None if self.ended => rest.len(),
+            None => {
+                self.scanned = rest.len();
+                return None;
+            }
+        };
+        let text = decode_character_references(&rest[..len]);
+        self.pos += len;
+        Some(Token::Text(text))
+    }
+
+    /// Parse the text of raw text element `tag_name` up to its end tag. Only
+    /// the text of escapable raw text elements has its character references
+    /// decoded.
+    fn parse_raw_text(&mut self, tag_name: Atom) -> Option<Token> {
+        let rest = &self.input[self.pos..];
+        let mut end = None;
+        for (i, _) in rest[self.scanned..].match_indices('<') {
+            let i = self.scanned + i;
+            let after_name = i + "</".len() + tag_name.len();
+            // An end tag cut off by the end of the input so far is looked at
+            // again once there is more.
+            if after_name >= rest.len() && !self.ended {
+                self.scanned = i;
+                return None;
+            }
+            let name_matches = rest[i..].starts_with("</")
+                && rest
+                    .get(i + "</".len()..after_name)
+                    .is_some_and(|name| name.eq_ignore_ascii_case(&tag_name));
+            if !name_matches {
+                continue;
+            }
+            // The name must end there, as in `</style>` but not `</styles>`.
+            match rest[after_name..].chars().next() {
+                Some(char) if char.is_whitespace() || matches!(char, '/' | '>') => {
+                    end = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let len = match end {
+            Some(len) => len,
+            None if self.ended => rest.len(),
+            None => {
+                self.scanned = rest.len();
+                return None;
+            }
+        };
+        let mut text = rest[..len].to_string();
+        if dom::is_escapable_raw_text_element(&tag_name) {
+            text = decode_character_references(&text);
+        }
+        self.pos += len;
+        self.raw_text = None;
+        if text.is_empty() {
+            return self.parse_token();
+        }
+        Some(Token::Text(text))
+    }
 
-        dom::element(tag_name, attrs, children)
+    /// Parse `</name>`. Without a name, it is taken as a comment.
+    fn parse_end_tag(&mut self) -> Option<Token> {
+        self.pos += "</".len();
+        let name = self.parse_tag_name();
+        if name.is_empty() {
+            return self.parse_bogus_comment();
+        }
+        self.consume_while(|char| char != '>');
+        if self.eof() {
+            return None;
+        }
+        self.consume_char();
+        Some(Token::EndTag { name })
     }
 
-    fn parse_attr(&mut self) -> (String, String) {
+    /// Parse a start tag. The text of raw text and escapable raw text
+    /// elements in HTML content follows it.
+    fn parse_start_tag(&mut self) -> Option<Token> {
+        assert!(self.consume_char() == '<');
         let name = self.parse_tag_name();
-        assert!(self.consume_char() == '=');
-        let value = self.parse_attributes_value();
-        (name, value)
+        let (attributes, self_closing) = self.parse_attributes()?;
+        let raw_text = dom::is_raw_text_element(&name) || dom::is_escapable_raw_text_element(&name);
+        if !self.foreign && raw_text {
+            self.raw_text = Some(name.clone());
+        }
+        Some(Token::StartTag {
+            name,
+            attributes,
+            self_closing,
+        })
     }
 
-    fn parse_attributes_value(&mut self) -> String {
-        let open_quote = self.consume_char();
-        assert!(open_quote == '"' || open_quote == '\'');
-        let value = self.consume_while(|char| char != open_quote);
-        assert!(self.consume_char() == open_quote);
-        value
+    /// Parse an attribute name, lowercased. It ends at whitespace, `/`, `>`
+    /// or `=`.
+    fn parse_attribute_name(&mut self) -> Atom {
+        Atom::lowercase(&self.consume_while(|char| {
+            !char.is_whitespace() && !matches!(char, '/' | '>' | '=' | '"' | '\'' | '<')
+        }))
     }
 
-    fn parse_attributes(&mut self) -> HashMap<String, String> {
-        let mut attributes = HashMap::new();
+    fn parse_attr(&mut self) -> Option<(Atom, String)> {
+        let name = self.parse_attribute_name();
+        self.consume_whitespace();
+        if self.eof() {
+            return None;
+        }
+        if !self.start_with("=") {
+            return Some((name, String::new()));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        let value = self.parse_attributes_value()?;
+        Some((name, value))
+    }
+
+    /// Parse a quoted or unquoted attribute value.
+    fn parse_attributes_value(&mut self) -> Option<String> {
+        if self.eof() {
+            return None;
+        }
+        let value = match self.next_char() {
+            open_quote @ ('"' | '\'') => {
+                self.consume_char();
+                let value = self.consume_while(|char| char != open_quote);
+                if self.eof() {
+                    return None;
+                }
+                self.consume_char();
+                value
+            }
+            _ => self.consume_while(|char| !char.is_whitespace() && char != '>'),
+        };
+        Some(decode_character_references(&value))
+    }
+
+    /// Parse attributes in source order, up to and including the `>` or
+    /// `/>` ending the start tag, and whether it was `/>`.
+    fn parse_attributes(&mut self) -> Option<(Vec<(Atom, String)>, bool)> {
+        let mut attributes: Vec<(Atom, String)> = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.next_char() == '>' {
-                break;
+            if self.eof() {
+                return None;
+            }
+            match self.next_char() {
+                '>' => {
+                    self.consume_char();
+                    return Some((attributes, false));
+                }
+                '/' => {
+                    self.consume_char();
+                    if self.start_with(">") {
+                        self.consume_char();
+                        return Some((attributes, true));
+                    }
+                }
+                '=' | '"' | '\'' | '<' => {
+                    self.consume_char();
+                }
+                _ => {
+                    let (name, value) = self.parse_attr()?;
+                    if attributes.iter().all(|(other, _)| *other != name) {
+                        attributes.push((name, value));
+                    }
+                }
             }
-            let (name, value) = self.parse_attr();
-            attributes.insert(name, value);
         }
+    }
+}
+
+impl Iterator for Tokenizer {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let start = self.pos;
+        let raw_text = self.raw_text.clone();
+        let token = self.parse_token();
+        if token.is_some() {
+            self.scanned = 0;
+        } else {
+            if self.ended {
+                self.pos = self.input.len();
+            } else {
+                self.pos = start;
+                self.raw_text = raw_text;
+            }
+        }
+        let end = self.location.advance(&self.input[start..self.pos]);
+        self.span = Span {
+            start: self.location,
+            end,
+        };
+        self.location = end;
+        token
+    }
+}
+
+/// Builds a `dom::Document` from the tokens of HTML fed to it a chunk at a
+/// time. Open elements are kept on a stack rather than the call stack, so
+/// any depth of nesting can be parsed.
+#[derive(Debug)]
+pub struct TreeBuilder {
+    tokenizer: Tokenizer,
+    tree: dom::Tree,
+    /// The elements whose end tag hasn't come yet, innermost last.
+    open_elements: Vec<dom::NodeId>,
+    doctype: Option<dom::Doctype>,
+}
+
+impl Default for TreeBuilder {
+    fn default() -> TreeBuilder {
+        TreeBuilder::new()
+    }
+}
+
+impl TreeBuilder {
+    pub fn new() -> TreeBuilder {
+        TreeBuilder {
+            tokenizer: Tokenizer::new(),
+            tree: dom::NodeBuilder {
+                node_type: dom::NodeType::Document,
+                children: Vec::new(),
+            }
+            .build(),
+            open_elements: Vec::new(),
+            doctype: None,
+        }
+    }
+
+    /// Add `chunk` to the page, building what can be built of it.
+    pub fn feed(&mut self, chunk: &str) {
+        self.tokenizer.feed(chunk);
+        self.build();
+    }
+
+    /// The document, once the whole page has been fed. Elements left open
+    /// are closed.
+    pub fn finish(mut self) -> dom::Document {
+        self.tokenizer.end();
+        self.build();
+        let end = self.tokenizer.location;
+        self.close_elements(0, end);
+        let root = self.tree.root().id();
+        let start = SourceLocation::default();
+        self.tree.set_span(root, Span { start, end });
+        dom::Document::from_tree(self.doctype, self.tree)
+    }
+
+    fn build(&mut self) {
+        while let Some(token) = self.tokenizer.next() {
+            let span = self.tokenizer.span();
+            self.process(token, span);
+            let foreign = self.namespace() != dom::Namespace::Html;
+            self.tokenizer.set_foreign(foreign);
+        }
+    }
+
+    fn current_node(&self) -> dom::NodeId {
+        match self.open_elements.last() {
+            Some(&id) => id,
+            None => self.tree.root().id(),
+        }
+    }
+
+    fn current_element(&self) -> Option<&dom::ElementData> {
+        self.tree.get(*self.open_elements.last()?).element()
+    }
+
+    /// The namespace of the children of the current node.
+    fn namespace(&self) -> dom::Namespace {
+        match self.current_element() {
+            Some(elem) => children_namespace(elem.namespace, &elem.tag_name),
+            None => dom::Namespace::Html,
+        }
+    }
+
+    /// Pop the open elements from the `index`th on, which end at `end`.
+    fn close_elements(&mut self, index: usize, end: SourceLocation) {
+        for id in self.open_elements.drain(index..) {
+            let start = self.tree.get(id).span().unwrap_or_default().start;
+            self.tree.set_span(id, Span { start, end });
+        }
+    }
+
+    fn process(&mut self, token: Token, span: Span) {
+        match token {
+            Token::Doctype(doctype) => {
+                // Only the first doctype counts, and only before any element.
+                let before_elements = self.open_elements.is_empty()
+                    && self
+                        .tree
+                        .root()
+                        .children()
+                        .all(|child| child.element().is_none());
+                if self.doctype.is_none() && before_elements {
+                    self.doctype = Some(doctype);
+                }
+            }
+            Token::StartTag {
+                name,
+                attributes,
+                self_closing,
+            } => self.insert_element(name, attributes, self_closing, span),
+            Token::EndTag { name } => {
+                let tree = &self.tree;
+                let open = self.open_elements.iter().rposition(|&id| {
+                    let elem = tree.get(id).element().unwrap();
+                    adjust_tag_name(elem.namespace, name.clone()) == elem.tag_name
+                });
+                // An end tag with no open element is ignored. The elements
+                // inside the one it closes end where it starts.
+                if let Some(open) = open {
+                    self.close_elements(open + 1, span.start);
+                    self.close_elements(open, span.end);
+                }
+            }
+            Token::Text(text) => {
+                // White space is kept for layout to collapse or preserve,
+                // except a newline just inside a `<pre>`, which is only
+                // there to start its content on a line of its own.
+                let parent = self.current_node();
+                let starts_pre = self.current_element().is_some_and(|elem| {
+                    elem.namespace == dom::Namespace::Html
+                        && dom::drops_leading_newline(&elem.tag_name)
+                }) && self.tree.get(parent).children().next().is_none();
+                let kept = match text.strip_prefix("\r\n").or(text.strip_prefix('\n')) {
+                    Some(rest) if starts_pre => rest,
+                    _ => &text,
+                };
+                if !kept.is_empty() {
+                    let id = self.tree.push_child(parent, dom::text(kept.to_string()));
+                    let start = span.start.advance(&text[..text.len() - kept.len()]);
+                    self.tree.set_span(id, Span { start, ..span });
+                }
+            }
+            Token::Comment(data) => {
+                let parent = self.current_node();
+                let id = self.tree.push_child(parent, dom::comment(data));
+                self.tree.set_span(id, span);
+            }
+        }
+    }
+
+    /// Add an element for a start tag. `<svg>` and `<math>` start foreign
+    /// content, where names get back the case SVG gives them and `/>`
+    /// closes an element. An HTML tag that breaks out of foreign content
+    /// first closes the foreign elements it is in.
+    fn insert_element(
+        &mut self,
+        name: Atom,
+        attributes: Vec<(Atom, String)>,
+        self_closing: bool,
+        span: Span,
+    ) {
+        if self.namespace() != dom::Namespace::Html && breaks_out(&name, &attributes) {
+            let tree = &self.tree;
+            let html = self.open_elements.iter().rposition(|&id| {
+                let elem = tree.get(id).element().unwrap();
+                children_namespace(elem.namespace, &elem.tag_name) == dom::Namespace::Html
+            });
+            self.close_elements(html.map_or(0, |index| index + 1), span.start);
+        }
+        let namespace = match self.namespace() {
+            dom::Namespace::Html if name == "svg" => dom::Namespace::Svg,
+            dom::Namespace::Html if name == "math" => dom::Namespace::MathMl,
+            namespace => namespace,
+        };
+        let tag_name = adjust_tag_name(namespace, name);
+        let mut attrs = dom::AttrMap::new();
+        for (name, value) in attributes {
+            attrs.insert(adjust_attribute_name(namespace, name), value);
+        }
+        let parent = self.current_node();
+        let id = self.tree.push_child(
+            parent,
+            dom::element_ns(namespace, tag_name.clone(), attrs, Vec::new()),
+        );
+        self.tree.set_span(id, span);
+        let html = namespace == dom::Namespace::Html;
+        if !(html && dom::is_void_element(&tag_name) || !html && self_closing) {
+            self.open_elements.push(id);
+        }
+    }
+}
+
+/// SVG element names that have capitals, by their lowercased form.
+const SVG_TAG_NAMES: [(&str, &str); 37] = [
+    ("altglyph", "altGlyph"),
+    ("altglyphdef", "altGlyphDef"),
+    ("altglyphitem", "altGlyphItem"),
+    ("animatecolor", "animateColor"),
+    ("animatemotion", "animateMotion"),
+    ("animatetransform", "animateTransform"),
+    ("clippath", "clipPath"),
+    ("feblend", "feBlend"),
+    ("fecolormatrix", "feColorMatrix"),
+    ("fecomponenttransfer", "feComponentTransfer"),
+    ("fecomposite", "feComposite"),
+    ("feconvolvematrix", "feConvolveMatrix"),
+    ("fediffuselighting", "feDiffuseLighting"),
+    ("fedisplacementmap", "feDisplacementMap"),
+    ("fedistantlight", "feDistantLight"),
+    ("fedropshadow", "feDropShadow"),
+    ("feflood", "feFlood"),
+    ("fefunca", "feFuncA"),
+    ("fefuncb", "feFuncB"),
+    ("fefuncg", "feFuncG"),
+    ("fefuncr", "feFuncR"),
+    ("fegaussianblur", "feGaussianBlur"),
+    ("feimage", "feImage"),
+    ("femerge", "feMerge"),
+    ("femergenode", "feMergeNode"),
+    ("femorphology", "feMorphology"),
+    ("feoffset", "feOffset"),
+    ("fepointlight", "fePointLight"),
+    ("fespecularlighting", "feSpecularLighting"),
+    ("fespotlight", "feSpotLight"),
+    ("fetile", "feTile"),
+    ("feturbulence", "feTurbulence"),
+    ("foreignobject", "foreignObject"),
+    ("glyphref", "glyphRef"),
+    ("lineargradient", "linearGradient"),
+    ("radialgradient", "radialGradient"),
+    ("textpath", "textPath"),
+];
+
+/// SVG attribute names that have capitals, by their lowercased form.
+const SVG_ATTRIBUTE_NAMES: [(&str, &str); 58] = [
+    ("attributename", "attributeName"),
+    ("attributetype", "attributeType"),
+    ("basefrequency", "baseFrequency"),
+    ("baseprofile", "baseProfile"),
+    ("calcmode", "calcMode"),
+    ("clippathunits", "clipPathUnits"),
+    ("diffuseconstant", "diffuseConstant"),
+    ("edgemode", "edgeMode"),
+    ("filterunits", "filterUnits"),
+    ("glyphref", "glyphRef"),
+    ("gradienttransform", "gradientTransform"),
+    ("gradientunits", "gradientUnits"),
+    ("kernelmatrix", "kernelMatrix"),
+    ("kernelunitlength", "kernelUnitLength"),
+    ("keypoints", "keyPoints"),
+    ("keysplines", "keySplines"),
+    ("keytimes", "keyTimes"),
+    ("lengthadjust", "lengthAdjust"),
+    ("limitingconeangle", "limitingConeAngle"),
+    ("markerheight", "markerHeight"),
+    ("markerunits", "markerUnits"),
+    ("markerwidth", "markerWidth"),
+    ("maskcontentunits", "maskContentUnits"),
+    ("maskunits", "maskUnits"),
+    ("numoctaves", "numOctaves"),
+    ("pathlength", "pathLength"),
+    ("patterncontentunits", "patternContentUnits"),
+    ("patterntransform", "patternTransform"),
+    ("patternunits", "patternUnits"),
+    ("pointsatx", "pointsAtX"),
+    ("pointsaty", "pointsAtY"),
+    ("pointsatz", "pointsAtZ"),
+    ("preservealpha", "preserveAlpha"),
+    ("preserveaspectratio", "preserveAspectRatio"),
+    ("primitiveunits", "primitiveUnits"),
+    ("refx", "refX"),
+    ("refy", "refY"),
+    ("repeatcount", "repeatCount"),
+    ("repeatdur", "repeatDur"),
+    ("requiredextensions", "requiredExtensions"),
+    ("requiredfeatures", "requiredFeatures"),
+    ("specularconstant", "specularConstant"),
+    ("specularexponent", "specularExponent"),
+    ("spreadmethod", "spreadMethod"),
+    ("startoffset", "startOffset"),
+    ("stddeviation", "stdDeviation"),
+    ("stitchtiles", "stitchTiles"),
+    ("surfacescale", "surfaceScale"),
+    ("systemlanguage", "systemLanguage"),
+    ("tablevalues", "tableValues"),
+    ("targetx", "targetX"),
+    ("targety", "targetY"),
+    ("textlength", "textLength"),
+    ("viewbox", "viewBox"),
+    ("viewtarget", "viewTarget"),
+    ("xchannelselector", "xChannelSelector"),
+    ("ychannelselector", "yChannelSelector"),
+    ("zoomandpan", "zoomAndPan"),
+];
+
+/// `name`, lowercased by the parser, with the case it has in `namespace`.
+fn adjust_tag_name(namespace: dom::Namespace, name: Atom) -> Atom {
+    match namespace {
+        dom::Namespace::Svg => adjust_name(&SVG_TAG_NAMES, name),
+        _ => name,
+    }
+}
+
+fn adjust_attribute_name(namespace: dom::Namespace, name: Atom) -> Atom {
+    match namespace {
+        dom::Namespace::Svg => adjust_name(&SVG_ATTRIBUTE_NAMES, name),
+        dom::Namespace::MathMl if name == "definitionurl" => Atom::new("definitionURL"),
+        _ => name,
+    }
+}
+
+fn adjust_name(names: &[(&str, &str)], name: Atom) -> Atom {
+    match names.binary_search_by_key(&name.as_str(), |&(lowercase, _)| lowercase) {
+        Ok(index) => Atom::new(names[index].1),
+        Err(_) => name,
+    }
+}
+
+/// The namespace of the children of element `tag_name`. The text elements
+/// of SVG and MathML hold HTML again.
+fn children_namespace(namespace: dom::Namespace, tag_name: &str) -> dom::Namespace {
+    let html_inside = match namespace {
+        dom::Namespace::Html => false,
+        dom::Namespace::Svg => ["foreignObject", "desc", "title"].contains(&tag_name),
+        dom::Namespace::MathMl => ["mi", "mo", "mn", "ms", "mtext"].contains(&tag_name),
+    };
+    if html_inside {
+        dom::Namespace::Html
+    } else {
+        namespace
+    }
+}
+
+/// HTML tags that end the foreign content they start in, as a page that
+/// forgot to close an `<svg>` or `<math>` would have meant.
+const BREAKOUT_TAG_NAMES: [&str; 44] = [
+    "b",
+    "big",
+    "blockquote",
+    "body",
+    "br",
+    "center",
+    "code",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "embed",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "listing",
+    "menu",
+    "meta",
+    "nobr",
+    "ol",
+    "p",
+    "pre",
+    "ruby",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "strike",
+    "sub",
+    "sup",
+    "table",
+    "tt",
+    "u",
+    "ul",
+    "var",
+];
+
+/// Whether a start tag breaks out of foreign content. `<font>` only does
+/// with the attributes HTML gives it.
+fn breaks_out(name: &str, attributes: &[(Atom, String)]) -> bool {
+    if name == "font" {
         attributes
+            .iter()
+            .any(|(attr, _)| ["color", "face", "size"].contains(&attr.as_str()))
+    } else {
+        BREAKOUT_TAG_NAMES.contains(&name)
     }
+}
 
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
-        let mut nodes = Vec::new();
-        loop {
-            self.consume_whitespace();
-            if self.eof() || self.start_with("</") {
-                break;
+/// Replace the character references the serializer writes, and numeric ones,
+/// with the characters they stand for. Anything else is left as it is.
+fn decode_character_references(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..]
+            .find(';')
+            .and_then(|end| Some((end + 2, decode_reference(&rest[1..end + 1])?)));
+        match reference {
+            Some((len, char)) => {
+                decoded.push(char);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
             }
-            let node = self.parse_node();
-            nodes.push(node);
         }
-        nodes
     }
+    decoded.push_str(rest);
+    decoded
 }
 
-pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser {
-        pos: 0,
-        input: source,
+fn decode_reference(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let (digits, radix) = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16),
+                None => (number, 10),
+            };
+            if digits.is_empty() || !digits.chars().all(|char| char.is_digit(radix)) {
+                return None;
+            }
+            let code = digits.chars().fold(0u32, |code, digit| {
+                code.saturating_mul(radix)
+                    .saturating_add(digit.to_digit(radix).unwrap())
+            });
+            // NUL, surrogates and numbers past Unicode stand for U+FFFD.
+            match code {
+                0 => Some('\u{fffd}'),
+                code => Some(char::from_u32(code).unwrap_or('\u{fffd}')),
+            }
+        }
     }
-    .parse_nodes();
+}
 
-    nodes.pop().unwrap()
+pub fn parse(source: String) -> dom::Document {
+    let mut builder = TreeBuilder::new();
+    builder.feed(&source);
+    builder.finish()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn get_parser(source: &str) -> Parser {
-        Parser {
-            pos: 0,
-            input: source.to_string(),
+    fn get_tokenizer(source: &str) -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.feed(source);
+        tokenizer.end();
+        tokenizer
+    }
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        get_tokenizer(source).collect()
+    }
+
+    fn start_tag(name: &str, attributes: &[(&str, &str)], self_closing: bool) -> Token {
+        Token::StartTag {
+            name: Atom::new(name),
+            attributes: attributes
+                .iter()
+                .map(|&(name, value)| (Atom::new(name), value.to_string()))
+                .collect(),
+            self_closing,
+        }
+    }
+
+    fn end_tag(name: &str) -> Token {
+        Token::EndTag {
+            name: Atom::new(name),
         }
     }
 
     #[test]
     fn test_next_char() {
         let source = "Test";
-        assert_eq!(Parser::next_char(&get_parser(source)), 'T');
+        assert_eq!(Tokenizer::next_char(&get_tokenizer(source)), 'T');
     }
 
     #[test]
     fn test_next_next_char() {
         let source = "Test";
-        assert_eq!(Parser::next_next_char(&get_parser(source)), 'e');
+        assert_eq!(Tokenizer::next_next_char(&get_tokenizer(source)), Some('e'));
     }
 
     #[test]
     fn test_start_with() {
         let source = "Test";
-        assert!(Parser::start_with(&get_parser(source), "T"));
-        assert!(!Parser::start_with(&get_parser(source), "e"));
+        assert!(Tokenizer::start_with(&get_tokenizer(source), "T"));
+        assert!(!Tokenizer::start_with(&get_tokenizer(source), "e"));
     }
 
     #[test]
     fn test_eof() {
         let source = "Test";
-        let mut parser = get_parser(source);
-        assert!(!Parser::eof(&parser));
-        parser.pos = 4;
-        assert!(Parser::eof(&parser));
+        let mut tokenizer = get_tokenizer(source);
+        assert!(!Tokenizer::eof(&tokenizer));
+        tokenizer.pos = 4;
+        assert!(Tokenizer::eof(&tokenizer));
     }
 
     #[test]
     fn test_consume_char() {
         let source = "Test";
-        assert_eq!(Parser::consume_char(&mut get_parser(source)), 'T');
+        assert_eq!(Tokenizer::consume_char(&mut get_tokenizer(source)), 'T');
     }
 
     #[test]
     fn test_consume_while() {
         let source = "test";
         assert_eq!(
-            Parser::consume_while(&mut get_parser(source), |char| matches!(char, 'a'..='z')),
+            Tokenizer::consume_while(&mut get_tokenizer(source), |char: char| char
+                .is_ascii_lowercase()),
             "test"
         );
     }
@@ -199,33 +989,33 @@ mod tests {
     #[test]
     fn test_consume_whitespace() {
         let source = "   test";
-        let mut parser = get_parser(source);
-        Parser::consume_whitespace(&mut parser);
-        assert_eq!(parser.pos, 3);
+        let mut tokenizer = get_tokenizer(source);
+        Tokenizer::consume_whitespace(&mut tokenizer);
+        assert_eq!(tokenizer.pos, 3);
     }
 
     #[test]
     fn test_parse_tag_name() {
         let source = "h1>";
-        assert_eq!(Parser::parse_tag_name(&mut get_parser(source)), "h1");
+        assert_eq!(Tokenizer::parse_tag_name(&mut get_tokenizer(source)), "h1");
     }
 
     #[test]
-    fn test_parse_node() {
+    fn test_parse_token() {
         let comment = "<!-- comment -->";
         let elem = "<title>Title</title>";
         let text = "text";
         assert_eq!(
-            Parser::parse_node(&mut get_parser(comment)),
-            Parser::parse_comment(&mut get_parser(comment))
+            Tokenizer::parse_token(&mut get_tokenizer(comment)),
+            Tokenizer::parse_comment(&mut get_tokenizer(comment))
         );
         assert_eq!(
-            Parser::parse_node(&mut get_parser(elem)),
-            Parser::parse_element(&mut get_parser(elem))
+            Tokenizer::parse_token(&mut get_tokenizer(elem)),
+            Tokenizer::parse_start_tag(&mut get_tokenizer(elem))
         );
         assert_eq!(
-            Parser::parse_node(&mut get_parser(text)),
-            Parser::parse_text(&mut get_parser(text))
+            Tokenizer::parse_token(&mut get_tokenizer(text)),
+            Tokenizer::parse_text(&mut get_tokenizer(text))
         );
     }
 
@@ -233,19 +1023,18 @@ mod tests {
     fn test_parse_comment() {
         let comment = "<!-- comment -->";
         assert_eq!(
-            Parser::parse_comment(&mut get_parser(comment)),
-            dom::comment()
+            Tokenizer::parse_comment(&mut get_tokenizer(comment)),
+            Some(Token::Comment(" comment ".to_string()))
         );
     }
 
     #[test]
     fn test_parse_text() {
         let text = "text<p>";
-        let node = dom::Node {
-            node_type: dom::NodeType::Text("text".to_string()),
-            children: Vec::new(),
-        };
-        assert_eq!(Parser::parse_text(&mut get_parser(text)), node);
+        assert_eq!(
+            Tokenizer::parse_text(&mut get_tokenizer(text)),
+            Some(Token::Text("text".to_string()))
+        );
     }
 
     #[test]
@@ -253,18 +1042,266 @@ mod tests {
         let elem = "<title>Title</title>";
         let expected = dom::element(
             "title".to_string(),
-            HashMap::new(),
+            dom::AttrMap::new(),
             vec![dom::text("Title".to_string())],
         );
-        assert_eq!(Parser::parse_element(&mut get_parser(elem)), expected);
+        assert_eq!(
+            parse(elem.to_string()),
+            dom::Document::new(None, vec![expected])
+        );
+    }
+
+    #[test]
+    fn test_parse_void_and_raw_text_elements() {
+        let source = "<div><br><script>if (a < b && c) {}</script></div>";
+        let expected = dom::element(
+            "div".to_string(),
+            dom::AttrMap::new(),
+            vec![
+                dom::element("br".to_string(), dom::AttrMap::new(), vec![]),
+                dom::element(
+                    "script".to_string(),
+                    dom::AttrMap::new(),
+                    vec![dom::text("if (a < b && c) {}".to_string())],
+                ),
+            ],
+        );
+        assert_eq!(
+            parse(source.to_string()),
+            dom::Document::new(None, vec![expected])
+        );
+
+        // The end tag must end with its name.
+        let style = parse("<style></stylex>a</style >b".to_string());
+        assert_eq!(
+            style
+                .root()
+                .first_child()
+                .unwrap()
+                .first_child()
+                .unwrap()
+                .node_type,
+            dom::NodeType::Text("</stylex>a".to_string())
+        );
+        // The `b` after it is outside.
+        assert_eq!(style.root().children().count(), 2);
+    }
+
+    #[test]
+    fn test_parse_escapable_raw_text_elements() {
+        let document =
+            parse("<title>a &amp; <b>b</b></title><textarea>\n<p>&lt;</textarea>".to_string());
+        let texts: Vec<_> = document
+            .root()
+            .children()
+            .map(|node| match &node.first_child().unwrap().node_type {
+                dom::NodeType::Text(text) => text.clone(),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(texts, ["a & <b>b</b>", "<p><"]);
+    }
+
+    #[test]
+    fn test_parse_document_keeps_top_level_nodes() {
+        let document = parse(
+            "<!-- a --><!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01//EN\" \"strict.dtd\">\
+             <html><body>1</body></html><!-- b -->"
+                .to_string(),
+        );
+        assert_eq!(
+            document.doctype,
+            Some(dom::Doctype {
+                name: "html".to_string(),
+                public_id: Some("-//W3C//DTD HTML 4.01//EN".to_string()),
+                system_id: Some("strict.dtd".to_string()),
+            })
+        );
+        assert_eq!(document.quirks_mode, dom::QuirksMode::NoQuirks);
+        let top_level: Vec<_> = document.root().children().collect();
+        assert_eq!(top_level.len(), 3);
+        assert_eq!(
+            top_level[0].node_type,
+            dom::NodeType::Comment(" a ".to_string())
+        );
+        assert_eq!(
+            top_level[2].node_type,
+            dom::NodeType::Comment(" b ".to_string())
+        );
+        assert_eq!(
+            document
+                .document_element()
+                .unwrap()
+                .element()
+                .unwrap()
+                .tag_name,
+            "html"
+        );
+    }
+
+    #[test]
+    fn test_parse_document_quirks_mode() {
+        let quirks_mode = |source: &str| parse(source.to_string()).quirks_mode;
+        assert_eq!(
+            quirks_mode("<!doctype html><p></p>"),
+            dom::QuirksMode::NoQuirks
+        );
+        assert_eq!(quirks_mode("<p></p>"), dom::QuirksMode::Quirks);
+        assert_eq!(
+            quirks_mode("<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\"><p></p>"),
+            dom::QuirksMode::Quirks
+        );
+        assert_eq!(
+            quirks_mode("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\"><p></p>"),
+            dom::QuirksMode::LimitedQuirks
+        );
+        // A doctype after the first element is ignored.
+        assert_eq!(
+            quirks_mode("<p></p><!doctype html>"),
+            dom::QuirksMode::Quirks
+        );
+    }
+
+    #[test]
+    fn test_parse_foreign_content() {
+        let document = parse(
+            "<div><SVG ViewBox='0 0 10 10' xlink:href='#a'><rect width='1'/>\
+             <clippath><Circle r='1' /></clippath><foreignobject><p>x<br/></p></foreignobject>\
+             </svg><math definitionurl='u'><mi>x</mi></math></div>"
+                .to_string(),
+        );
+        let div = document.document_element().unwrap();
+        let svg = div.first_child().unwrap();
+        let elem = svg.element().unwrap();
+        assert_eq!(
+            (elem.namespace, elem.tag_name.as_str()),
+            (dom::Namespace::Svg, "svg")
+        );
+        assert_eq!(
+            elem.attributes.iter().collect::<Vec<_>>(),
+            [("viewBox", "0 0 10 10"), ("xlink:href", "#a")]
+        );
+        let names: Vec<_> = svg
+            .descendants()
+            .filter_map(|node| node.element())
+            .map(|elem| (elem.namespace, elem.tag_name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (dom::Namespace::Svg, "svg"),
+                (dom::Namespace::Svg, "rect"),
+                (dom::Namespace::Svg, "clipPath"),
+                (dom::Namespace::Svg, "circle"),
+                (dom::Namespace::Svg, "foreignObject"),
+                (dom::Namespace::Html, "p"),
+                (dom::Namespace::Html, "br"),
+            ]
+        );
+        let math = svg.next_sibling().unwrap();
+        assert_eq!(math.element().unwrap().namespace, dom::Namespace::MathMl);
+        assert!(math
+            .element()
+            .unwrap()
+            .attributes
+            .contains_key("definitionURL"));
+
+        let html = div.outer_html(dom::Format::Exact);
+        assert_eq!(
+            html,
+            "<div><svg viewBox=\"0 0 10 10\" xlink:href=\"#a\"><rect width=\"1\"/>\
+             <clipPath><circle r=\"1\"/></clipPath><foreignObject><p>x<br></p></foreignObject>\
+             </svg><math definitionURL=\"u\"><mi>x</mi></math></div>"
+        );
+        assert_eq!(parse(html), document);
+    }
+
+    #[test]
+    fn test_parse_breaks_out_of_foreign_content() {
+        let names = |html: &str| -> Vec<_> {
+            parse(html.to_string())
+                .root()
+                .descendants()
+                .filter_map(|node| node.element())
+                .map(|elem| (elem.namespace, elem.tag_name.as_str().to_string()))
+                .collect()
+        };
+        let svg = |name: &str| (dom::Namespace::Svg, name.to_string());
+        let html = |name: &str| (dom::Namespace::Html, name.to_string());
+        // `<p>` closes the `<svg>` and `<g>` it is in and the rest of the
+        // page is HTML again.
+        assert_eq!(
+            names("<div><svg><g><p>x</p><rect/></g></svg></div>"),
+            [html("div"), svg("svg"), svg("g"), html("p"), html("rect")]
+        );
+        // Inside an integration point, only the foreign elements below it
+        // are closed.
+        assert_eq!(
+            names("<svg><foreignObject><svg><b>x</b></svg></foreignObject></svg>"),
+            [svg("svg"), svg("foreignObject"), svg("svg"), html("b")]
+        );
+        // `<font>` breaks out only with presentational attributes.
+        assert_eq!(
+            names("<math><font id=a></font><font color=red></font></math>"),
+            [
+                (dom::Namespace::MathMl, "math".to_string()),
+                (dom::Namespace::MathMl, "font".to_string()),
+                html("font")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_character_references() {
+        assert_eq!(
+            decode_character_references("a &amp;&lt;b&gt; &#65;&#x42; &nbsp;&copy; & c;"),
+            "a &<b> AB \u{a0}&copy; & c;"
+        );
+        assert_eq!(
+            decode_character_references("&#0;&#xD800;&#x110000;&#99999999999;&#x;"),
+            "\u{fffd}\u{fffd}\u{fffd}\u{fffd}&#x;"
+        );
+    }
+
+    #[test]
+    fn test_serialized_html_round_trips() {
+        let source = "<html><div class=\"a &amp; b\" title='say \"hi\"'>1 &lt; 2\
+                      <!-- note --><p>x &amp; y</p><style>p > a { }</style><hr></div></html>";
+        let tree = parse(source.to_string());
+        let html = tree
+            .document_element()
+            .unwrap()
+            .outer_html(dom::Format::Exact);
+        assert_eq!(
+            html,
+            "<html><div class=\"a &amp; b\" title=\"say &quot;hi&quot;\">1 &lt; 2<!-- note -->\
+             <p>x &amp; y</p><style>p > a { }</style><hr></div></html>"
+        );
+        assert_eq!(parse(html), tree);
+
+        // The newline parsing drops after `<pre>` is written back in front
+        // of one that starts the text.
+        for source in [
+            "<pre>\n\nx</pre>",
+            "<textarea>\n</textarea>",
+            "<pre>x\n</pre>",
+        ] {
+            let tree = parse(source.to_string());
+            let html = tree.to_html(dom::Format::Exact);
+            assert_eq!(parse(html), tree, "{source:?}");
+        }
+        assert_eq!(
+            parse("<pre>\n\nx</pre>".to_string()).to_html(dom::Format::Exact),
+            "<pre>\n\nx</pre>"
+        );
     }
 
     #[test]
     fn test_parse_attr() {
         let attr = "id=\"1\"";
         assert_eq!(
-            Parser::parse_attr(&mut get_parser(attr)),
-            ("id".to_string(), "1".to_string())
+            Tokenizer::parse_attr(&mut get_tokenizer(attr)),
+            Some((Atom::new("id"), "1".to_string()))
         );
     }
 
@@ -272,15 +1309,32 @@ mod tests {
     fn test_parse_attributes_value() {
         let value = "\"1\"";
         assert_eq!(
-            Parser::parse_attributes_value(&mut get_parser(value)),
-            "1".to_string()
+            Tokenizer::parse_attributes_value(&mut get_tokenizer(value)),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_attributes_keeps_source_order_and_first_duplicate() {
+        let attributes =
+            Tokenizer::parse_attributes(&mut get_tokenizer("title='t' id='a' class='x' id='b'>"));
+        assert_eq!(
+            attributes,
+            Some((
+                vec![
+                    (Atom::new("title"), "t".to_string()),
+                    (Atom::new("id"), "a".to_string()),
+                    (Atom::new("class"), "x".to_string()),
+                ],
+                false
+            ))
         );
     }
 
     #[test]
     fn test_parse_nodes() {
         let source = "<title id='1'>Test</title>";
-        println!("{:?}", Parser::parse_nodes(&mut get_parser(source)));
+        println!("{:?}", tokenize(source));
     }
 
     #[test]
@@ -291,6 +1345,152 @@ mod tests {
             <title id='1'>Test</title>
         </div>
         ";
-        println!("{:?}", Parser::parse_nodes(&mut get_parser(source)));
+        println!("{:?}", tokenize(source));
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            tokenize(
+                "<!DOCTYPE html><P Class=a hidden>1 &lt; 2</p><!-- x -->\
+                 <script>a</b></SCRIPT><svg><style>s</style><br/></svg>"
+            ),
+            [
+                Token::Doctype(dom::Doctype {
+                    name: "html".to_string(),
+                    public_id: None,
+                    system_id: None,
+                }),
+                start_tag("p", &[("class", "a"), ("hidden", "")], false),
+                Token::Text("1 < 2".to_string()),
+                end_tag("p"),
+                Token::Comment(" x ".to_string()),
+                start_tag("script", &[], false),
+                Token::Text("a</b>".to_string()),
+                end_tag("script"),
+                start_tag("svg", &[], false),
+                start_tag("style", &[], false),
+                Token::Text("s".to_string()),
+                end_tag("style"),
+                start_tag("br", &[], true),
+                end_tag("svg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_accepts_chunks() {
+        let source = "<!doctype html><div title='a &amp; b'>x < y<!-- c --><style>p{}</style>\
+                      <svg viewbox='0 0 1 1'><rect/></svg>é</div>";
+        let mut tokenizer = Tokenizer::new();
+        let mut tokens = Vec::new();
+        for char in source.chars() {
+            tokenizer.feed(&char.to_string());
+            tokens.extend(&mut tokenizer);
+        }
+        tokenizer.end();
+        tokens.extend(&mut tokenizer);
+        assert_eq!(tokens, tokenize(source));
+
+        let mut builder = TreeBuilder::new();
+        let chars: Vec<char> = source.chars().collect();
+        for chunk in chars.chunks(7) {
+            builder.feed(&chunk.iter().collect::<String>());
+        }
+        assert_eq!(builder.finish(), parse(source.to_string()));
+    }
+
+    #[test]
+    fn test_tokenizer_scans_chunked_text_once() {
+        for source in [
+            format!("<p>{}</p>", "x < y &amp; é ".repeat(2000)),
+            format!(
+                "<script>{}</SCRIPT >",
+                "x < y </scrip </scriptx ".repeat(1000)
+            ),
+        ] {
+            let mut tokenizer = Tokenizer::new();
+            let mut tokens = Vec::new();
+            let mut chunks = source.as_bytes().chunks(5).peekable();
+            while let Some(chunk) = chunks.next() {
+                tokenizer.feed(std::str::from_utf8(chunk).unwrap());
+                tokens.extend(&mut tokenizer);
+                if chunks.peek().is_some() && tokens.len() == 1 {
+                    // All but at most the last few bytes are never looked at
+                    // again.
+                    assert!(tokenizer.scanned + "</script".len() >= tokenizer.input.len());
+                }
+            }
+            tokenizer.end();
+            tokens.extend(&mut tokenizer);
+            assert_eq!(tokens, tokenize(&source));
+        }
+    }
+
+    #[test]
+    fn test_parse_deeply_nested_elements() {
+        let depth = 100_000;
+        let document = parse("<div>".repeat(depth));
+        let deepest = document.root().descendants().last().unwrap();
+        assert_eq!(deepest.ancestors().count(), depth);
+    }
+
+    #[test]
+    fn test_parse_unbalanced_tags() {
+        let document = parse("</p><div><p>a</div>b</span><i>c".to_string());
+        assert_eq!(
+            document.root().inner_html(dom::Format::Exact),
+            "<div><p>a</p></div>b<i>c</i>"
+        );
+    }
+
+    #[test]
+    fn test_parse_keeps_white_space() {
+        let document =
+            parse("<div> a <b>b</b>\n</div> <pre>\n\n x</pre><pre>y\n</pre>".to_string());
+        let texts: Vec<_> = document
+            .root()
+            .descendants()
+            .filter_map(|node| match &node.node().node_type {
+                dom::NodeType::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        // Only the newline starting a `<pre>` is dropped.
+        assert_eq!(texts, [" a ", "b", "\n", " ", "\n x", "y\n"]);
+    }
+
+    #[test]
+    fn test_parse_records_spans() {
+        let source =
+            "<!doctype html>\n<div id='a'>\n  <p>x &amp; y</p><!-- c -->\n  <i>z</div>é<br>";
+        let document = parse(source.to_string());
+        let text = |node: dom::NodeRef| &source[node.span().unwrap().range()];
+        let div = document.document_element().unwrap();
+        let indent = div.first_child().unwrap();
+        let p = indent.next_sibling().unwrap();
+        let i = div.last_child().unwrap();
+        assert_eq!(
+            text(div),
+            "<div id='a'>\n  <p>x &amp; y</p><!-- c -->\n  <i>z</div>"
+        );
+        assert_eq!(text(indent), "\n  ");
+        assert_eq!(text(p), "<p>x &amp; y</p>");
+        assert_eq!(text(p.first_child().unwrap()), "x &amp; y");
+        assert_eq!(text(p.next_sibling().unwrap()), "<!-- c -->");
+        // An element closed by its parent's end tag ends where that starts.
+        assert_eq!(text(i), "<i>z");
+        assert_eq!(text(document.root()), source);
+
+        let br = div.next_sibling().unwrap().next_sibling().unwrap();
+        assert_eq!(br.span().unwrap().to_string(), "4:14-4:18");
+        assert_eq!(
+            p.span().unwrap().start,
+            SourceLocation {
+                offset: 31,
+                line: 3,
+                column: 3
+            }
+        );
     }
 }
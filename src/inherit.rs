@@ -0,0 +1,103 @@
+use crate::style::{self, PropertyMap};
+
+/// Properties whose value passes from an element to its children when the
+/// children don't set it themselves.
+const INHERITED_PROPERTIES: &[&str] = &[
+    "border-collapse",
+    "border-spacing",
+    "caption-side",
+    "color",
+    "cursor",
+    "direction",
+    "empty-cells",
+    "font",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-variant",
+    "font-weight",
+    "letter-spacing",
+    "line-height",
+    "list-style",
+    "list-style-image",
+    "list-style-position",
+    "list-style-type",
+    "quotes",
+    "text-align",
+    "text-indent",
+    "text-transform",
+    "visibility",
+    "white-space",
+    "word-spacing",
+];
+
+/// Whether property `name` inherits. Custom properties, such as
+/// `--brand-color`, always do.
+pub fn is_inherited(name: &str) -> bool {
+    style::is_custom_property(name) || INHERITED_PROPERTIES.contains(&name)
+}
+
+/// What a node inherits from its parent's `parent_values`, before its own
+/// declarations apply. The root inherits nothing.
+pub fn inherited_values(parent: Option<&PropertyMap>) -> PropertyMap {
+    parent
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| is_inherited(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{self, Value};
+
+    #[test]
+    fn test_inherited_values() {
+        let parent: PropertyMap = [
+            ("color", Value::Keyword("red".to_string())),
+            ("margin", Value::Length(4.0, css::Unit::Px)),
+            ("--gap", Value::Unparsed(Vec::new())),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+        let mut names: Vec<_> = inherited_values(Some(&parent)).into_keys().collect();
+        names.sort();
+        assert_eq!(names, ["--gap", "color"]);
+        assert!(inherited_values(None).is_empty());
+    }
+
+    #[test]
+    fn test_style_tree_inherits() {
+        let tree = crate::html::parse("<div><p>text</p><span>more</span></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "div { color: #ff0000; margin: 4px; white-space: pre; } p { display: block; }\
+             span { color: #0000ff; }"
+                .to_string(),
+        );
+        let styled = style::style_tree(root, &style_sheet);
+        let red = Some(Value::Color(css::Color {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        }));
+        let p = &styled.children[0];
+        assert_eq!(p.value("color"), red);
+        assert_eq!(p.value("margin"), None);
+        // Through an element that doesn't set it, down to its text.
+        assert_eq!(p.children[0].value("color"), red);
+        assert_eq!(
+            p.children[0].value("white-space"),
+            Some(Value::Keyword("pre".to_string()))
+        );
+        // A declared value wins over the inherited one, for the element
+        // and what it holds.
+        let span = &styled.children[1];
+        assert_ne!(span.value("color"), red);
+        assert_eq!(span.children[0].value("color"), span.value("color"));
+    }
+}
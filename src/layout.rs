@@ -1,6 +1,9 @@
+use std::ops::Range;
+
 use crate::{
-    css::{Unit, Value},
-    style::{self, Display},
+    css::{self, Unit, Value},
+    style::{self, Display, DisplayInside, DisplayInternal, DisplayOutside, WhiteSpace},
+    text::{self, FontMetrics},
 };
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -35,6 +38,18 @@ pub struct Rect {
 }
 
 impl Rect {
+    /// The smallest rectangle covering both `self` and `other`.
+    fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
     fn expanded_by(self, edge: EdgeSizes) -> Rect {
         Rect {
             x: self.x - edge.left,
@@ -53,11 +68,194 @@ pub struct EdgeSizes {
     pub left: f32,
 }
 
+/// What a box hands down to its children for resolving relative lengths
+/// and measuring text.
+#[derive(Clone, Copy)]
+struct LayoutContext<'m> {
+    metrics: &'m dyn FontMetrics,
+    root_font_size: f32,
+    /// The containing block's height, if it is known before its contents
+    /// are laid out. Percentage heights only resolve against a known height.
+    definite_height: Option<f32>,
+}
+
 #[derive(Debug)]
 pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
     pub box_type: BoxType<'a>,
     pub children: Vec<LayoutBox<'a>>,
+    /// The lines the inline content of the box is laid out in, if it holds
+    /// any.
+    pub lines: Vec<LineBox>,
+    /// For a box laid out in lines, its margin box on each line it is on.
+    /// An inline box broken across lines has one per line.
+    pub fragments: Vec<Rect>,
+    /// For a text box, its text on each line it is on, with white space
+    /// collapsed as `white-space` says.
+    pub text: Vec<String>,
+}
+
+/// A line of inline content.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LineBox {
+    /// Where the line is, as wide as what is on it and as tall as its
+    /// tallest item.
+    pub rect: Rect,
+    /// The distance from the top of the line to the baseline its items are
+    /// aligned on.
+    pub baseline: f32,
+}
+
+/// The used `line-height` of a box with lengths resolved by `lengths`.
+/// `normal` is 1.2 times the font size, and percentages and `em` are of
+/// the box's own font size, so an inherited `1.5em` acts as a multiplier.
+fn line_height(style: &style::StyledNode, lengths: &css::LengthContext) -> f32 {
+    let normal = lengths.font_size * 1.2;
+    let lengths = css::LengthContext {
+        percent_base: Some(lengths.font_size),
+        ..*lengths
+    };
+    match style.value("line-height") {
+        Some(Value::Keyword(_)) | None => normal,
+        Some(value) => value.resolve(&lengths).unwrap_or(normal),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InlineItemKind {
+    /// The left or right margin, border and padding of an inline box.
+    Edge,
+    /// A word of text.
+    Text,
+    /// A preserved newline, which ends the line it is on.
+    LineBreak,
+    /// An inline-block or other box placed on a line whole.
+    Atomic,
+}
+
+/// A piece of inline content that is placed on a line as a unit.
+#[derive(Debug)]
+struct InlineItem {
+    kind: InlineItemKind,
+    /// The child indices leading to the box the item belongs to, from the
+    /// box holding the lines.
+    path: Vec<usize>,
+    /// The text of a text item.
+    text: String,
+    width: f32,
+    /// The width of preserved white space after the item, which may hang
+    /// over the end of the line rather than wrap.
+    hang: f32,
+    /// The height above the baseline.
+    ascent: f32,
+    /// The height below the baseline.
+    descent: f32,
+    /// The width of the space before the item. A space starting a line is
+    /// dropped.
+    space_before: Option<f32>,
+    /// Whether the line may break before the item.
+    break_before: bool,
+}
+
+/// What inline items are collected for: laying them out, or finding how
+/// narrow or wide their lines can be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sizing {
+    Layout,
+    MinContent,
+    MaxContent,
+}
+
+/// Whether the space before the `i`th item of a line is kept. A space with
+/// only the edges of inline boxes before it on the line is dropped.
+fn is_spaced(line: &[InlineItem], i: usize) -> bool {
+    line[i].space_before.is_some()
+        && line[..i]
+            .iter()
+            .any(|item| item.kind != InlineItemKind::Edge)
+}
+
+/// How wide a line of `items` is, without the white space hanging at its
+/// end.
+fn line_width(line: &[InlineItem]) -> f32 {
+    let width: f32 = (0..line.len())
+        .map(|i| {
+            let space = match is_spaced(line, i) {
+                true => line[i].space_before.unwrap_or(0.0),
+                false => 0.0,
+            };
+            space + line[i].width + line[i].hang
+        })
+        .sum();
+    let hang = line
+        .iter()
+        .rfind(|item| item.kind != InlineItemKind::LineBreak)
+        .map_or(0.0, |item| item.hang);
+    width - hang
+}
+
+/// Whether `items` end in a collapsible space, perhaps followed by the
+/// edges of inline boxes, so that a space after them collapses into it.
+fn ends_with_space(items: &[InlineItem]) -> bool {
+    items
+        .iter()
+        .rev()
+        .take_while(|item| item.kind == InlineItemKind::Edge)
+        .any(|item| item.space_before.is_some())
+}
+
+/// Split `items` into lines no wider than `width`, breaking only where the
+/// items allow it and around atomic items, and always after a line break.
+/// Whatever fits on no line gets one to itself.
+fn break_lines(items: &[InlineItem], width: f32) -> Vec<Range<usize>> {
+    // Where the line may break, and whether it has to.
+    let mut breaks = Vec::new();
+    let mut after_atomic = false;
+    for (i, item) in items.iter().enumerate() {
+        let atomic = item.kind == InlineItemKind::Atomic;
+        let text = item.kind == InlineItemKind::Text;
+        if i > 0 && items[i - 1].kind == InlineItemKind::LineBreak {
+            breaks.push((i, true));
+        } else if i > 0 && (item.break_before || after_atomic && text) {
+            breaks.push((i, false));
+        }
+        if atomic || text {
+            after_atomic = atomic;
+        }
+    }
+    breaks.push((items.len(), false));
+
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut line_width = 0.0;
+    let mut start = 0;
+    for (end, forced) in breaks {
+        let segment = &items[start..end];
+        let width_with_hang: f32 = segment.iter().map(|item| item.width + item.hang).sum();
+        let hang = segment
+            .iter()
+            .rfind(|item| item.kind != InlineItemKind::LineBreak)
+            .map_or(0.0, |item| item.hang);
+        let space = items[start].space_before.unwrap_or(0.0);
+        if start == line_start {
+            line_width = width_with_hang;
+        } else if line_width + space + width_with_hang - hang > width {
+            lines.push(line_start..start);
+            line_start = start;
+            line_width = width_with_hang;
+        } else {
+            line_width += space + width_with_hang;
+        }
+        if forced {
+            lines.push(line_start..end);
+            line_start = end;
+        }
+        start = end;
+    }
+    if line_start < items.len() {
+        lines.push(line_start..items.len());
+    }
+    lines
 }
 
 impl<'a> LayoutBox<'a> {
@@ -66,12 +264,17 @@ impl<'a> LayoutBox<'a> {
             dimensions: Default::default(),
             box_type,
             children: Vec::new(),
+            lines: Vec::new(),
+            fragments: Vec::new(),
+            text: Vec::new(),
         }
     }
 
     fn get_style_node(&mut self) -> &'a style::StyledNode<'a> {
         match self.box_type {
-            BoxType::BlockNode(node) | BoxType::InlineNode(node) => node,
+            BoxType::BlockNode(node)
+            | BoxType::InlineNode(node)
+            | BoxType::InlineBlockNode(node) => node,
             BoxType::AnonymousBlock => panic!("Anonymous block box has no style node"),
         }
     }
@@ -79,7 +282,7 @@ impl<'a> LayoutBox<'a> {
     fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
         match self.box_type {
             BoxType::InlineNode(_) | BoxType::AnonymousBlock => self,
-            BoxType::BlockNode(_) => {
+            BoxType::BlockNode(_) | BoxType::InlineBlockNode(_) => {
                 match self.children.last() {
                     Some(&LayoutBox {
                         box_type: BoxType::AnonymousBlock,
@@ -92,47 +295,126 @@ impl<'a> LayoutBox<'a> {
         }
     }
 
-    fn layout(&mut self, containing_block: Dimensions) {
+    fn layout(&mut self, containing_block: Dimensions, context: LayoutContext) {
         match self.box_type {
-            BoxType::BlockNode(_) => self.layout_block(&containing_block),
-            BoxType::InlineNode(_) => self.layout_inline(&containing_block),
-            BoxType::AnonymousBlock => self.layout_anonymous_block(&containing_block),
+            BoxType::BlockNode(_) => self.layout_block(&containing_block, context),
+            BoxType::InlineNode(_) => self.layout_inline(&containing_block, context),
+            BoxType::InlineBlockNode(_) => self.layout_inline_block(&containing_block, context),
+            BoxType::AnonymousBlock => self.layout_anonymous_block(&containing_block, context),
         }
     }
 
-    fn layout_block(&mut self, containing_block: &Dimensions) {
+    fn layout_block(&mut self, containing_block: &Dimensions, context: LayoutContext) {
         println!("its block");
-        self.calculate_block_width(containing_block);
+        self.calculate_block_width(containing_block, &context);
 
-        self.calculate_position_by_styles();
+        self.calculate_position_by_styles(containing_block, &context);
         self.calculate_block_position(containing_block);
 
-        self.layout_block_children();
+        let children_context = self.children_context(&context);
+        self.layout_block_children(children_context);
 
-        self.calculate_height();
+        self.calculate_height(&context);
     }
 
-    fn layout_anonymous_block(&mut self, containing_block: &Dimensions) {
+    fn layout_anonymous_block(&mut self, containing_block: &Dimensions, context: LayoutContext) {
         println!("its anonymous");
 
         self.calculate_anonymous_position(containing_block);
+        self.dimensions.content.width = containing_block.content.width;
 
-        self.layout_inline_children(containing_block);
+        self.layout_lines(context);
     }
 
-    fn layout_inline(&mut self, containing_block: &Dimensions) {
+    /// Lay out an inline box that isn't on a line of its parent's, such as
+    /// an inline root element. Its contents are laid out in lines of its
+    /// own.
+    fn layout_inline(&mut self, containing_block: &Dimensions, context: LayoutContext) {
         println!("its inline");
-        self.calculate_position_by_styles();
+        self.calculate_position_by_styles(containing_block, &context);
+        self.calculate_inline_width(containing_block, &context);
+        self.calculate_block_position(containing_block);
+
+        let d = &mut self.dimensions;
+        let edges = d.margin_box().width - d.content.width;
+        d.content.width = (containing_block.content.width - edges).max(0.0);
+
+        self.layout_lines(context);
+    }
+
+    fn layout_inline_block(&mut self, containing_block: &Dimensions, context: LayoutContext) {
+        self.calculate_position_by_styles(containing_block, &context);
+        self.calculate_inline_width(containing_block, &context);
         self.calculate_inline_position(containing_block);
 
-        self.calculate_inline_width();
+        // An `auto` width shrinks to fit the contents, but no narrower than
+        // their min-content width.
+        let percent_base = Some(containing_block.content.width);
+        if self.specified_width(percent_base, &context).is_none() {
+            let d = self.dimensions;
+            let available =
+                containing_block.content.width - (d.margin_box().width - d.content.width);
+            let (min, max) = self.content_widths(context);
+            self.dimensions.content.width = available.max(min).min(max);
+        }
 
-        self.calculate_height();
+        let children_context = self.children_context(&context);
+        self.layout_block_children(children_context);
 
-        self.layout_inline_children(containing_block);
+        self.calculate_height(&context);
     }
 
-    fn calculate_block_width(&mut self, containing_block: &Dimensions) {
+    /// The context for resolving this box's lengths, with percentages
+    /// taken of `percent_base`.
+    fn length_context(
+        &mut self,
+        percent_base: Option<f32>,
+        context: &LayoutContext,
+    ) -> css::LengthContext {
+        let font_size = self
+            .get_style_node()
+            .value("font-size")
+            .and_then(|size| size.resolve(&css::LengthContext::default()))
+            .unwrap_or(css::DEFAULT_FONT_SIZE);
+        css::LengthContext {
+            percent_base,
+            font_size,
+            root_font_size: context.root_font_size,
+        }
+    }
+
+    /// The used `width`, unless it is `auto` or a percentage of an unknown
+    /// `percent_base`.
+    fn specified_width(
+        &mut self,
+        percent_base: Option<f32>,
+        context: &LayoutContext,
+    ) -> Option<f32> {
+        let lengths = self.length_context(percent_base, context);
+        self.get_style_node()
+            .value("width")
+            .and_then(|width| width.resolve(&lengths))
+    }
+
+    /// The used `height`, if it can be known before laying out the contents.
+    fn specified_height(&mut self, context: &LayoutContext) -> Option<f32> {
+        let lengths = self.length_context(context.definite_height, context);
+        self.get_style_node()
+            .value("height")
+            .and_then(|height| height.resolve(&lengths))
+    }
+
+    fn children_context<'m>(&mut self, context: &LayoutContext<'m>) -> LayoutContext<'m> {
+        LayoutContext {
+            definite_height: self.specified_height(context),
+            ..*context
+        }
+    }
+
+    fn calculate_block_width(&mut self, containing_block: &Dimensions, context: &LayoutContext) {
+        // Horizontal percentages, even of margins, refer to the containing block's width.
+        let lengths = self.length_context(Some(containing_block.content.width), context);
+        let px = |value: &Value| value.resolve(&lengths).unwrap_or(0.0);
         let style = self.get_style_node();
 
         let auto = Value::Keyword("auto".to_string());
@@ -159,7 +441,7 @@ impl<'a> LayoutBox<'a> {
             &width,
         ]
         .iter()
-        .map(|v| v.to_px())
+        .map(|v| px(v))
         .sum();
 
         if width != auto && total > containing_block.content.width {
@@ -175,7 +457,7 @@ impl<'a> LayoutBox<'a> {
 
         match (width == auto, margin_left == auto, margin_right == auto) {
             (false, false, false) => {
-                margin_right = Value::Length(margin_right.to_px() + underflow, Unit::Px);
+                margin_right = Value::Length(px(&margin_right) + underflow, Unit::Px);
             }
 
             (false, false, true) => {
@@ -197,7 +479,7 @@ impl<'a> LayoutBox<'a> {
                     width = Value::Length(underflow, Unit::Px);
                 } else {
                     width = Value::Length(0.0, Unit::Px);
-                    margin_right = Value::Length(margin_right.to_px() + underflow, Unit::Px);
+                    margin_right = Value::Length(px(&margin_right) + underflow, Unit::Px);
                 }
             }
 
@@ -209,19 +491,21 @@ impl<'a> LayoutBox<'a> {
         }
 
         let d = &mut self.dimensions;
-        d.content.width = width.to_px();
+        d.content.width = px(&width);
 
-        d.padding.left = padding_left.to_px();
-        d.padding.right = padding_right.to_px();
+        d.padding.left = px(&padding_left);
+        d.padding.right = px(&padding_right);
 
-        d.border.left = border_left.to_px();
-        d.border.right = border_right.to_px();
+        d.border.left = px(&border_left);
+        d.border.right = px(&border_right);
 
-        d.margin.left = margin_left.to_px();
-        d.margin.right = margin_right.to_px();
+        d.margin.left = px(&margin_left);
+        d.margin.right = px(&margin_right);
     }
 
-    fn calculate_inline_width(&mut self) {
+    fn calculate_inline_width(&mut self, containing_block: &Dimensions, context: &LayoutContext) {
+        let lengths = self.length_context(Some(containing_block.content.width), context);
+        let px = |value: &Value| value.resolve(&lengths).unwrap_or(0.0);
         let style = self.get_style_node();
         let zero = Value::Length(0.0, Unit::Px);
         let width = style.value("width").unwrap_or(zero);
@@ -236,36 +520,38 @@ impl<'a> LayoutBox<'a> {
         let padding_right = style.lookup("padding-right", "padding", &zero);
 
         let d = &mut self.dimensions;
-        d.content.width = width.to_px();
+        d.content.width = px(&width);
 
-        d.padding.left = padding_left.to_px();
-        d.padding.right = padding_right.to_px();
+        d.padding.left = px(&padding_left);
+        d.padding.right = px(&padding_right);
 
-        d.border.left = border_left.to_px();
-        d.border.right = border_right.to_px();
+        d.border.left = px(&border_left);
+        d.border.right = px(&border_right);
 
-        d.margin.left = margin_left.to_px();
-        d.margin.right = margin_right.to_px();
+        d.margin.left = px(&margin_left);
+        d.margin.right = px(&margin_right);
     }
 
-    fn calculate_position_by_styles(&mut self) {
+    fn calculate_position_by_styles(
+        &mut self,
+        containing_block: &Dimensions,
+        context: &LayoutContext,
+    ) {
+        let lengths = self.length_context(Some(containing_block.content.width), context);
+        let px = |value: Value| value.resolve(&lengths).unwrap_or(0.0);
         let style = self.get_style_node();
         let d = &mut self.dimensions;
         let zero = Value::Length(0.0, Unit::Px);
 
         // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px();
+        d.margin.top = px(style.lookup("margin-top", "margin", &zero));
+        d.margin.bottom = px(style.lookup("margin-bottom", "margin", &zero));
 
-        d.border.top = style
-            .lookup("border-top-width", "border-width", &zero)
-            .to_px();
-        d.border.bottom = style
-            .lookup("border-bottom-width", "border-width", &zero)
-            .to_px();
+        d.border.top = px(style.lookup("border-top-width", "border-width", &zero));
+        d.border.bottom = px(style.lookup("border-bottom-width", "border-width", &zero));
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px();
+        d.padding.top = px(style.lookup("padding-top", "padding", &zero));
+        d.padding.bottom = px(style.lookup("padding-bottom", "padding", &zero));
     }
 
     fn calculate_block_position(&mut self, containing_block: &Dimensions) {
@@ -286,62 +572,456 @@ impl<'a> LayoutBox<'a> {
 
     fn calculate_inline_position(&mut self, containing_block: &Dimensions) {
         let d = &mut self.dimensions;
-        d.content.x = containing_block.content.x
-            + containing_block.content.width
-            + d.margin.left
-            + d.border.left
-            + d.padding.left;
+        d.content.x = containing_block.content.x + d.margin.left + d.border.left + d.padding.left;
         d.content.y = containing_block.content.y + d.margin.top + d.border.top + d.padding.top;
     }
 
-    fn layout_block_children(&mut self) {
+    fn layout_block_children(&mut self, context: LayoutContext) {
         let d = &mut self.dimensions;
         for child in &mut self.children {
-            child.layout(*d);
+            child.layout(*d, context);
             // Track the height so each child is laid out below the previous content.
             d.content.height += child.dimensions.margin_box().height;
         }
     }
 
-    fn layout_inline_children(&mut self, containing_block: &Dimensions) {
-        let d = &mut self.dimensions;
-        let mut is_first = true;
+    /// Lay out the children as an inline formatting context: their items
+    /// are placed left to right, wrapped at the content width, and the
+    /// lines stacked downwards. A line is as tall as its items reach above
+    /// and below the baseline they share.
+    fn layout_lines(&mut self, context: LayoutContext) {
+        let containing_block = self.dimensions;
+        let items = self.inline_items(&containing_block, context, Sizing::Layout);
+
+        let content = self.dimensions.content;
+        let mut y = content.y;
+        for line in break_lines(&items, content.width) {
+            let items = &items[line];
+            let ascent = items.iter().map(|item| item.ascent).fold(0.0, f32::max);
+            let descent = items.iter().map(|item| item.descent).fold(0.0, f32::max);
+            let mut x = content.x;
+            // The margin box of each box with items on the line, and the
+            // text of each text box.
+            let mut fragments: Vec<(&[usize], Rect)> = Vec::new();
+            let mut texts: Vec<(&[usize], String)> = Vec::new();
+            for (i, item) in items.iter().enumerate() {
+                let spaced = is_spaced(items, i);
+                if spaced {
+                    x += item.space_before.unwrap_or(0.0);
+                }
+                let rect = Rect {
+                    x,
+                    y: y + ascent - item.ascent,
+                    width: item.width + item.hang,
+                    height: item.ascent + item.descent,
+                };
+                if matches!(item.kind, InlineItemKind::Text | InlineItemKind::LineBreak) {
+                    let text = match texts.iter_mut().find(|(path, _)| *path == item.path) {
+                        Some((_, text)) => text,
+                        None => {
+                            texts.push((&item.path, String::new()));
+                            &mut texts.last_mut().unwrap().1
+                        }
+                    };
+                    if spaced {
+                        text.push(' ');
+                    }
+                    text.push_str(&item.text);
+                }
+                if item.kind == InlineItemKind::Atomic {
+                    let layout_box = self.descendant(&item.path);
+                    let margin_box = layout_box.dimensions.margin_box();
+                    layout_box.translate(rect.x - margin_box.x, rect.y - margin_box.y);
+                }
+                for depth in 1..=item.path.len() {
+                    let path = &item.path[..depth];
+                    match fragments.iter_mut().find(|(other, _)| *other == path) {
+                        Some((_, fragment)) => *fragment = fragment.union(rect),
+                        None => fragments.push((path, rect)),
+                    }
+                }
+                x += item.width + item.hang;
+            }
+            for (path, fragment) in fragments {
+                self.descendant(path).fragments.push(fragment);
+            }
+            for (path, text) in texts {
+                self.descendant(path).text.push(text);
+            }
+            self.lines.push(LineBox {
+                rect: Rect {
+                    x: content.x,
+                    y,
+                    width: x - content.x,
+                    height: ascent + descent,
+                },
+                baseline: ascent,
+            });
+            y += ascent + descent;
+        }
+        self.dimensions.content.height = y - content.y;
+
         for child in &mut self.children {
-            child.layout(*d);
-            if is_first {
-                d.content.height += child.dimensions.margin_box().height;
-                is_first = false;
+            child.fit_to_fragments();
+        }
+    }
+
+    /// The items of the inline content in the children.
+    fn inline_items(
+        &mut self,
+        containing_block: &Dimensions,
+        context: LayoutContext,
+        sizing: Sizing,
+    ) -> Vec<InlineItem> {
+        let mut items = Vec::new();
+        let mut pending_space = None;
+        for (i, child) in self.children.iter_mut().enumerate() {
+            let mut path = vec![i];
+            child.collect_inline_items(
+                containing_block,
+                context,
+                sizing,
+                &mut path,
+                &mut items,
+                &mut pending_space,
+            );
+        }
+        items
+    }
+
+    /// The min-content and max-content widths of the inline content in the
+    /// children: the widest thing the lines can't break inside, and the
+    /// widest line when they only break where they have to.
+    fn inline_content_widths(&mut self, context: LayoutContext) -> (f32, f32) {
+        let containing_block = Default::default();
+        let mut widest_line = |sizing, width| {
+            let items = self.inline_items(&containing_block, context, sizing);
+            break_lines(&items, width)
+                .into_iter()
+                .map(|line| line_width(&items[line]))
+                .fold(0.0, f32::max)
+        };
+        (
+            widest_line(Sizing::MinContent, 0.0),
+            widest_line(Sizing::MaxContent, f32::INFINITY),
+        )
+    }
+
+    /// The min-content and max-content widths of the content box.
+    fn content_widths(&mut self, context: LayoutContext) -> (f32, f32) {
+        match self.box_type {
+            BoxType::AnonymousBlock | BoxType::InlineNode(_) => self.inline_content_widths(context),
+            BoxType::BlockNode(_) | BoxType::InlineBlockNode(_) => self
+                .children
+                .iter_mut()
+                .map(|child| child.intrinsic_contribution(context))
+                .fold((0.0, 0.0), |(min, max), (child_min, child_max)| {
+                    (f32::max(min, child_min), f32::max(max, child_max))
+                }),
+        }
+    }
+
+    /// The min-content and max-content widths of the margin box, as it
+    /// counts towards the width of a box that shrinks to fit its contents.
+    /// Percentages count as zero, or as `auto` for `width`.
+    fn intrinsic_contribution(&mut self, context: LayoutContext) -> (f32, f32) {
+        if let BoxType::AnonymousBlock = self.box_type {
+            return self.content_widths(context);
+        }
+        self.calculate_inline_width(&Default::default(), &context);
+        let d = self.dimensions;
+        let edges = d.margin_box().width - d.content.width;
+        let (min, max) = match self.specified_width(None, &context) {
+            Some(width) => (width, width),
+            None => self.content_widths(context),
+        };
+        (min + edges, max + edges)
+    }
+
+    /// Add the items of this inline-level box to `items`. `path` leads to
+    /// it from the box holding the lines, and `pending_space` is the width
+    /// of a space that ended the text before, to go before the next item.
+    fn collect_inline_items(
+        &mut self,
+        containing_block: &Dimensions,
+        context: LayoutContext,
+        sizing: Sizing,
+        path: &mut Vec<usize>,
+        items: &mut Vec<InlineItem>,
+        pending_space: &mut Option<f32>,
+    ) {
+        match self.box_type {
+            BoxType::InlineNode(style_node) => {
+                if let Some(text) = style_node.text() {
+                    self.collect_text_items(&text, context, path, items, pending_space);
+                    return;
+                }
+
+                self.calculate_position_by_styles(containing_block, &context);
+                self.calculate_inline_width(containing_block, &context);
+                let d = self.dimensions;
+                let wraps = style_node.white_space().wraps();
+                let edge = |width: f32, space_before: Option<f32>| InlineItem {
+                    kind: InlineItemKind::Edge,
+                    path: path.clone(),
+                    text: String::new(),
+                    width,
+                    hang: 0.0,
+                    ascent: 0.0,
+                    descent: 0.0,
+                    space_before,
+                    break_before: wraps && space_before.is_some(),
+                };
+                items.push(edge(
+                    d.margin.left + d.border.left + d.padding.left,
+                    pending_space.take(),
+                ));
+                let end = edge(d.margin.right + d.border.right + d.padding.right, None);
+                for (i, child) in self.children.iter_mut().enumerate() {
+                    path.push(i);
+                    child.collect_inline_items(
+                        containing_block,
+                        context,
+                        sizing,
+                        path,
+                        items,
+                        pending_space,
+                    );
+                    path.pop();
+                }
+                items.push(end);
+            }
+            BoxType::BlockNode(_) | BoxType::InlineBlockNode(_) | BoxType::AnonymousBlock => {
+                let (width, ascent, descent) = match sizing {
+                    Sizing::Layout => {
+                        // Laid out at the origin, then moved to its place on
+                        // a line.
+                        let mut origin: Dimensions = Default::default();
+                        origin.content.width = containing_block.content.width;
+                        self.layout(origin, context);
+                        let margin_box = self.dimensions.margin_box();
+                        // Its baseline is that of its last line, or its
+                        // bottom margin edge if it has none.
+                        let ascent = self
+                            .last_baseline()
+                            .map_or(margin_box.height, |baseline| baseline - margin_box.y);
+                        (margin_box.width, ascent, margin_box.height - ascent)
+                    }
+                    Sizing::MinContent => (self.intrinsic_contribution(context).0, 0.0, 0.0),
+                    Sizing::MaxContent => (self.intrinsic_contribution(context).1, 0.0, 0.0),
+                };
+                items.push(InlineItem {
+                    kind: InlineItemKind::Atomic,
+                    path: path.clone(),
+                    text: String::new(),
+                    width,
+                    hang: 0.0,
+                    ascent,
+                    descent,
+                    space_before: pending_space.take(),
+                    break_before: true,
+                });
             }
-            let new_width = d.content.width + child.dimensions.margin_box().width;
-            if new_width > containing_block.content.width {
-                println!("over");
-                d.content.width = 0.0;
-                d.content.y += containing_block.content.y;
-                d.content.height += child.dimensions.margin_box().height;
+        }
+    }
+
+    /// Add the items of a text box: its words, and its newlines where
+    /// `white-space` keeps them. Collapsible white space becomes spaces
+    /// between the items; preserved white space is part of them.
+    fn collect_text_items(
+        &mut self,
+        text: &str,
+        context: LayoutContext,
+        path: &[usize],
+        items: &mut Vec<InlineItem>,
+        pending_space: &mut Option<f32>,
+    ) {
+        let style_node = self.get_style_node();
+        let white_space = style_node.white_space();
+        let metrics = context.metrics;
+        let lengths = self.length_context(None, &context);
+        let line_height = line_height(style_node, &lengths);
+        let font_size = lengths.font_size;
+        // Half the leading goes above the text and half below.
+        let (ascent, descent) = (metrics.ascent(font_size), metrics.descent(font_size));
+        let ascent = ascent + (line_height - ascent - descent) / 2.0;
+        let space = metrics.advance(" ", font_size);
+        let item = |kind, text: String, width, hang, space_before, break_before| InlineItem {
+            kind,
+            path: path.to_vec(),
+            text,
+            width,
+            hang,
+            ascent,
+            descent: line_height - ascent,
+            space_before,
+            break_before,
+        };
+
+        let text = match white_space.collapses_spaces() {
+            true => text::collapse_white_space(text, white_space.preserves_newlines()),
+            false => text.to_string(),
+        };
+        for (n, line) in text.split('\n').enumerate() {
+            if n > 0 {
+                *pending_space = None;
+                let line_break = item(
+                    InlineItemKind::LineBreak,
+                    String::new(),
+                    0.0,
+                    0.0,
+                    None,
+                    false,
+                );
+                items.push(line_break);
+            }
+
+            if white_space.collapses_spaces() {
+                let words = line.trim_start_matches(' ');
+                if words.len() < line.len() && !ends_with_space(items) {
+                    *pending_space = Some(space);
+                }
+                // Inside the text, the line may break wherever Unicode line
+                // breaking allows it; before the text, only at white space.
+                for (i, segment) in text::segments(words).into_iter().enumerate() {
+                    if !segment.word.is_empty() {
+                        let space_before = pending_space.take();
+                        let break_before = white_space.wraps() && (i > 0 || space_before.is_some());
+                        let width = metrics.advance(segment.word, font_size);
+                        let text = segment.word.to_string();
+                        items.push(item(
+                            InlineItemKind::Text,
+                            text,
+                            width,
+                            0.0,
+                            space_before,
+                            break_before,
+                        ));
+                    }
+                    if !segment.space.is_empty() {
+                        *pending_space = Some(space);
+                    }
+                }
             } else {
-                d.content.width = new_width;
+                let line = text::expand_tabs(line);
+                let segments = match white_space.wraps() {
+                    true => text::segments(&line),
+                    false => {
+                        let word = line.trim_end_matches(text::is_white_space);
+                        let space = &line[word.len()..];
+                        vec![text::Segment { word, space }]
+                    }
+                };
+                for (i, segment) in segments.into_iter().enumerate() {
+                    let text = format!("{}{}", segment.word, segment.space);
+                    if text.is_empty() {
+                        continue;
+                    }
+                    let space_before = pending_space.take();
+                    let break_before = white_space.wraps() && (i > 0 || space_before.is_some());
+                    // Preserved spaces hang at the end of a line, except
+                    // with `break-spaces`, where they wrap like the text.
+                    let (width, hang) = match white_space {
+                        WhiteSpace::BreakSpaces => (metrics.advance(&text, font_size), 0.0),
+                        _ => (
+                            metrics.advance(segment.word, font_size),
+                            metrics.advance(segment.space, font_size),
+                        ),
+                    };
+                    items.push(item(
+                        InlineItemKind::Text,
+                        text,
+                        width,
+                        hang,
+                        space_before,
+                        break_before,
+                    ));
+                }
             }
         }
     }
 
-    fn calculate_height(&mut self) {
-        if let Some(Value::Length(h, Unit::Px)) = self.get_style_node().value("height") {
-            self.dimensions.content.height = h;
+    /// The box `path` leads to from this one.
+    fn descendant(&mut self, path: &[usize]) -> &mut LayoutBox<'a> {
+        path.iter()
+            .fold(self, |layout_box, &i| &mut layout_box.children[i])
+    }
+
+    fn translate(&mut self, dx: f32, dy: f32) {
+        let move_rect = |rect: &mut Rect| {
+            rect.x += dx;
+            rect.y += dy;
+        };
+        move_rect(&mut self.dimensions.content);
+        self.lines
+            .iter_mut()
+            .for_each(|line| move_rect(&mut line.rect));
+        self.fragments.iter_mut().for_each(move_rect);
+        for child in &mut self.children {
+            child.translate(dx, dy);
+        }
+    }
+
+    /// Where the baseline of the last line in the box is, looking in its
+    /// children if it has no lines of its own.
+    fn last_baseline(&self) -> Option<f32> {
+        match self.lines.last() {
+            Some(line) => Some(line.rect.y + line.baseline),
+            None => self
+                .children
+                .iter()
+                .rev()
+                .find_map(LayoutBox::last_baseline),
+        }
+    }
+
+    /// Set the content box of an inline box laid out in lines to cover all
+    /// its fragments, inside its margins, borders and padding.
+    fn fit_to_fragments(&mut self) {
+        if !matches!(self.box_type, BoxType::InlineNode(_)) {
+            return;
+        }
+        if let Some(bounds) = self.fragments.iter().copied().reduce(Rect::union) {
+            let d = &mut self.dimensions;
+            let left = d.margin.left + d.border.left + d.padding.left;
+            let right = d.margin.right + d.border.right + d.padding.right;
+            d.content = Rect {
+                x: bounds.x + left,
+                width: (bounds.width - left - right).max(0.0),
+                ..bounds
+            };
+        }
+        for child in &mut self.children {
+            child.fit_to_fragments();
+        }
+    }
+
+    fn calculate_height(&mut self, context: &LayoutContext) {
+        // A percentage of an unknown height behaves as `auto`.
+        if let Some(height) = self.specified_height(context) {
+            self.dimensions.content.height = height;
         }
     }
 }
 
-/// Transform a style tree into a layout tree.
+/// Transform a style tree into a layout tree, measuring text with `metrics`.
 pub fn layout_tree<'a>(
     node: &'a style::StyledNode<'a>,
     mut containing_block: Dimensions,
+    metrics: &dyn FontMetrics,
 ) -> LayoutBox<'a> {
+    let context = LayoutContext {
+        metrics,
+        root_font_size: node
+            .value("font-size")
+            .map_or(css::DEFAULT_FONT_SIZE, |size| size.to_px()),
+        definite_height: Some(containing_block.content.height),
+    };
     // The layout algorithm expects the container height to start at 0.
-    // TODO: Save the initial containing block height, for calculating percent heights.
     containing_block.content.height = 0.0;
 
     let mut root_box = build_layout_tree(node);
-    root_box.layout(containing_block);
+    root_box.layout(containing_block, context);
     root_box
 }
 
@@ -349,36 +1029,91 @@ pub fn layout_tree<'a>(
 pub enum BoxType<'a> {
     BlockNode(&'a style::StyledNode<'a>),
     InlineNode(&'a style::StyledNode<'a>),
+    /// An atomic inline: placed like an inline box, laid out inside like a block.
+    InlineBlockNode(&'a style::StyledNode<'a>),
     AnonymousBlock,
 }
 
-pub fn build_layout_tree<'a>(style_node: &'a style::StyledNode<'a>) -> LayoutBox<'a> {
-    let mut root = LayoutBox::new(match style_node.display() {
-        Display::Block => BoxType::BlockNode(style_node),
-        Display::Inline => BoxType::InlineNode(style_node),
-        Display::None => panic!("Root node has display: none."),
-    });
+/// The box an element generates, after mapping display types without a
+/// dedicated layout mode onto the closest one that exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BoxKind {
+    Block,
+    Inline,
+    InlineBlock,
+    Contents,
+    None,
+}
 
-    for child in &style_node.children {
-        match child.display() {
-            Display::Block => root.children.push(build_layout_tree(child)),
-            Display::Inline => root
-                .get_inline_container()
-                .children
-                .push(build_layout_tree(child)),
-            Display::None => {}
+fn box_kind(display: Display) -> BoxKind {
+    match display {
+        Display::None => BoxKind::None,
+        Display::Contents => BoxKind::Contents,
+        Display::Box {
+            outside: DisplayOutside::Inline,
+            inside: DisplayInside::Flow | DisplayInside::Ruby,
+            ..
+        } => BoxKind::Inline,
+        // inline-block, inline-flex, inline-grid and inline-table.
+        Display::Box {
+            outside: DisplayOutside::Inline,
+            ..
+        } => BoxKind::InlineBlock,
+        // Flex, grid and table containers (and run-in boxes) fall back to
+        // ordinary block containers.
+        Display::Box { .. } => BoxKind::Block,
+        // Columns only carry styles for their cells; they render nothing.
+        Display::Internal(DisplayInternal::TableColumn | DisplayInternal::TableColumnGroup) => {
+            BoxKind::None
         }
+        Display::Internal(
+            DisplayInternal::RubyBase
+            | DisplayInternal::RubyText
+            | DisplayInternal::RubyBaseContainer
+            | DisplayInternal::RubyTextContainer,
+        ) => BoxKind::Inline,
+        // Rows, row groups, cells and captions stack like blocks.
+        Display::Internal(_) => BoxKind::Block,
     }
+}
 
+pub fn build_layout_tree<'a>(style_node: &'a style::StyledNode<'a>) -> LayoutBox<'a> {
+    let mut root = LayoutBox::new(match box_kind(style_node.display()) {
+        // `display: contents` on the root element computes to `block`.
+        BoxKind::Block | BoxKind::Contents => BoxType::BlockNode(style_node),
+        BoxKind::Inline => BoxType::InlineNode(style_node),
+        BoxKind::InlineBlock => BoxType::InlineBlockNode(style_node),
+        BoxKind::None => panic!("Root node has display: none."),
+    });
+    root.build_children(&style_node.children);
     root
 }
 
+impl<'a> LayoutBox<'a> {
+    fn build_children(&mut self, children: &'a [style::StyledNode<'a>]) {
+        for child in children {
+            match box_kind(child.display()) {
+                BoxKind::Block => self.children.push(build_layout_tree(child)),
+                BoxKind::Inline | BoxKind::InlineBlock => self
+                    .get_inline_container()
+                    .children
+                    .push(build_layout_tree(child)),
+                // The element's children take its place in the box tree.
+                BoxKind::Contents => self.build_children(&child.children),
+                BoxKind::None => {}
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{css, html};
 
     use super::*;
 
+    const METRICS: text::FixedAdvance = text::FixedAdvance { advance: 0.5 };
+
     #[test]
     fn test_build_layout_tree() {
         let css = "
@@ -397,9 +1132,363 @@ mod tests {
         let html = "
             <h1 id='1'>Test<p>para</p></h1>
         ";
-        let root = html::parse(html.to_string());
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
         let style_sheet = css::parse(css.to_string());
-        let style_node = style::style_tree(&root, &style_sheet);
+        let style_node = style::style_tree(root, &style_sheet);
         println!("{:?}", build_layout_tree(&style_node));
     }
+
+    fn box_types(layout_box: &LayoutBox) -> Vec<String> {
+        layout_box
+            .children
+            .iter()
+            .map(|child| match child.box_type {
+                BoxType::BlockNode(_) => "block".to_string(),
+                BoxType::InlineNode(_) => "inline".to_string(),
+                BoxType::InlineBlockNode(_) => "inline-block".to_string(),
+                BoxType::AnonymousBlock => format!("anonymous{:?}", box_types(child)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_layout_tree_display_values() {
+        let css = "
+        div { display: block; }
+        .flex { display: flex; }
+        .grid { display: inline grid; }
+        .cell { display: table-cell; }
+        .col { display: table-column; }
+        .contents { display: contents; }
+        .hidden { display: none; }
+        .chip { display: inline-block; }
+        ";
+        let html = "<div>\
+            <p class='flex'>a</p>\
+            <p class='cell'>b</p>\
+            <p class='col'>c</p>\
+            <p class='hidden'>d</p>\
+            <p class='contents'><div>e</div><span>f</span></p>\
+            <p class='grid'>g</p>\
+            <p class='chip'>h</p>\
+        </div>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = build_layout_tree(&style_node);
+        assert_eq!(
+            box_types(&layout_root),
+            vec![
+                "block",
+                "block",
+                "block",
+                "anonymous[\"inline\", \"inline-block\", \"inline-block\"]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_block_lays_out_children_as_blocks() {
+        let css =
+            ".chip { display: inline-block; width: 50px; } p { display: block; height: 10px; }";
+        let html = "<div><span class='chip'><p>a</p><p>b</p></span></div>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let mut viewport: Dimensions = Default::default();
+        viewport.content.width = 800.0;
+        let layout_root = layout_tree(&style_node, viewport, &METRICS);
+        let chip = &layout_root.children[0];
+        assert!(matches!(chip.box_type, BoxType::InlineBlockNode(_)));
+        assert_eq!(chip.dimensions.content.width, 50.0);
+        assert_eq!(chip.children[1].dimensions.content.y, 10.0);
+    }
+
+    #[test]
+    fn test_inline_block_shrinks_to_fit() {
+        let css = "
+        html { display: block; font-size: 10px; }
+        div { display: block; }
+        .wide { width: 100px; }
+        .narrow { width: 25px; }
+        .chip { display: inline-block; padding-left: 1px; }
+        .flex { display: inline-flex; }
+        ";
+        let html = "<html>\
+            <div class='wide'>a <span class='chip'>bb cc dd</span> e</div>\
+            <div class='narrow'><span class='chip'>bb cc dd</span></div>\
+            <div class='narrow'><span class='flex'>bbbbbbbb</span></div>\
+            </html>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+        let chip = |i: usize, j: usize| &layout_root.children[i].children[0].children[j];
+
+        // As wide as its text on one line.
+        assert_eq!(chip(0, 1).dimensions.content.width, 40.0);
+        assert_eq!(chip(0, 1).children[0].lines.len(), 1);
+        // As wide as there is room for, wrapping the text.
+        assert_eq!(chip(1, 0).dimensions.content.width, 24.0);
+        assert_eq!(chip(1, 0).children[0].lines.len(), 3);
+        // No narrower than its longest word.
+        assert_eq!(chip(2, 0).dimensions.content.width, 40.0);
+    }
+
+    #[test]
+    fn test_layout_resolves_math_and_relative_lengths() {
+        let css = "
+        html { display: block; font-size: 10px; height: 100%; }
+        div { display: block; }
+        .outer { width: calc(100% - 2em); height: 50%; padding: 1rem; }
+        .inner { width: clamp(100px, 50%, 200px); height: calc(50% + 1px); margin-left: 10%; }
+        .auto { height: 50%; }
+        ";
+        let html = "<html><div class='outer'><div class='inner'></div></div><div><div class='auto'></div></div></html>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let mut viewport: Dimensions = Default::default();
+        viewport.content.width = 800.0;
+        viewport.content.height = 600.0;
+        let layout_root = layout_tree(&style_node, viewport, &METRICS);
+
+        let outer = &layout_root.children[0];
+        assert_eq!(outer.dimensions.content.width, 780.0);
+        assert_eq!(outer.dimensions.padding.left, 10.0);
+        assert_eq!(outer.dimensions.content.height, 300.0);
+
+        let inner = &outer.children[0];
+        assert_eq!(inner.dimensions.content.width, 200.0);
+        assert_eq!(inner.dimensions.margin.left, 78.0);
+        assert_eq!(inner.dimensions.content.height, 151.0);
+
+        // The parent's height depends on its contents, so 50% acts as auto.
+        let auto = &layout_root.children[1].children[0];
+        assert_eq!(auto.dimensions.content.height, 0.0);
+    }
+
+    fn layout_html<'a>(style_node: &'a style::StyledNode<'a>) -> LayoutBox<'a> {
+        let mut viewport: Dimensions = Default::default();
+        viewport.content.width = 800.0;
+        layout_tree(style_node, viewport, &METRICS)
+    }
+
+    #[test]
+    fn test_inline_content_wraps_into_lines() {
+        let css = "div { display: block; width: 100px; font-size: 10px; } em { padding-left: 5px; padding-right: 5px; }";
+        let html = "<div>aaaaaaaa bbbbbbbb <em>cccc dddd eeeeeeeeeeeeeeee</em></div>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        let lines = &layout_root.children[0];
+        assert!(matches!(lines.box_type, BoxType::AnonymousBlock));
+        let rects: Vec<_> = lines
+            .lines
+            .iter()
+            .map(|line| (line.rect.x, line.rect.y, line.rect.width, line.rect.height))
+            .collect();
+        // Each character is 5px wide, and lines are 1.2 times the font size.
+        assert_eq!(
+            rects,
+            [
+                (0.0, 0.0, 85.0, 12.0),
+                (0.0, 12.0, 50.0, 12.0),
+                (0.0, 24.0, 85.0, 12.0)
+            ]
+        );
+        assert_eq!(layout_root.dimensions.content.height, 36.0);
+
+        // The `em` is broken across the last two lines.
+        let em = &lines.children[1];
+        let fragments: Vec<_> = em
+            .fragments
+            .iter()
+            .map(|rect| (rect.x, rect.width))
+            .collect();
+        assert_eq!(fragments, [(0.0, 50.0), (0.0, 85.0)]);
+        assert_eq!(em.dimensions.content.x, 5.0);
+        assert_eq!(em.dimensions.content.width, 75.0);
+    }
+
+    #[test]
+    fn test_line_height_and_baseline_come_from_the_tallest_item() {
+        let css = "
+        html { display: block; }
+        div { display: block; font-size: 10px; }
+        .em { line-height: 2em; }
+        .px { line-height: 20px; }
+        .chip { display: inline-block; width: 20px; height: 30px; }
+        .big { font-size: 20px; }
+        ";
+        let html = "<html>\
+            <div class='em'>a <span class='chip'></span> b</div>\
+            <div class='px'><span class='big'>c</span> d</div>\
+            </html>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        // The text is 20px high, 13px of it above the baseline, and the
+        // inline-block sits on the baseline.
+        let lines = &layout_root.children[0].children[0];
+        assert_eq!(lines.lines[0].baseline, 30.0);
+        assert_eq!(lines.lines[0].rect.height, 37.0);
+        assert_eq!(lines.children[0].fragments[0].y, 17.0);
+        let chip = &lines.children[1];
+        assert_eq!(chip.dimensions.content.x, 10.0);
+        assert_eq!(chip.dimensions.content.y, 0.0);
+
+        // The bigger text reaches higher above the baseline, and the smaller
+        // one lower below it.
+        let lines = &layout_root.children[1].children[0];
+        assert_eq!(lines.lines[0].rect.y, 37.0);
+        assert_eq!(lines.lines[0].baseline, 16.0);
+        assert_eq!(lines.lines[0].rect.height, 23.0);
+        assert_eq!(lines.children[1].fragments[0].y, 40.0);
+        assert_eq!(layout_root.dimensions.content.height, 60.0);
+    }
+
+    #[test]
+    fn test_inline_block_sits_on_its_last_baseline() {
+        let css = "
+        html { display: block; }
+        p { display: block; }
+        b { display: inline-block; }
+        ";
+        let html = "<html><p>a <b>b</b> c</p></html>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        // The text in the inline-block lines up with the text around it, so
+        // the line is no taller than either.
+        let lines = &layout_root.children[0].children[0];
+        let line = lines.lines[0];
+        assert!((line.rect.height - 19.2).abs() < 0.01);
+        let text_y = lines.children[0].fragments[0].y;
+        assert_eq!(text_y, line.rect.y);
+        let b = &lines.children[1];
+        assert_eq!(b.dimensions.margin_box().y, text_y);
+        assert_eq!(b.last_baseline(), Some(line.rect.y + line.baseline));
+    }
+
+    #[test]
+    fn test_text_breaks_at_line_break_opportunities() {
+        let css = "div { display: block; width: 40px; font-size: 10px; }";
+        let html = "<div>well-known 日本語</div>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+
+        let layout_root = layout_html(&style_node);
+        let widths: Vec<_> = layout_root.children[0]
+            .lines
+            .iter()
+            .map(|line| line.rect.width)
+            .collect();
+        assert_eq!(widths, [25.0, 40.0, 5.0]);
+
+        // In a terminal, each ideograph takes two 8px cells.
+        let mut viewport: Dimensions = Default::default();
+        viewport.content.width = 800.0;
+        let layout_root = layout_tree(&style_node, viewport, &text::TerminalMetrics::default());
+        let widths: Vec<_> = layout_root.children[0]
+            .lines
+            .iter()
+            .map(|line| line.rect.width)
+            .collect();
+        assert_eq!(widths, [40.0, 40.0, 32.0, 16.0]);
+    }
+
+    #[test]
+    fn test_white_space_collapses_across_inline_boxes() {
+        let css = "div { display: block; font-size: 10px; }";
+        let html = "<div>  a \n <em> b </em>\t c</div>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        let lines = &layout_root.children[0];
+        assert_eq!(lines.lines.len(), 1);
+        assert_eq!(lines.lines[0].rect.width, 25.0);
+        assert_eq!(lines.children[0].text, ["a"]);
+        assert_eq!(lines.children[1].children[0].text, ["b"]);
+        assert_eq!(lines.children[2].text, [" c"]);
+    }
+
+    #[test]
+    fn test_pre_keeps_white_space_and_newlines() {
+        let css = "pre { display: block; white-space: pre; width: 30px; font-size: 10px; }";
+        let html = "<pre>\n  a  b\n\n\tc</pre>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        let lines = &layout_root.children[0];
+        let widths: Vec<_> = lines.lines.iter().map(|line| line.rect.width).collect();
+        // The empty line is as tall as the others, and the last one
+        // overflows rather than wrap.
+        assert_eq!(widths, [30.0, 0.0, 45.0]);
+        assert_eq!(layout_root.dimensions.content.height, 36.0);
+        assert_eq!(lines.children[0].text, ["  a  b", "", "        c"]);
+    }
+
+    #[test]
+    fn test_white_space_values_wrap_differently() {
+        let css = "
+        body { display: block; }
+        p { display: block; width: 30px; font-size: 10px; }
+        .nowrap { white-space: nowrap; }
+        .pre-wrap { white-space: pre-wrap; }
+        .break-spaces { white-space: break-spaces; }
+        .pre-line { white-space: pre-line; }
+        ";
+        let html = "<body>\
+            <p>aa  bb cc</p>\
+            <p class='nowrap'>aa  bb cc</p>\
+            <p class='pre-wrap'>aa  bb cc</p>\
+            <p class='break-spaces'>aa  bb cc</p>\
+            <p class='pre-line'>aa \n  bb cc</p>\
+            </body>";
+        let tree = html::parse(html.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(css.to_string());
+        let style_node = style::style_tree(root, &style_sheet);
+        let layout_root = layout_html(&style_node);
+
+        let lines: Vec<_> = layout_root
+            .children
+            .iter()
+            .map(|p| p.children[0].children[0].text.clone())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                vec!["aa bb", "cc"],
+                vec!["aa bb cc"],
+                // Preserved spaces hang over the end of the line.
+                vec!["aa  bb ", "cc"],
+                vec!["aa  ", "bb cc"],
+                vec!["aa", "bb cc"],
+            ]
+        );
+    }
 }
@@ -1,27 +1,73 @@
+use cursive::views::{BoxedView, Panel};
+
+pub mod atom;
 pub mod css;
 pub mod dom;
+pub mod encoding;
 pub mod file;
 pub mod html;
+pub mod inherit;
 pub mod layout;
+pub mod minify;
 pub mod render;
+pub mod restyle;
+pub mod source;
 pub mod style;
+pub mod text;
 
 pub fn run(config: file::Config) {
+    let document = match file::load_document(&config.html_filename, config.default_encoding) {
+        Ok(document) => document,
+        Err(error) => return eprintln!("Couldn't read {}: {}", config.html_filename, error),
+    };
+    let stylesheet = match file::load_style_sheet(&config.css_filename) {
+        Ok(stylesheet) => stylesheet,
+        Err(error) => return eprintln!("Couldn't read {}: {}", config.css_filename, error),
+    };
+    let root_node = match document.document_element() {
+        Some(root_node) => root_node,
+        None => return eprintln!("The page has no root element"),
+    };
+
+    match config.command {
+        file::Command::Render => render(root_node, &stylesheet, document.title()),
+        file::Command::Explain { target, property } => {
+            let media = style::MediaEnvironment::screen(&viewport());
+            let explanation = root_node
+                .lineage(&target)
+                .and_then(|lineage| style::explain(&lineage, &stylesheet, &media, &property));
+            match explanation {
+                Some(explanation) => print!("{}", explanation),
+                None => eprintln!("No element matches {}", target),
+            }
+        }
+        file::Command::Minify => {
+            let minified = minify::minify(root_node, &stylesheet);
+            println!("{}", minified.to_css(css::Format::Minified));
+        }
+    }
+}
+
+fn viewport() -> layout::Dimensions {
     let mut viewport: layout::Dimensions = Default::default();
     viewport.content.width = 800.0;
     viewport.content.height = 600.0;
+    viewport
+}
+
+fn render(root_node: dom::NodeRef, stylesheet: &css::StyleSheet, title: Option<String>) {
+    let viewport = viewport();
+    let media = style::MediaEnvironment::screen(&viewport);
 
-    let html_source = file::read_source(config.html_filename);
-    let css_source = file::read_source(config.css_filename);
-    let root_node = html::parse(html_source);
-    let stylesheet = css::parse(css_source);
-    let style_root = style::style_tree(&root_node, &stylesheet);
+    let style_root =
+        style::style_tree_with(root_node, stylesheet, &media, style::Traversal::Parallel);
 
-    let layout_root = layout::layout_tree(&style_root, viewport);
+    let layout_root = layout::layout_tree(&style_root, viewport, &text::TerminalMetrics::default());
     let mut siv = cursive::default();
-    let container = Some(render::to_element_container(layout_root));
-    if let Some(c) = container {
-        siv.add_layer(c);
+    let container = render::to_element_container(layout_root);
+    match title {
+        Some(title) => siv.add_layer(Panel::new(BoxedView::new(container)).title(title)),
+        None => siv.add_layer(container),
     }
 
     siv.menubar().add_leaf("Quit", |s| s.quit());
@@ -0,0 +1,178 @@
+use crate::{css, dom, style};
+
+/// A smaller copy of `style_sheet` that styles `root` the same way.
+///
+/// Selectors matching no element of `root` are dropped, along with rules
+/// left without selectors or declarations. Declarations overridden within
+/// their rule are removed, neighbouring rules with the same selectors or
+/// the same declarations are merged, an earlier copy of an identical rule
+/// is removed, and zero lengths lose their unit.
+pub fn minify(root: dom::NodeRef, style_sheet: &css::StyleSheet) -> css::StyleSheet {
+    let mut rules: Vec<css::Rule> = Vec::new();
+    for rule in &style_sheet.rules {
+        let selectors: Vec<css::Selector> = rule
+            .selectors
+            .iter()
+            .filter(|selector| {
+                root.descendants()
+                    .any(|node| style::matches(node, selector))
+            })
+            .cloned()
+            .collect();
+        let declarations = winning_declarations(rule.declarations.iter().cloned());
+        if selectors.is_empty() || declarations.is_empty() {
+            continue;
+        }
+        push_merged(
+            &mut rules,
+            css::Rule {
+                selectors,
+                declarations: declarations.into_iter().map(shorten).collect(),
+                conditions: rule.conditions.clone(),
+                layer: rule.layer.clone(),
+                span: rule.span,
+            },
+        );
+    }
+
+    // A later identical rule always wins over an earlier one.
+    let mut i = 0;
+    while i < rules.len() {
+        if rules[i + 1..].contains(&rules[i]) {
+            rules.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    css::StyleSheet {
+        rules,
+        origin: style_sheet.origin,
+        layers: style_sheet.layers.clone(),
+        namespaces: style_sheet.namespaces.clone(),
+    }
+}
+
+/// Add `rule` after the last of `rules`, merging the two when they are in
+/// the same layer and conditions and share their selectors or declarations.
+/// Neighbours can merge without changing the cascade because no rule sits
+/// between them.
+fn push_merged(rules: &mut Vec<css::Rule>, rule: css::Rule) {
+    if let Some(last) = rules.last_mut() {
+        if last.layer == rule.layer && last.conditions == rule.conditions {
+            if last.selectors == rule.selectors {
+                let declarations = last.declarations.drain(..).chain(rule.declarations);
+                last.declarations = winning_declarations(declarations);
+                return;
+            }
+            if last.declarations == rule.declarations {
+                for selector in rule.selectors {
+                    if !last.selectors.contains(&selector) {
+                        last.selectors.push(selector);
+                    }
+                }
+                return;
+            }
+        }
+    }
+    rules.push(rule);
+}
+
+/// One declaration per property: the last important one if there is one,
+/// otherwise the last. They keep the order the winners were written in.
+fn winning_declarations(
+    declarations: impl IntoIterator<Item = css::Declaration>,
+) -> Vec<css::Declaration> {
+    let declarations: Vec<css::Declaration> = declarations.into_iter().collect();
+    let wins = |i: usize| {
+        let declaration = &declarations[i];
+        let same_name = |other: &&css::Declaration| other.name == declaration.name;
+        let beaten_later = declarations[i + 1..]
+            .iter()
+            .filter(same_name)
+            .any(|later| later.important || !declaration.important);
+        let beaten_by_important =
+            !declaration.important && declarations.iter().filter(same_name).any(|d| d.important);
+        !beaten_later && !beaten_by_important
+    };
+    let winners: Vec<usize> = (0..declarations.len()).filter(|&i| wins(i)).collect();
+    winners
+        .into_iter()
+        .map(|i| declarations[i].clone())
+        .collect()
+}
+
+fn shorten(mut declaration: css::Declaration) -> css::Declaration {
+    declaration.value = shorten_value(declaration.value);
+    declaration
+}
+
+/// Zero is zero in every length unit but percentages, and px is written
+/// without one.
+fn shorten_value(value: css::Value) -> css::Value {
+    match value {
+        css::Value::Length(length, unit) if length == 0.0 && unit != css::Unit::Percent => {
+            css::Value::Length(0.0, css::Unit::Px)
+        }
+        css::Value::List(values) => {
+            css::Value::List(values.into_iter().map(shorten_value).collect())
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    const HTML: &str = "<div id='main' class='box'><p class='lead'>text</p><p>more</p></div>";
+
+    #[test]
+    fn test_minify_removes_unused_selectors_and_merges_rules() {
+        let tree = html::parse(HTML.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "h1, p { margin: 0em; }
+            .missing { color: #ff0000; }
+            #main { padding: 1px; padding: 2px; color: #00ff00 !important; color: #0000ff; }
+            #main { width: 10px; }
+            .lead { display: block; }
+            .box { display: block; }
+            @media print { .box, .missing { margin: 0.5em; } }
+            span { margin: 0; }
+            p { margin: 0em; }"
+                .to_string(),
+        );
+        let minified = minify(root, &style_sheet);
+        assert_eq!(
+            minified.to_css(css::Format::Minified),
+            "#main{padding:2px;color:#0f0!important;width:10px}\
+             .lead,.box{display:block}\
+             @media print{.box{margin:.5em}}\
+             p{margin:0}"
+        );
+    }
+
+    #[test]
+    fn test_minify_keeps_the_cascade() {
+        let tree = html::parse(HTML.to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "p { margin: 1px; }
+            .lead { margin: 2px; }
+            p { margin: 1px; }
+            div, .unused { color: #ff0000; }
+            div { font-size: 2em; font-size: 20px !important; font-size: 3em; }
+            @layer base { p { padding: 1px !important; } }
+            p { padding: 2px !important; }"
+                .to_string(),
+        );
+        let minified = minify(root, &style_sheet);
+        assert!(minified.rules.len() < style_sheet.rules.len());
+        assert_eq!(
+            style::style_tree(root, &minified),
+            style::style_tree(root, &style_sheet)
+        );
+    }
+}
@@ -4,9 +4,8 @@ use cursive::{
 };
 
 use crate::{
-    dom::{Node, NodeType},
+    dom::NodeType,
     layout::{BoxType, LayoutBox},
-    style::StyledNode,
 };
 
 pub type ElementContainer = Box<dyn View>;
@@ -17,42 +16,31 @@ pub fn new_element_container() -> ElementContainer {
 
 pub fn to_element_container(layout: LayoutBox) -> ElementContainer {
     match layout.box_type {
-        BoxType::BlockNode(style_node) | BoxType::InlineNode(style_node) => match style_node {
-            StyledNode {
-                node:
-                    Node {
-                        node_type: NodeType::Element(ref element),
-                        ..
-                    },
-                ..
-            } => {
+        BoxType::BlockNode(style_node)
+        | BoxType::InlineNode(style_node)
+        | BoxType::InlineBlockNode(style_node) => match &style_node.node.node_type {
+            NodeType::Element(element) => {
                 let mut panel =
-                    Panel::new(LinearLayout::vertical()).title(element.tag_name.clone());
+                    Panel::new(LinearLayout::vertical()).title(element.tag_name.as_str());
                 for child in layout.children.into_iter() {
                     panel.with_view_mut(|v| v.add_child(to_element_container(child)));
                 }
 
                 panel.into_boxed_view()
             }
-            StyledNode {
-                node:
-                    Node {
-                        node_type: NodeType::Text(ref text),
-                        ..
-                    },
-                ..
-            } => {
-                let text_to_display = text.clone();
-                let text_to_display = text_to_display.replace("\n", "");
-                let text_to_display = text_to_display.trim();
-                if !text_to_display.is_empty() {
-                    TextView::new(text_to_display).into_boxed_view()
+            // Layout has already collapsed the white space and broken the
+            // text into lines.
+            NodeType::Text(_) => {
+                if !layout.text.is_empty() {
+                    TextView::new(layout.text.join("\n")).into_boxed_view()
                 } else {
                     (DummyView {}).into_boxed_view()
                 }
             }
             _ => new_element_container(),
         },
+        // Collapsed white space between blocks lays out to no lines.
+        BoxType::AnonymousBlock if layout.lines.is_empty() => new_element_container(),
         BoxType::AnonymousBlock => {
             let mut p = Panel::new(LinearLayout::horizontal());
             for child in layout.children.into_iter() {
@@ -0,0 +1,693 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    atom::Atom,
+    css, dom, inherit,
+    style::{self, MediaEnvironment, PropertyMap, StyledNode},
+};
+
+/// The ids, classes and attribute names that some compound selectors test.
+/// Changing anything outside these sets cannot change whether an element
+/// matches them.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SelectorFeatures {
+    pub ids: HashSet<Atom>,
+    pub classes: HashSet<Atom>,
+    pub attributes: HashSet<Atom>,
+}
+
+impl SelectorFeatures {
+    fn add(&mut self, simple: &css::SimpleSelector) {
+        self.ids.extend(simple.id.clone());
+        self.classes.extend(simple.class.iter().cloned());
+        self.attributes
+            .extend(simple.attributes.iter().map(|attr| attr.name.clone()));
+    }
+
+    /// Whether changing attribute `name` from `old` to `new` can change
+    /// whether an element matches.
+    pub fn affects(&self, name: &str, old: Option<&str>, new: Option<&str>) -> bool {
+        let lowercase = Atom::find(&name.to_ascii_lowercase());
+        if lowercase.is_some_and(|name| self.attributes.contains(&name)) {
+            return true;
+        }
+        match name {
+            "id" => [old, new]
+                .iter()
+                .flatten()
+                .filter_map(|id| Atom::find(id))
+                .any(|id| self.ids.contains(&id)),
+            "class" => {
+                let old: HashSet<&str> = old
+                    .into_iter()
+                    .flat_map(|c| c.split_ascii_whitespace())
+                    .collect();
+                let new: HashSet<&str> = new
+                    .into_iter()
+                    .flat_map(|c| c.split_ascii_whitespace())
+                    .collect();
+                old.symmetric_difference(&new)
+                    .filter_map(|class| Atom::find(class))
+                    .any(|class| self.classes.contains(&class))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What the selectors of a style sheet test, split by where the tested
+/// element stands relative to the element the selector matches.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct InvalidationSets {
+    /// Tested on the matched element itself.
+    pub subjects: SelectorFeatures,
+    /// Tested on an ancestor, left of a descendant or child combinator.
+    pub ancestors: SelectorFeatures,
+    /// Tested on an earlier sibling, left of a sibling combinator.
+    pub siblings: SelectorFeatures,
+    /// Whether any selector has a sibling combinator, so that inserting or
+    /// removing a node can restyle the siblings after it.
+    pub sibling_combinators: bool,
+}
+
+impl InvalidationSets {
+    pub fn new(style_sheet: &css::StyleSheet) -> InvalidationSets {
+        let mut sets = InvalidationSets::default();
+        for rule in &style_sheet.rules {
+            for selector in &rule.selectors {
+                sets.add_selector(selector);
+            }
+        }
+        sets
+    }
+
+    fn add_selector(&mut self, selector: &css::Selector) {
+        match selector {
+            css::Selector::Simple(simple) => self.subjects.add(simple),
+            css::Selector::Complex(first, rest) => {
+                let mut compound = first;
+                for (combinator, next) in rest {
+                    match combinator {
+                        css::Combinator::Descendant | css::Combinator::Child => {
+                            self.ancestors.add(compound)
+                        }
+                        css::Combinator::NextSibling | css::Combinator::SubsequentSibling => {
+                            self.siblings.add(compound);
+                            self.sibling_combinators = true;
+                        }
+                    }
+                    compound = next;
+                }
+                self.subjects.add(compound);
+            }
+        }
+    }
+
+    /// Whether changing attribute `name` from `old` to `new` can change the
+    /// style of the element carrying it or of any other element.
+    pub fn affects(&self, name: &str, old: Option<&str>, new: Option<&str>) -> bool {
+        [&self.subjects, &self.ancestors, &self.siblings]
+            .iter()
+            .any(|features| features.affects(name, old, new))
+    }
+}
+
+/// Styles for a DOM tree that can be brought up to date after DOM, style
+/// sheet or media changes without restyling the whole document.
+///
+/// Styles are kept by `NodeId` rather than in a copy of the tree's shape,
+/// so nodes inserted into the tree are styled on the next flush and removed
+/// ones are dropped, without rebuilding the cache. Changes to the DOM come
+/// from its mutation records, given to `apply_records`.
+pub struct StyleCache {
+    root: dom::NodeId,
+    nodes: HashMap<dom::NodeId, CachedNode>,
+    media: MediaEnvironment,
+    invalidation: InvalidationSets,
+    /// Selectors of changed rules; elements they match are restyled on flush.
+    pending_selectors: Vec<css::Selector>,
+}
+
+/// The styles of a node. A node without one, such as a node just inserted,
+/// is styled on the next flush along with everything below it.
+#[derive(Debug)]
+struct CachedNode {
+    values: PropertyMap,
+    /// The rules matching this node may have changed.
+    dirty: bool,
+    /// Some node below this one is dirty or has no styles yet.
+    dirty_descendants: bool,
+}
+
+impl StyleCache {
+    pub fn new(root: dom::NodeRef, style_sheet: &css::StyleSheet) -> StyleCache {
+        StyleCache::with_media(root, style_sheet, MediaEnvironment::default())
+    }
+
+    pub fn with_media(
+        root: dom::NodeRef,
+        style_sheet: &css::StyleSheet,
+        media: MediaEnvironment,
+    ) -> StyleCache {
+        let styled =
+            style::style_tree_with(root, style_sheet, &media, style::Traversal::Sequential);
+        let mut nodes = HashMap::new();
+        cache_styles(&mut nodes, styled);
+        StyleCache {
+            root: root.id(),
+            nodes,
+            media,
+            invalidation: InvalidationSets::new(style_sheet),
+            pending_selectors: Vec::new(),
+        }
+    }
+
+    /// Mark `node` for restyling.
+    pub fn mark_dirty(&mut self, node: dom::NodeRef) {
+        if let Some(cached) = self.nodes.get_mut(&node.id()) {
+            cached.dirty = true;
+        }
+        self.mark_ancestors(node);
+    }
+
+    /// Mark `node` and everything below it for restyling.
+    pub fn mark_subtree_dirty(&mut self, node: dom::NodeRef) {
+        for descendant in node.descendants() {
+            if let Some(cached) = self.nodes.get_mut(&descendant.id()) {
+                cached.dirty = true;
+                cached.dirty_descendants = descendant.first_child().is_some();
+            }
+        }
+        self.mark_ancestors(node);
+    }
+
+    /// Lead the next flush down from the root to `node`.
+    fn mark_ancestors(&mut self, node: dom::NodeRef) {
+        if node.id() == self.root {
+            return;
+        }
+        for ancestor in node.ancestors() {
+            if let Some(cached) = self.nodes.get_mut(&ancestor.id()) {
+                // The ones above are marked already.
+                if cached.dirty_descendants {
+                    break;
+                }
+                cached.dirty_descendants = true;
+            }
+            if ancestor.id() == self.root {
+                break;
+            }
+        }
+    }
+
+    /// Take in `records`, the mutations made to `tree` since the last call,
+    /// as `Tree::take_records` gives them. Only the nodes they can restyle
+    /// are marked. Returns whether any node needs restyling.
+    pub fn apply_records(&mut self, tree: &dom::Tree, records: &[dom::MutationRecord]) -> bool {
+        let mut restyle = false;
+        for record in records {
+            restyle |= match record {
+                dom::MutationRecord::ChildList {
+                    target,
+                    added,
+                    removed,
+                    next_sibling,
+                    ..
+                } => self.children_changed(tree.get(*target), added, removed, *next_sibling),
+                dom::MutationRecord::Attributes {
+                    target,
+                    name,
+                    old_value,
+                } => {
+                    let node = tree.get(*target);
+                    let new = node
+                        .element()
+                        .and_then(|elem| elem.attributes.get(name.as_str()))
+                        .map(String::as_str);
+                    self.attribute_changed(node, name, old_value.as_deref(), new)
+                }
+                // No selector tests text.
+                dom::MutationRecord::CharacterData { .. } => false,
+            };
+        }
+        restyle
+    }
+
+    /// Record that `added` were inserted into `parent` and `removed` taken
+    /// out of it, just before `next_sibling`.
+    fn children_changed(
+        &mut self,
+        parent: dom::NodeRef,
+        added: &[dom::NodeId],
+        removed: &[dom::NodeId],
+        next_sibling: Option<dom::NodeId>,
+    ) -> bool {
+        let tree = parent.tree();
+        for &id in removed.iter().chain(added) {
+            for node in tree.get(id).descendants() {
+                self.nodes.remove(&node.id());
+            }
+        }
+        for &id in added {
+            self.mark_ancestors(tree.get(id));
+        }
+        if !self.invalidation.sibling_combinators {
+            return !added.is_empty();
+        }
+
+        // Sibling combinators may now match the later siblings differently.
+        let next_sibling = next_sibling
+            .map(|id| tree.get(id))
+            .filter(|next| next.parent() == Some(parent));
+        let following: Vec<_> = match next_sibling {
+            Some(next) => std::iter::successors(Some(next), |node| node.next_sibling()).collect(),
+            None => parent.children().collect(),
+        };
+        for &node in &following {
+            self.mark_subtree_dirty(node);
+        }
+        !added.is_empty() || !following.is_empty()
+    }
+
+    /// Record that attribute `name` of `node` changed from `old` to `new`.
+    /// Returns whether any element needs restyling.
+    ///
+    /// Selectors with combinators test elements other than the one they
+    /// match, so a change can restyle the element's descendants, or its
+    /// later siblings and their descendants.
+    pub fn attribute_changed(
+        &mut self,
+        node: dom::NodeRef,
+        name: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> bool {
+        let sets = &self.invalidation;
+        if sets.siblings.affects(name, old, new) {
+            for sibling in std::iter::successors(Some(node), |node| node.next_sibling()) {
+                self.mark_subtree_dirty(sibling);
+            }
+        } else if sets.ancestors.affects(name, old, new) {
+            self.mark_subtree_dirty(node);
+        } else if sets.subjects.affects(name, old, new) {
+            self.mark_dirty(node);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Record that rules with the given selectors were added to, removed
+    /// from or edited in `style_sheet`, which is the sheet after the change.
+    /// For an edited rule, pass the selectors from before and after the edit.
+    pub fn rules_changed<'s>(
+        &mut self,
+        style_sheet: &css::StyleSheet,
+        selectors: impl IntoIterator<Item = &'s css::Selector>,
+    ) {
+        self.invalidation = InvalidationSets::new(style_sheet);
+        self.pending_selectors
+            .extend(selectors.into_iter().cloned());
+    }
+
+    /// Switch to a new media environment, such as after the viewport is
+    /// resized. Only elements matched by rules that start or stop applying
+    /// are restyled on the next flush.
+    pub fn media_changed(&mut self, style_sheet: &css::StyleSheet, media: MediaEnvironment) {
+        let toggled = style_sheet.rules.iter().filter(|rule| {
+            style::rule_applies(rule, &self.media) != style::rule_applies(rule, &media)
+        });
+        for rule in toggled {
+            self.pending_selectors
+                .extend(rule.selectors.iter().cloned());
+        }
+        self.media = media;
+    }
+
+    /// Restyle everything marked since the last flush, along with the
+    /// descendants of any node whose values changed. Returns the number of
+    /// nodes restyled.
+    pub fn flush(&mut self, root: dom::NodeRef, style_sheet: &css::StyleSheet) -> usize {
+        let pending = std::mem::take(&mut self.pending_selectors);
+        flush_node(
+            &mut self.nodes,
+            root,
+            style_sheet,
+            &self.media,
+            &pending,
+            None,
+            false,
+        )
+    }
+
+    /// The current styles as a style tree over `root`, which must be the
+    /// tree the cache was flushed against.
+    pub fn style_tree<'a>(&self, root: dom::NodeRef<'a>) -> StyledNode<'a> {
+        StyledNode {
+            node: root,
+            specified_values: self.nodes[&root.id()].values.clone(),
+            children: root
+                .children()
+                .map(|child| self.style_tree(child))
+                .collect(),
+        }
+    }
+}
+
+fn cache_styles(nodes: &mut HashMap<dom::NodeId, CachedNode>, styled: StyledNode) {
+    nodes.insert(
+        styled.node.id(),
+        CachedNode {
+            values: styled.specified_values,
+            dirty: false,
+            dirty_descendants: false,
+        },
+    );
+    for child in styled.children {
+        cache_styles(nodes, child);
+    }
+}
+
+fn flush_node(
+    nodes: &mut HashMap<dom::NodeId, CachedNode>,
+    node: dom::NodeRef,
+    style_sheet: &css::StyleSheet,
+    media: &MediaEnvironment,
+    pending: &[css::Selector],
+    parent_values: Option<&PropertyMap>,
+    parent_changed: bool,
+) -> usize {
+    let matches_pending = pending
+        .iter()
+        .any(|selector| style::matches(node, selector));
+
+    // Taken out while the children are flushed, which read its values.
+    let cached = nodes.remove(&node.id());
+    let mut restyled = 0;
+    // Children only need restyling when something they inherit changed.
+    let (mut cached, changed) = match cached {
+        Some(cached) if !cached.dirty && !parent_changed && !matches_pending => (cached, false),
+        cached => {
+            let values = style::node_values(node, style_sheet, media, parent_values);
+            let changed = cached
+                .as_ref()
+                .is_none_or(|cached| inherited_part(&values) != inherited_part(&cached.values));
+            let dirty_descendants = cached.is_some_and(|cached| cached.dirty_descendants);
+            restyled += 1;
+            let cached = CachedNode {
+                values,
+                dirty: false,
+                dirty_descendants,
+            };
+            (cached, changed)
+        }
+    };
+
+    if changed || cached.dirty_descendants || !pending.is_empty() {
+        for child in node.children() {
+            restyled += flush_node(
+                nodes,
+                child,
+                style_sheet,
+                media,
+                pending,
+                Some(&cached.values),
+                changed,
+            );
+        }
+    }
+
+    cached.dirty = false;
+    cached.dirty_descendants = false;
+    nodes.insert(node.id(), cached);
+    restyled
+}
+
+fn inherited_part(values: &PropertyMap) -> Vec<(&String, &css::Value)> {
+    let mut inherited: Vec<_> = values
+        .iter()
+        .filter(|(name, _)| inherit::is_inherited(name))
+        .collect();
+    inherited.sort_by_key(|&(name, _)| name);
+    inherited
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    const CSS: &str = "
+        div { display: block; }
+        .on { color: #ff0000; }
+        #main { margin: 4px; }
+        [data-state=open] { padding: 2px; }
+    ";
+
+    fn document() -> dom::Document {
+        let mut source = String::from("<div id='root'>");
+        for _ in 0..20 {
+            source.push_str("<div class='item'><p>text</p></div>");
+        }
+        source.push_str("</div>");
+        html::parse(source)
+    }
+
+    fn root(document: &dom::Document) -> dom::NodeRef<'_> {
+        document.document_element().unwrap()
+    }
+
+    fn node_at(document: &dom::Document, path: &[usize]) -> dom::NodeId {
+        path.iter()
+            .fold(root(document), |node, &index| {
+                node.children().nth(index).unwrap()
+            })
+            .id()
+    }
+
+    fn set_attribute(
+        document: &mut dom::Document,
+        cache: &mut StyleCache,
+        path: &[usize],
+        name: &str,
+        value: &str,
+    ) -> bool {
+        document
+            .tree
+            .set_attribute(node_at(document, path), name, value)
+            .unwrap();
+        apply_records(document, cache)
+    }
+
+    fn apply_records(document: &mut dom::Document, cache: &mut StyleCache) -> bool {
+        let records = document.tree.take_records();
+        cache.apply_records(&document.tree, &records)
+    }
+
+    fn item(class: &str) -> dom::NodeBuilder {
+        let mut attrs = dom::AttrMap::new();
+        attrs.insert("class", class.to_string());
+        let text = dom::text("new".to_string());
+        let p = dom::element("p", dom::AttrMap::new(), vec![text]);
+        dom::element("div", attrs, vec![p])
+    }
+
+    #[test]
+    fn test_invalidation_sets() {
+        let sets = InvalidationSets::new(&css::parse(CSS.to_string()));
+        assert!(sets.affects("class", Some("item"), Some("item on")));
+        assert!(!sets.affects("class", Some("item"), Some("item other")));
+        assert!(sets.affects("id", None, Some("main")));
+        assert!(!sets.affects("id", Some("a"), Some("b")));
+        assert!(sets.affects("data-state", None, Some("closed")));
+        assert!(!sets.affects("title", None, Some("hello")));
+    }
+
+    #[test]
+    fn test_class_change_matches_full_restyle() {
+        let style_sheet = css::parse(CSS.to_string());
+        let mut document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        assert!(set_attribute(
+            &mut document,
+            &mut cache,
+            &[3],
+            "class",
+            "item on"
+        ));
+        let restyled = cache.flush(root(&document), &style_sheet);
+
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        // The element, its paragraph and the paragraph's text inherit the color.
+        assert_eq!(restyled, 3);
+    }
+
+    #[test]
+    fn test_id_and_attribute_changes_match_full_restyle() {
+        let style_sheet = css::parse(CSS.to_string());
+        let mut document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        set_attribute(&mut document, &mut cache, &[1], "id", "main");
+        set_attribute(&mut document, &mut cache, &[7, 0], "data-state", "open");
+        assert!(!set_attribute(
+            &mut document,
+            &mut cache,
+            &[9],
+            "title",
+            "x"
+        ));
+        let restyled = cache.flush(root(&document), &style_sheet);
+
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        assert_eq!(restyled, 2);
+        assert_eq!(cache.flush(root(&document), &style_sheet), 0);
+    }
+
+    #[test]
+    fn test_rule_change_matches_full_restyle() {
+        let mut style_sheet = css::parse(CSS.to_string());
+        let mut document = document();
+        document
+            .tree
+            .set_attribute(node_at(&document, &[2]), "class", "on")
+            .unwrap();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        style_sheet.rules[1].declarations[0].value = css::Value::Keyword("blue".to_string());
+        let changed = style_sheet.rules[1].selectors.clone();
+        cache.rules_changed(&style_sheet, &changed);
+        let restyled = cache.flush(root(&document), &style_sheet);
+
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        assert_eq!(restyled, 3);
+    }
+
+    #[test]
+    fn test_combinator_changes_match_full_restyle() {
+        let style_sheet = css::parse(
+            ".open p { margin: 1px; } .first + div { padding: 1px; } #main { margin: 4px; }"
+                .to_string(),
+        );
+        let mut document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        assert!(set_attribute(
+            &mut document,
+            &mut cache,
+            &[4],
+            "class",
+            "item open"
+        ));
+        let restyled = cache.flush(root(&document), &style_sheet);
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        // The element, its paragraph and the paragraph's text.
+        assert_eq!(restyled, 3);
+
+        set_attribute(&mut document, &mut cache, &[6], "class", "item first");
+        let restyled = cache.flush(root(&document), &style_sheet);
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        // The element and the siblings after it, which may now match, with
+        // what is below them.
+        assert_eq!(restyled, 42);
+    }
+
+    #[test]
+    fn test_child_list_changes_match_full_restyle() {
+        let style_sheet = css::parse(CSS.to_string());
+        let mut document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        let new = document.tree.create(item("item on"));
+        let parent = root(&document).id();
+        let reference = node_at(&document, &[5]);
+        document
+            .tree
+            .insert_before(parent, new, Some(reference))
+            .unwrap();
+        assert!(apply_records(&mut document, &mut cache));
+        let restyled = cache.flush(root(&document), &style_sheet);
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        // Only the new element, its paragraph and the paragraph's text.
+        assert_eq!(restyled, 3);
+
+        document.tree.remove(node_at(&document, &[0])).unwrap();
+        assert!(!apply_records(&mut document, &mut cache));
+        assert_eq!(cache.flush(root(&document), &style_sheet), 0);
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+    }
+
+    #[test]
+    fn test_child_list_changes_restyle_later_siblings() {
+        let style_sheet = css::parse(".item + .item { margin: 1px; }".to_string());
+        let mut document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        let new = document.tree.create(item("other"));
+        let parent = root(&document).id();
+        let reference = node_at(&document, &[18]);
+        document
+            .tree
+            .insert_before(parent, new, Some(reference))
+            .unwrap();
+        assert!(apply_records(&mut document, &mut cache));
+        let restyled = cache.flush(root(&document), &style_sheet);
+        assert_eq!(
+            cache.style_tree(root(&document)),
+            style::style_tree(root(&document), &style_sheet)
+        );
+        // The new element and the two after it, with what is below them.
+        assert_eq!(restyled, 9);
+    }
+
+    #[test]
+    fn test_media_change_matches_full_restyle() {
+        let style_sheet = css::parse(
+            "p { margin: 1px; } @media (max-width: 500px) { #root { color: #ff0000; } }"
+                .to_string(),
+        );
+        let document = document();
+        let mut cache = StyleCache::new(root(&document), &style_sheet);
+
+        let narrow = MediaEnvironment {
+            width: 400.0,
+            ..Default::default()
+        };
+        cache.media_changed(&style_sheet, narrow.clone());
+        let restyled = cache.flush(root(&document), &style_sheet);
+
+        let expected = style::style_tree_with(
+            root(&document),
+            &style_sheet,
+            &narrow,
+            style::Traversal::Sequential,
+        );
+        assert_eq!(cache.style_tree(root(&document)), expected);
+        // The color reaches every node of the document.
+        assert_eq!(restyled, 61);
+
+        cache.media_changed(&style_sheet, narrow);
+        assert_eq!(cache.flush(root(&document), &style_sheet), 0);
+    }
+}
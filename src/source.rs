@@ -0,0 +1,96 @@
+use std::{fmt, ops::Range};
+
+/// A byte offset in a source file, with the 1-based line and column it is
+/// at.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SourceLocation {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for SourceLocation {
+    /// The start of a source.
+    fn default() -> SourceLocation {
+        SourceLocation {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl SourceLocation {
+    /// The location just after `text`, when it is read from here.
+    pub fn advance(self, text: &str) -> SourceLocation {
+        let offset = self.offset + text.len();
+        match text.rfind('\n') {
+            Some(last_newline) => SourceLocation {
+                offset,
+                line: self.line + text.matches('\n').count(),
+                column: text[last_newline + 1..].chars().count() + 1,
+            },
+            None => SourceLocation {
+                offset,
+                line: self.line,
+                column: self.column + text.chars().count(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The part of a source file something was parsed from, from `start` up to
+/// but not including `end`.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: SourceLocation,
+    pub end: SourceLocation,
+}
+
+impl Span {
+    /// The byte range of the span, to slice the source with.
+    pub fn range(self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance() {
+        let start = SourceLocation::default();
+        assert_eq!(
+            start.advance("ab"),
+            SourceLocation {
+                offset: 2,
+                line: 1,
+                column: 3
+            }
+        );
+        let end = start.advance("é\n\n  x");
+        assert_eq!(
+            end,
+            SourceLocation {
+                offset: 7,
+                line: 3,
+                column: 4
+            }
+        );
+        assert_eq!(Span { start, end }.to_string(), "1:1-3:4");
+        assert_eq!(Span { start, end }.range(), 0..7);
+    }
+}
@@ -1,23 +1,389 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use rayon::prelude::*;
 
 use crate::{
-    css::{self, Value},
+    atom::Atom,
+    css::{self, Token, Value},
     dom::{self, NodeType},
+    inherit, layout,
 };
 
-type PropertyMap = HashMap<String, css::Value>;
+pub type PropertyMap = HashMap<String, css::Value>;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct StyledNode<'a> {
-    pub node: &'a dom::Node,
+    pub node: dom::NodeRef<'a>,
     pub specified_values: PropertyMap,
     pub children: Vec<StyledNode<'a>>,
 }
 
+/// How `style_tree_with` walks the DOM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    /// Style one node after another on the calling thread.
+    Sequential,
+    /// Style sibling subtrees concurrently on the rayon thread pool.
+    Parallel,
+}
+
+/// The device a document is styled for, which decides the `@media` rules
+/// that apply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaEnvironment {
+    /// Lowercased, such as `screen` or `print`.
+    pub media_type: String,
+    pub width: f32,
+    pub height: f32,
+    pub color_scheme: ColorScheme,
+    pub reduced_motion: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+impl Default for MediaEnvironment {
+    fn default() -> MediaEnvironment {
+        MediaEnvironment {
+            media_type: "screen".to_string(),
+            width: 800.0,
+            height: 600.0,
+            color_scheme: ColorScheme::Light,
+            reduced_motion: false,
+        }
+    }
+}
+
+impl MediaEnvironment {
+    /// A light screen the size of the viewport's content box.
+    pub fn screen(viewport: &layout::Dimensions) -> MediaEnvironment {
+        MediaEnvironment {
+            width: viewport.content.width,
+            height: viewport.content.height,
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, queries: &css::MediaQueryList) -> bool {
+        queries.0.iter().any(|query| self.matches_query(query))
+    }
+
+    fn matches_query(&self, query: &css::MediaQuery) -> bool {
+        let matched = (query.media_type == "all" || query.media_type == self.media_type)
+            && query
+                .condition
+                .as_ref()
+                .is_none_or(|condition| self.matches_condition(condition));
+        matched != query.negated
+    }
+
+    fn matches_condition(&self, condition: &css::MediaCondition) -> bool {
+        match condition {
+            css::MediaCondition::Feature(feature) => self.matches_feature(feature),
+            css::MediaCondition::Not(condition) => !self.matches_condition(condition),
+            css::MediaCondition::And(conditions) => {
+                conditions.iter().all(|c| self.matches_condition(c))
+            }
+            css::MediaCondition::Or(conditions) => {
+                conditions.iter().any(|c| self.matches_condition(c))
+            }
+            css::MediaCondition::Unknown(_) => false,
+        }
+    }
+
+    fn matches_feature(&self, feature: &css::MediaFeature) -> bool {
+        // Lengths in media queries resolve against the initial font size.
+        let px = |value: &Value| value.resolve(&css::LengthContext::default());
+        match feature {
+            css::MediaFeature::Boolean(name) => match self.feature_value(name) {
+                Some(Value::Keyword(keyword)) => keyword != "none" && keyword != "no-preference",
+                Some(value) => px(&value) != Some(0.0),
+                None => false,
+            },
+            css::MediaFeature::Plain(name, expected) => match self.feature_value(name) {
+                Some(Value::Keyword(keyword)) => {
+                    matches!(expected, Value::Keyword(k) if k.eq_ignore_ascii_case(&keyword))
+                }
+                Some(value) => px(&value).is_some() && px(&value) == px(expected),
+                None => false,
+            },
+            css::MediaFeature::Range { name, lower, upper } => {
+                let actual = match self.feature_value(name).as_ref().and_then(px) {
+                    Some(actual) => actual,
+                    None => return false,
+                };
+                let above_lower = lower.as_ref().map_or(Some(true), |bound| {
+                    px(&bound.value).map(|v| actual > v || (bound.inclusive && actual == v))
+                });
+                let below_upper = upper.as_ref().map_or(Some(true), |bound| {
+                    px(&bound.value).map(|v| actual < v || (bound.inclusive && actual == v))
+                });
+                above_lower == Some(true) && below_upper == Some(true)
+            }
+        }
+    }
+
+    fn feature_value(&self, name: &str) -> Option<Value> {
+        let keyword = |keyword: &str| Some(Value::Keyword(keyword.to_string()));
+        match name {
+            "width" => Some(Value::Length(self.width, css::Unit::Px)),
+            "height" => Some(Value::Length(self.height, css::Unit::Px)),
+            "orientation" if self.height >= self.width => keyword("portrait"),
+            "orientation" => keyword("landscape"),
+            "prefers-color-scheme" => match self.color_scheme {
+                ColorScheme::Light => keyword("light"),
+                ColorScheme::Dark => keyword("dark"),
+            },
+            "prefers-reduced-motion" if self.reduced_motion => keyword("reduce"),
+            "prefers-reduced-motion" => keyword("no-preference"),
+            _ => None,
+        }
+    }
+}
+
+/// Whether every condition `rule` is nested in holds in `media`.
+pub fn rule_applies(rule: &css::Rule, media: &MediaEnvironment) -> bool {
+    rule.conditions.iter().all(|condition| match condition {
+        css::Condition::Media(queries) => media.matches(queries),
+        css::Condition::Supports(condition) => supports(condition),
+    })
+}
+
+/// Evaluate an `@supports` condition against `SUPPORTED_PROPERTIES` and the
+/// selectors the style sheet parser understands.
+pub fn supports(condition: &css::SupportsCondition) -> bool {
+    match condition {
+        css::SupportsCondition::Declaration(name, tokens) => {
+            let uses_var = tokens.iter().any(
+                |token| matches!(token, Token::Function(name) if name.eq_ignore_ascii_case("var")),
+            );
+            let value = if uses_var {
+                Some(Value::Unparsed(tokens.clone()))
+            } else {
+                Value::from_tokens(tokens)
+            };
+            value.is_some_and(|value| is_supported(name, &value))
+        }
+        css::SupportsCondition::Selector(tokens) => css::Selector::from_tokens(tokens).is_some(),
+        css::SupportsCondition::Not(condition) => !supports(condition),
+        css::SupportsCondition::And(conditions) => conditions.iter().all(supports),
+        css::SupportsCondition::Or(conditions) => conditions.iter().any(supports),
+        css::SupportsCondition::Unknown(_) => false,
+    }
+}
+
+/// The computed `display` value, following the outer/inner display model of
+/// CSS Display Level 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Display {
+    /// A principal box, e.g. `block` is `Box { outside: Block, inside: Flow, .. }`.
+    Box {
+        outside: DisplayOutside,
+        inside: DisplayInside,
+        list_item: bool,
+    },
+    /// A layout-internal box such as `table-row` or `ruby-text`.
+    Internal(DisplayInternal),
+    /// The element generates no box, but its children do.
+    Contents,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayOutside {
     Block,
     Inline,
-    None,
+    RunIn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayInside {
+    Flow,
+    FlowRoot,
+    Table,
+    Flex,
+    Grid,
+    Ruby,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayInternal {
+    TableRowGroup,
+    TableHeaderGroup,
+    TableFooterGroup,
+    TableRow,
+    TableCell,
+    TableColumnGroup,
+    TableColumn,
+    TableCaption,
+    RubyBase,
+    RubyText,
+    RubyBaseContainer,
+    RubyTextContainer,
+}
+
+impl Display {
+    pub const BLOCK: Display = Display::Box {
+        outside: DisplayOutside::Block,
+        inside: DisplayInside::Flow,
+        list_item: false,
+    };
+    pub const INLINE: Display = Display::Box {
+        outside: DisplayOutside::Inline,
+        inside: DisplayInside::Flow,
+        list_item: false,
+    };
+
+    /// The `display` a declared value stands for, or `None` if it is
+    /// invalid.
+    pub fn from_value(value: &Value) -> Option<Display> {
+        match value {
+            Value::Keyword(keyword) => Display::parse(&[keyword]),
+            Value::List(values) => values
+                .iter()
+                .map(|value| match value {
+                    Value::Keyword(keyword) => Some(keyword.as_str()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .and_then(|keywords| Display::parse(&keywords)),
+            _ => None,
+        }
+    }
+
+    /// Parse the keywords of a `display` declaration, accepting both the
+    /// legacy single keywords (`inline-block`) and the multi-keyword syntax
+    /// (`inline flow-root`). Returns `None` for an invalid value.
+    pub fn parse(keywords: &[&str]) -> Option<Display> {
+        if let [keyword] = keywords {
+            if let Some(display) = Display::parse_single(keyword) {
+                return Some(display);
+            }
+        }
+
+        let mut outside = None;
+        let mut inside = None;
+        let mut list_item = false;
+        for keyword in keywords {
+            match *keyword {
+                "block" if outside.is_none() => outside = Some(DisplayOutside::Block),
+                "inline" if outside.is_none() => outside = Some(DisplayOutside::Inline),
+                "run-in" if outside.is_none() => outside = Some(DisplayOutside::RunIn),
+                "flow" if inside.is_none() => inside = Some(DisplayInside::Flow),
+                "flow-root" if inside.is_none() => inside = Some(DisplayInside::FlowRoot),
+                "table" if inside.is_none() => inside = Some(DisplayInside::Table),
+                "flex" if inside.is_none() => inside = Some(DisplayInside::Flex),
+                "grid" if inside.is_none() => inside = Some(DisplayInside::Grid),
+                "ruby" if inside.is_none() => inside = Some(DisplayInside::Ruby),
+                "list-item" if !list_item => list_item = true,
+                _ => return None,
+            }
+        }
+
+        let inside = inside.unwrap_or(DisplayInside::Flow);
+        if list_item && !matches!(inside, DisplayInside::Flow | DisplayInside::FlowRoot) {
+            return None;
+        }
+        let outside = outside.unwrap_or(match inside {
+            DisplayInside::Ruby => DisplayOutside::Inline,
+            _ => DisplayOutside::Block,
+        });
+        Some(Display::Box {
+            outside,
+            inside,
+            list_item,
+        })
+    }
+
+    fn parse_single(keyword: &str) -> Option<Display> {
+        let inline = |inside| Display::Box {
+            outside: DisplayOutside::Inline,
+            inside,
+            list_item: false,
+        };
+        let internal = match keyword {
+            "none" => return Some(Display::None),
+            "contents" => return Some(Display::Contents),
+            "inline-block" => return Some(inline(DisplayInside::FlowRoot)),
+            "inline-table" => return Some(inline(DisplayInside::Table)),
+            "inline-flex" => return Some(inline(DisplayInside::Flex)),
+            "inline-grid" => return Some(inline(DisplayInside::Grid)),
+            "table-row-group" => DisplayInternal::TableRowGroup,
+            "table-header-group" => DisplayInternal::TableHeaderGroup,
+            "table-footer-group" => DisplayInternal::TableFooterGroup,
+            "table-row" => DisplayInternal::TableRow,
+            "table-cell" => DisplayInternal::TableCell,
+            "table-column-group" => DisplayInternal::TableColumnGroup,
+            "table-column" => DisplayInternal::TableColumn,
+            "table-caption" => DisplayInternal::TableCaption,
+            "ruby-base" => DisplayInternal::RubyBase,
+            "ruby-text" => DisplayInternal::RubyText,
+            "ruby-base-container" => DisplayInternal::RubyBaseContainer,
+            "ruby-text-container" => DisplayInternal::RubyTextContainer,
+            _ => return None,
+        };
+        Some(Display::Internal(internal))
+    }
+}
+
+/// The computed `white-space` value: whether white space collapses, whether
+/// newlines force line breaks, and whether lines wrap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteSpace {
+    Normal,
+    Pre,
+    Nowrap,
+    PreWrap,
+    PreLine,
+    BreakSpaces,
+}
+
+impl WhiteSpace {
+    pub const KEYWORDS: &'static [&'static str] = &[
+        "normal",
+        "pre",
+        "nowrap",
+        "pre-wrap",
+        "pre-line",
+        "break-spaces",
+    ];
+
+    pub fn parse(keyword: &str) -> Option<WhiteSpace> {
+        match keyword {
+            "normal" => Some(WhiteSpace::Normal),
+            "pre" => Some(WhiteSpace::Pre),
+            "nowrap" => Some(WhiteSpace::Nowrap),
+            "pre-wrap" => Some(WhiteSpace::PreWrap),
+            "pre-line" => Some(WhiteSpace::PreLine),
+            "break-spaces" => Some(WhiteSpace::BreakSpaces),
+            _ => None,
+        }
+    }
+
+    /// Whether runs of spaces and tabs collapse into one space.
+    pub fn collapses_spaces(self) -> bool {
+        matches!(
+            self,
+            WhiteSpace::Normal | WhiteSpace::Nowrap | WhiteSpace::PreLine
+        )
+    }
+
+    /// Whether newlines are kept as forced line breaks, rather than
+    /// collapsing like spaces.
+    pub fn preserves_newlines(self) -> bool {
+        !matches!(self, WhiteSpace::Normal | WhiteSpace::Nowrap)
+    }
+
+    /// Whether lines wrap when they are too long.
+    pub fn wraps(self) -> bool {
+        !matches!(self, WhiteSpace::Pre | WhiteSpace::Nowrap)
+    }
 }
 
 impl StyledNode<'_> {
@@ -37,28 +403,91 @@ impl StyledNode<'_> {
             .unwrap_or_else(|| self.value(fallback_name).unwrap_or_else(|| default.clone()))
     }
 
+    /// The computed `display` value. An invalid or missing declaration falls
+    /// back to the initial value, `inline`.
     pub fn display(&self) -> Display {
-        match self.value("display") {
-            Some(Value::Keyword(s)) => match &*s {
-                "block" => Display::Block,
-                "inline" => Display::Inline,
-                _ => Display::None,
-            },
-            _ => Display::Inline,
+        self.value("display")
+            .and_then(|value| Display::from_value(&value))
+            .unwrap_or(Display::INLINE)
+    }
+
+    /// The computed `white-space` value, `normal` if it is invalid or
+    /// missing.
+    pub fn white_space(&self) -> WhiteSpace {
+        match self.value("white-space") {
+            Some(Value::Keyword(keyword)) => WhiteSpace::parse(&keyword),
+            _ => None,
         }
+        .unwrap_or(WhiteSpace::Normal)
     }
 }
 
-pub fn matches(elem: &dom::ElementData, selector: &css::Selector) -> bool {
+pub fn matches(node: dom::NodeRef, selector: &css::Selector) -> bool {
     match *selector {
-        css::Selector::Simple(ref simple_selector) => {
-            matches_simple_selectors(elem, simple_selector)
+        css::Selector::Simple(ref simple_selector) => matches_compound(node, simple_selector),
+        css::Selector::Complex(ref first, ref rest) => matches_complex(node, first, rest),
+    }
+}
+
+/// Match from the right: `node` must match the last compound, and some
+/// element related to it by the last combinator must match the rest.
+fn matches_complex(
+    node: dom::NodeRef,
+    first: &css::SimpleSelector,
+    rest: &[(css::Combinator, css::SimpleSelector)],
+) -> bool {
+    let Some(((combinator, subject), rest)) = rest.split_last() else {
+        return matches_compound(node, first);
+    };
+    if !matches_compound(node, subject) {
+        return false;
+    }
+    let mut previous_elements = std::iter::successors(node.previous_sibling(), |sibling| {
+        sibling.previous_sibling()
+    })
+    .filter(|sibling| sibling.element().is_some());
+    match combinator {
+        css::Combinator::Descendant => node
+            .ancestors()
+            .any(|ancestor| matches_complex(ancestor, first, rest)),
+        css::Combinator::Child => node
+            .parent()
+            .is_some_and(|parent| matches_complex(parent, first, rest)),
+        css::Combinator::NextSibling => previous_elements
+            .next()
+            .is_some_and(|sibling| matches_complex(sibling, first, rest)),
+        css::Combinator::SubsequentSibling => {
+            previous_elements.any(|sibling| matches_complex(sibling, first, rest))
         }
     }
 }
 
+fn matches_compound(node: dom::NodeRef, selector: &css::SimpleSelector) -> bool {
+    node.element()
+        .is_some_and(|elem| matches_simple_selectors(elem, selector))
+}
+
 pub fn matches_simple_selectors(elem: &dom::ElementData, selector: &css::SimpleSelector) -> bool {
-    if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
+    let in_namespace = match &selector.namespace {
+        None | Some(css::NamespaceConstraint::Any) => true,
+        Some(css::NamespaceConstraint::NoNamespace) => false,
+        Some(css::NamespaceConstraint::Url { url, .. }) => elem.namespace.url() == url.as_str(),
+    };
+    if !in_namespace {
+        return false;
+    }
+
+    // Selector names are lowercased, so they match HTML elements by atom.
+    // SVG and MathML names keep their case and are compared ignoring it.
+    let html = elem.namespace == dom::Namespace::Html;
+    let same_name = |name: &Atom, selector_name: &Atom| {
+        name == selector_name || !html && name.eq_ignore_ascii_case(selector_name)
+    };
+    if selector
+        .tag_name
+        .iter()
+        .any(|name| !same_name(&elem.tag_name, name))
+    {
         return false;
     }
 
@@ -70,124 +499,710 @@ pub fn matches_simple_selectors(elem: &dom::ElementData, selector: &css::SimpleS
     if selector
         .class
         .iter()
-        .any(|class| !elem_classes.contains(&**class))
+        .any(|class| !elem_classes.contains(class))
     {
         return false;
     };
-    true
+
+    selector.attributes.iter().all(|attribute| {
+        let actual = if html {
+            elem.attributes
+                .get_atom(&attribute.name)
+                .map(String::as_str)
+        } else {
+            elem.attributes
+                .iter()
+                .find(|&(name, _)| name.eq_ignore_ascii_case(&attribute.name))
+                .map(|(_, value)| value)
+        };
+        match (actual, &attribute.value) {
+            (Some(actual), Some(expected)) => actual == expected,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    })
+}
+
+/// The values a supported property accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertySyntax {
+    /// A length, percentage or math function.
+    Length,
+    /// A length, percentage, math function or `auto`.
+    LengthOrAuto,
+    /// A length, percentage, math function or `normal`.
+    LengthOrNormal,
+    /// A `display` value that layout gives its own behaviour, rather than
+    /// falling back to a block or inline box.
+    Display,
+    /// One of the given keywords.
+    Keyword(&'static [&'static str]),
+}
+
+/// The properties layout and rendering act on. Anything else parses but
+/// has no effect, so `@supports` reports it as unsupported.
+pub const SUPPORTED_PROPERTIES: &[(&str, PropertySyntax)] = &[
+    ("border-bottom-width", PropertySyntax::Length),
+    ("border-left-width", PropertySyntax::Length),
+    ("border-right-width", PropertySyntax::Length),
+    ("border-top-width", PropertySyntax::Length),
+    ("border-width", PropertySyntax::Length),
+    ("display", PropertySyntax::Display),
+    ("font-size", PropertySyntax::Length),
+    ("height", PropertySyntax::LengthOrAuto),
+    ("line-height", PropertySyntax::LengthOrNormal),
+    ("margin", PropertySyntax::LengthOrAuto),
+    ("margin-bottom", PropertySyntax::LengthOrAuto),
+    ("margin-left", PropertySyntax::LengthOrAuto),
+    ("margin-right", PropertySyntax::LengthOrAuto),
+    ("margin-top", PropertySyntax::LengthOrAuto),
+    ("padding", PropertySyntax::Length),
+    ("padding-bottom", PropertySyntax::Length),
+    ("padding-left", PropertySyntax::Length),
+    ("padding-right", PropertySyntax::Length),
+    ("padding-top", PropertySyntax::Length),
+    ("white-space", PropertySyntax::Keyword(WhiteSpace::KEYWORDS)),
+    ("width", PropertySyntax::LengthOrAuto),
+];
+
+pub fn property_syntax(name: &str) -> Option<PropertySyntax> {
+    SUPPORTED_PROPERTIES
+        .iter()
+        .find(|(property, _)| *property == name)
+        .map(|&(_, syntax)| syntax)
+}
+
+/// Whether `name: value` is a declaration Yoyo implements. Custom
+/// properties accept anything, and a value using `var()` is accepted for
+/// any supported property since it can only be checked once substituted.
+pub fn is_supported(name: &str, value: &Value) -> bool {
+    if is_custom_property(name) {
+        return true;
+    }
+    let syntax = match property_syntax(name) {
+        Some(syntax) => syntax,
+        None => return false,
+    };
+    let is_length = matches!(value, Value::Length(..) | Value::Calc(_));
+    match (syntax, value) {
+        (_, Value::Unparsed(_)) => true,
+        (PropertySyntax::Length, _) => is_length,
+        (PropertySyntax::LengthOrAuto, Value::Keyword(keyword)) => keyword == "auto",
+        (PropertySyntax::LengthOrAuto, _) => is_length,
+        (PropertySyntax::LengthOrNormal, Value::Keyword(keyword)) => keyword == "normal",
+        (PropertySyntax::LengthOrNormal, _) => is_length,
+        (PropertySyntax::Keyword(keywords), Value::Keyword(keyword)) => {
+            keywords.contains(&keyword.as_str())
+        }
+        (PropertySyntax::Keyword(_), _) => false,
+        (PropertySyntax::Display, _) => {
+            matches!(
+                Display::from_value(value),
+                Some(
+                    Display::Box {
+                        outside: DisplayOutside::Block | DisplayOutside::Inline,
+                        inside: DisplayInside::Flow | DisplayInside::FlowRoot,
+                        list_item: false,
+                    } | Display::Contents
+                        | Display::None
+                )
+            )
+        }
+    }
+}
+
+pub fn is_custom_property(name: &str) -> bool {
+    name.starts_with("--")
 }
 
-type MatchedRule<'a> = (css::Specificity, &'a css::Rule);
+/// A rule matching an element through its most specific matching selector.
+type MatchedRule<'a> = (css::Specificity, &'a css::Selector, &'a css::Rule);
 
-fn match_rule<'a>(elem: &dom::ElementData, rule: &'a css::Rule) -> Option<MatchedRule<'a>> {
+fn match_rule<'a>(node: dom::NodeRef, rule: &'a css::Rule) -> Option<MatchedRule<'a>> {
     rule.selectors
         .iter()
-        .find(|selector| matches(elem, *selector))
-        .map(|selector| (selector.specificity(), rule))
+        .filter(|selector| matches(node, selector))
+        .max_by_key(|selector| selector.specificity())
+        .map(|selector| (selector.specificity(), selector, rule))
 }
 
 fn match_rules<'a>(
-    elem: &dom::ElementData,
+    node: dom::NodeRef,
     style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
 ) -> Vec<MatchedRule<'a>> {
     style_sheet
         .rules
         .iter()
-        .filter_map(|rule| match_rule(elem, rule))
+        .filter(|rule| rule_applies(rule, media))
+        .filter_map(|rule| match_rule(node, rule))
         .collect()
 }
 
-fn specified_values(elem: &dom::ElementData, style_sheet: &css::StyleSheet) -> PropertyMap {
+/// A declaration that applies to an element, with everything that decides
+/// its place in the cascade.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedDeclaration<'a> {
+    pub declaration: &'a css::Declaration,
+    pub selector: &'a css::Selector,
+    pub specificity: css::Specificity,
+    pub origin: css::Origin,
+    /// The cascade layer of the rule, empty when unlayered.
+    pub layer: &'a [String],
+}
+
+impl MatchedDeclaration<'_> {
+    /// Origin and importance come first, then the layer, then specificity.
+    /// Important declarations reverse the order of the layers, so unlayered
+    /// ones rank lowest.
+    fn cascade_rank(&self, layers: &css::LayerOrder) -> (u8, usize, css::Specificity) {
+        let important = self.declaration.important;
+        let layer = layers.rank(self.layer);
+        (
+            self.origin.cascade_rank(important),
+            if important { usize::MAX - layer } else { layer },
+            self.specificity,
+        )
+    }
+}
+
+/// Every declaration that applies to `node`, sorted from the lowest to the
+/// highest precedence: by origin and importance, then cascade layer, then
+/// the specificity of the most specific selector in the rule's list that
+/// matches. Ties keep source order, so the last one wins.
+fn matched_declarations<'a>(
+    node: dom::NodeRef,
+    style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
+) -> Vec<MatchedDeclaration<'a>> {
+    let mut declarations: Vec<_> = match_rules(node, style_sheet, media)
+        .into_iter()
+        .flat_map(|(specificity, selector, rule)| {
+            rule.declarations
+                .iter()
+                .map(move |declaration| MatchedDeclaration {
+                    declaration,
+                    selector,
+                    specificity,
+                    origin: style_sheet.origin,
+                    layer: &rule.layer,
+                })
+        })
+        .collect();
+    declarations.sort_by_key(|matched| matched.cascade_rank(&style_sheet.layers));
+    declarations
+}
+
+fn specified_values(
+    node: dom::NodeRef,
+    style_sheet: &css::StyleSheet,
+    media: &MediaEnvironment,
+) -> PropertyMap {
     let mut values = HashMap::new();
-    let mut rules = match_rules(elem, style_sheet);
+    for matched in matched_declarations(node, style_sheet, media) {
+        let declaration = matched.declaration;
+        values.insert(declaration.name.clone(), declaration.value.clone());
+    }
+    values
+}
+
+/// Build the style tree for the default `MediaEnvironment`.
+pub fn style_tree<'a>(root: dom::NodeRef<'a>, style_sheet: &'a css::StyleSheet) -> StyledNode<'a> {
+    style_tree_with(
+        root,
+        style_sheet,
+        &MediaEnvironment::default(),
+        Traversal::Sequential,
+    )
+}
+
+/// Build the style tree for `media` using the given traversal.
+///
+/// A node's values depend only on the node itself and its parent's values,
+/// so both traversals produce identical trees; the parallel one just styles
+/// sibling subtrees on different threads and collects them back in document
+/// order.
+pub fn style_tree_with<'a>(
+    root: dom::NodeRef<'a>,
+    style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
+    traversal: Traversal,
+) -> StyledNode<'a> {
+    style_node(root, style_sheet, media, traversal, None)
+}
+
+/// The values of `node`: what it inherits from its parent's `parent_values`,
+/// overridden by the declarations matching it. Only inherited properties
+/// (see `inherit::is_inherited`) are taken from the parent.
+pub(crate) fn node_values(
+    node: dom::NodeRef,
+    style_sheet: &css::StyleSheet,
+    media: &MediaEnvironment,
+    parent_values: Option<&PropertyMap>,
+) -> PropertyMap {
+    computed_values(node, style_sheet, media, parent_values).0
+}
+
+/// `node_values`, along with the properties whose declared value was
+/// invalid at computed-value time.
+fn computed_values(
+    node: dom::NodeRef,
+    style_sheet: &css::StyleSheet,
+    media: &MediaEnvironment,
+    parent_values: Option<&PropertyMap>,
+) -> (PropertyMap, HashSet<String>) {
+    let mut values = inherit::inherited_values(parent_values);
+    values.extend(specified_values(node, style_sheet, media));
+    let invalid = substitute_variables(&mut values, parent_values);
+    compute_font_size(&mut values, parent_values);
+    (values, invalid)
+}
+
+/// Resolve `font-size` to px against the parent's, so children inherit an
+/// absolute size rather than compounding relative ones. `rem` is taken
+/// relative to the initial font size here.
+fn compute_font_size(values: &mut PropertyMap, parent_values: Option<&PropertyMap>) {
+    let parent_size = parent_values
+        .and_then(|parent| parent.get("font-size"))
+        .map_or(css::DEFAULT_FONT_SIZE, Value::to_px);
+    let context = css::LengthContext {
+        percent_base: Some(parent_size),
+        font_size: parent_size,
+        root_font_size: css::DEFAULT_FONT_SIZE,
+    };
+    if let Some(size) = values
+        .get("font-size")
+        .and_then(|size| size.resolve(&context))
+    {
+        values.insert("font-size".to_string(), Value::Length(size, css::Unit::Px));
+    }
+}
+
+/// Replace `var()` references in the unparsed values of one element, and
+/// return the names of the properties whose value couldn't be resolved.
+///
+/// A value that can't be resolved is invalid at computed-value time: a
+/// custom property then has no value at all, and any other property acts
+/// as `unset`, inheriting from the parent if it is inherited.
+fn substitute_variables(
+    values: &mut PropertyMap,
+    parent_values: Option<&PropertyMap>,
+) -> HashSet<String> {
+    let mut resolver = VariableResolver {
+        values,
+        resolved: HashMap::new(),
+        stack: Vec::new(),
+        cyclic: HashSet::new(),
+    };
+    let substituted: Vec<(String, Option<Value>)> = values
+        .iter()
+        .filter_map(|(name, value)| match value {
+            Value::Unparsed(tokens) => Some((name, tokens)),
+            _ => None,
+        })
+        .map(|(name, tokens)| {
+            let value = if is_custom_property(name) {
+                resolver.custom_property(name).map(Value::Unparsed)
+            } else {
+                resolver
+                    .substitute(tokens)
+                    .and_then(|tokens| Value::from_tokens(&tokens))
+            };
+            (name.clone(), value)
+        })
+        .collect();
 
-    rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
-    for (_, rule) in rules {
-        for declaration in &rule.declarations {
-            values.insert(declaration.name.clone(), declaration.value.clone());
+    let mut invalid = HashSet::new();
+    for (name, value) in substituted {
+        if value.is_none() {
+            invalid.insert(name.clone());
         }
+        let value = value.or_else(|| {
+            parent_values
+                .filter(|_| inherit::is_inherited(&name) && !is_custom_property(&name))
+                .and_then(|parent| parent.get(&name).cloned())
+        });
+        match value {
+            Some(value) => values.insert(name, value),
+            None => values.remove(&name),
+        };
     }
-    values
+    invalid
 }
 
-pub fn style_tree<'a>(root: &'a dom::Node, style_sheet: &'a css::StyleSheet) -> StyledNode<'a> {
-    StyledNode {
-        node: root,
-        specified_values: match root.node_type {
-            dom::NodeType::Element(ref elem) => specified_values(elem, style_sheet),
-            _ => HashMap::new(),
-        },
-        children: root
-            .children
-            .iter()
-            .map(|child| style_tree(child, style_sheet))
+struct VariableResolver<'a> {
+    values: &'a PropertyMap,
+    resolved: HashMap<String, Option<Vec<Token>>>,
+    /// Custom properties being resolved, to detect reference cycles.
+    stack: Vec<String>,
+    cyclic: HashSet<String>,
+}
+
+impl VariableResolver<'_> {
+    fn custom_property(&mut self, name: &str) -> Option<Vec<Token>> {
+        if let Some(resolved) = self.resolved.get(name) {
+            return resolved.clone();
+        }
+        if let Some(i) = self.stack.iter().position(|entry| entry == name) {
+            // Every property in a cycle is invalid, whatever its fallbacks.
+            self.cyclic.extend(self.stack[i..].iter().cloned());
+            return None;
+        }
+        let tokens = match self.values.get(name) {
+            Some(Value::Unparsed(tokens)) => tokens,
+            _ => return None,
+        };
+
+        self.stack.push(name.to_string());
+        let resolved = self.substitute(tokens);
+        self.stack.pop();
+
+        let resolved = resolved.filter(|_| !self.cyclic.contains(name));
+        self.resolved.insert(name.to_string(), resolved.clone());
+        resolved
+    }
+
+    fn substitute(&mut self, tokens: &[Token]) -> Option<Vec<Token>> {
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            match tokens[i] {
+                Token::Function(ref name) if name.eq_ignore_ascii_case("var") => {
+                    let end = closing_paren(tokens, i)?;
+                    output.extend(self.var(&tokens[i + 1..end])?);
+                    i = end + 1;
+                }
+                ref token => {
+                    output.push(token.clone());
+                    i += 1;
+                }
+            }
+        }
+        Some(output)
+    }
+
+    /// Resolve the arguments of one `var()`: a custom property name and an
+    /// optional fallback after a comma.
+    fn var(&mut self, arguments: &[Token]) -> Option<Vec<Token>> {
+        let arguments = trim_whitespace(arguments);
+        let name = match arguments.first() {
+            Some(Token::Ident(name)) if is_custom_property(name) => name,
+            _ => return None,
+        };
+        let fallback = match trim_whitespace(&arguments[1..]) {
+            [] => None,
+            [Token::Comma, fallback @ ..] => Some(trim_whitespace(fallback)),
+            _ => return None,
+        };
+
+        match (self.custom_property(name), fallback) {
+            (Some(tokens), _) => Some(tokens),
+            (None, Some(fallback)) => self.substitute(fallback),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The index of the `)` closing the function or parenthesis at `open`.
+fn closing_paren(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token {
+            Token::Function(_) | Token::OpenParen => depth += 1,
+            Token::CloseParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn trim_whitespace(tokens: &[Token]) -> &[Token] {
+    let start = tokens
+        .iter()
+        .position(|token| *token != Token::Whitespace)
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|token| *token != Token::Whitespace)
+        .map_or(start, |i| i + 1);
+    &tokens[start..end]
+}
+
+fn style_node<'a>(
+    node: dom::NodeRef<'a>,
+    style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
+    traversal: Traversal,
+    parent: Option<&PropertyMap>,
+) -> StyledNode<'a> {
+    let values = node_values(node, style_sheet, media, parent);
+
+    let children = match traversal {
+        Traversal::Sequential => node
+            .children()
+            .map(|child| style_node(child, style_sheet, media, traversal, Some(&values)))
             .collect(),
+        Traversal::Parallel => node
+            .children()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|child| style_node(child, style_sheet, media, traversal, Some(&values)))
+            .collect(),
+    };
+
+    StyledNode {
+        node,
+        specified_values: values,
+        children,
+    }
+}
+
+/// Why a property has the value it has on an element.
+#[derive(Debug)]
+pub struct CascadeExplanation<'a> {
+    pub property: String,
+    /// Every matching declaration of the property, lowest precedence first.
+    pub declarations: Vec<MatchedDeclaration<'a>>,
+    /// Index into `declarations` of the one that won the cascade.
+    pub winner: Option<usize>,
+    /// Set when no declaration matched and the value came from an ancestor.
+    pub inherited_from: Option<InheritedSource<'a>>,
+    /// The value the element ends up with, after `var()` substitution and
+    /// computing relative font sizes.
+    pub computed: Option<Value>,
+    /// Whether the declared value couldn't be resolved, so the property
+    /// acts as `unset` instead.
+    pub invalid_at_computed_value_time: bool,
+}
+
+#[derive(Debug)]
+pub struct InheritedSource<'a> {
+    pub ancestor: dom::NodeRef<'a>,
+    /// 1 for the parent, 2 for the grandparent, and so on.
+    pub generations: usize,
+    pub declaration: MatchedDeclaration<'a>,
+}
+
+impl CascadeExplanation<'_> {
+    /// The declared value that won the cascade, before it is computed.
+    pub fn value(&self) -> Option<&Value> {
+        match (self.winner, &self.inherited_from) {
+            (Some(i), _) => Some(&self.declarations[i].declaration.value),
+            (None, Some(source)) => Some(&source.declaration.declaration.value),
+            (None, None) => None,
+        }
+    }
+}
+
+fn property_declarations<'a>(
+    node: dom::NodeRef,
+    style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
+    property: &str,
+) -> Vec<MatchedDeclaration<'a>> {
+    matched_declarations(node, style_sheet, media)
+        .into_iter()
+        .filter(|matched| matched.declaration.name == property)
+        .collect()
+}
+
+/// Explain how `property` gets its value on the last node of `lineage`,
+/// which lists the node's ancestors from the root down, ending with the node.
+/// Returns `None` for an empty lineage.
+pub fn explain<'a>(
+    lineage: &[dom::NodeRef<'a>],
+    style_sheet: &'a css::StyleSheet,
+    media: &MediaEnvironment,
+    property: &str,
+) -> Option<CascadeExplanation<'a>> {
+    let (node, ancestors) = lineage.split_last()?;
+    let declarations = property_declarations(*node, style_sheet, media, property);
+    let winner = declarations.len().checked_sub(1);
+
+    let mut inherited_from = None;
+    if winner.is_none() && inherit::is_inherited(property) {
+        for (i, ancestor) in ancestors.iter().rev().enumerate() {
+            if let Some(declaration) =
+                property_declarations(*ancestor, style_sheet, media, property).pop()
+            {
+                inherited_from = Some(InheritedSource {
+                    ancestor: *ancestor,
+                    generations: i + 1,
+                    declaration,
+                });
+                break;
+            }
+        }
+    }
+
+    let mut values = None;
+    let mut invalid = HashSet::new();
+    for node in lineage {
+        let computed = computed_values(*node, style_sheet, media, values.as_ref());
+        values = Some(computed.0);
+        invalid = computed.1;
+    }
+
+    Some(CascadeExplanation {
+        property: property.to_string(),
+        declarations,
+        winner,
+        inherited_from,
+        computed: values.and_then(|mut values| values.remove(property)),
+        invalid_at_computed_value_time: winner.is_some() && invalid.contains(property),
+    })
+}
+
+impl fmt::Display for MatchedDeclaration<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let declaration = self.declaration;
+        write!(
+            f,
+            "{} {:?} {}",
+            self.selector, self.specificity, self.origin
+        )?;
+        if !self.layer.is_empty() {
+            write!(f, " layer {}", self.layer.join("."))?;
+        }
+        write!(
+            f,
+            " at {}: {}: {}",
+            declaration.span.start, declaration.name, declaration.value
+        )?;
+        if declaration.important {
+            f.write_str(" !important")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CascadeExplanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, matched) in self.declarations.iter().enumerate() {
+            let marker = if Some(i) == self.winner { "*" } else { " " };
+            writeln!(f, "{} {}", marker, matched)?;
+        }
+        match (&self.inherited_from, self.value()) {
+            (Some(source), _) => {
+                let name = match source.ancestor.node_type {
+                    NodeType::Element(ref elem) => elem.tag_name.as_str(),
+                    _ => "#text",
+                };
+                let location = source
+                    .ancestor
+                    .span()
+                    .map(|span| format!(" at {}", span.start))
+                    .unwrap_or_default();
+                writeln!(
+                    f,
+                    "* inherited from <{}>{} {} generation(s) up: {}",
+                    name, location, source.generations, source.declaration
+                )
+            }
+            (None, None) => writeln!(f, "no declaration of {} applies", self.property),
+            (None, Some(_)) => Ok(()),
+        }?;
+        match (self.invalid_at_computed_value_time, &self.computed) {
+            (true, Some(value)) => {
+                writeln!(f, "= invalid at computed-value time, so unset: {}", value)
+            }
+            (true, None) => writeln!(f, "= invalid at computed-value time, so unset"),
+            (false, Some(value)) => writeln!(f, "= computed value: {}", value),
+            (false, None) => Ok(()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-
     use super::*;
 
     fn rule_1() -> css::Rule {
         css::Rule {
             selectors: vec![css::Selector::Simple(css::SimpleSelector {
+                namespace: None,
                 tag_name: None,
-                id: Some("1".to_string()),
+                id: Some(Atom::new("1")),
                 class: Vec::new(),
+                attributes: Vec::new(),
             })],
             declarations: vec![css::Declaration {
                 name: "margin".to_string(),
                 value: css::Value::Keyword("auto".to_string()),
+                important: false,
+                span: Default::default(),
             }],
+            conditions: Vec::new(),
+            layer: Vec::new(),
+            span: Default::default(),
         }
     }
 
     fn rule_2() -> css::Rule {
         css::Rule {
             selectors: vec![css::Selector::Simple(css::SimpleSelector {
-                tag_name: Some("h1".to_string()),
+                namespace: None,
+                tag_name: Some(Atom::new("h1")),
                 id: None,
                 class: Vec::new(),
+                attributes: Vec::new(),
             })],
             declarations: vec![css::Declaration {
                 name: "margin".to_string(),
                 value: css::Value::Keyword("0".to_string()),
+                important: false,
+                span: Default::default(),
             }],
+            conditions: Vec::new(),
+            layer: Vec::new(),
+            span: Default::default(),
         }
     }
 
     fn style_sheet() -> css::StyleSheet {
         css::StyleSheet {
             rules: vec![rule_1(), rule_2()],
+            origin: css::Origin::Author,
+            layers: Default::default(),
+            namespaces: Default::default(),
         }
     }
 
     #[test]
     fn test_matches_simple_selectors() {
-        let mut hash = HashMap::new();
+        let mut hash = dom::AttrMap::new();
         hash.insert("id".to_string(), "1".to_string());
         hash.insert("class".to_string(), "square".to_string());
         let elem = dom::ElementData {
-            tag_name: "h1".to_string(),
+            namespace: dom::Namespace::Html,
+            tag_name: Atom::new("h1"),
             attributes: hash,
         };
         let heading_selector = css::SimpleSelector {
-            tag_name: Some("h1".to_string()),
+            namespace: None,
+            tag_name: Some(Atom::new("h1")),
             id: None,
             class: Vec::new(),
+            attributes: Vec::new(),
         };
         let id_selector = css::SimpleSelector {
+            namespace: None,
             tag_name: None,
-            id: Some("1".to_string()),
+            id: Some(Atom::new("1")),
             class: Vec::new(),
+            attributes: Vec::new(),
         };
         let class_selector = css::SimpleSelector {
+            namespace: None,
             tag_name: None,
             id: None,
-            class: vec!["square".to_string()],
+            class: vec![Atom::new("square")],
+            attributes: Vec::new(),
         };
         assert!(matches_simple_selectors(&elem, &heading_selector));
         assert!(matches_simple_selectors(&elem, &id_selector));
@@ -196,23 +1211,559 @@ mod tests {
 
     #[test]
     fn test_match_rules() {
-        let mut hash = HashMap::new();
+        let mut hash = dom::AttrMap::new();
         hash.insert("id".to_string(), "1".to_string());
-        let elem = dom::ElementData {
-            tag_name: "h1".to_string(),
-            attributes: hash,
-        };
-        println!("{:?}", match_rules(&elem, &style_sheet()));
+        let tree = dom::element("h1".to_string(), hash, vec![]).build();
+        println!(
+            "{:?}",
+            match_rules(tree.root(), &style_sheet(), &MediaEnvironment::default())
+        );
     }
 
     #[test]
     fn test_specified_values() {
-        let mut hash = HashMap::new();
+        let mut hash = dom::AttrMap::new();
         hash.insert("id".to_string(), "1".to_string());
-        let elem = dom::ElementData {
-            tag_name: "h1".to_string(),
-            attributes: hash,
+        let tree = dom::element("h1".to_string(), hash, vec![]).build();
+        println!(
+            "{:?}",
+            specified_values(tree.root(), &style_sheet(), &MediaEnvironment::default())
+        );
+    }
+
+    #[test]
+    fn test_important_declarations_win_over_specificity() {
+        let tree = crate::html::parse("<p id='x' class='a'>text</p>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "#x { margin: 1px; } p { margin: 2px !important; } .a { margin: 3px; }".to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        assert_eq!(
+            styled.value("margin"),
+            Some(Value::Length(2.0, css::Unit::Px))
+        );
+    }
+
+    #[test]
+    fn test_cascade_order() {
+        let tree = crate::html::parse("<div><p id='x' class='a'>text</p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "p.a { padding: 1px; }
+            p.a { padding: 2px; }
+            .a, #x { margin: 1px; }
+            p.a { margin: 2px; }
+            #x { width: 1px !important; }
+            #x { width: 2px; }
+            div { color: #ff0000; height: 1px; }"
+                .to_string(),
+        );
+        let p = &style_tree(root, &style_sheet).children[0];
+        let px = |px| Some(Value::Length(px, css::Unit::Px));
+        // The later of two equally specific declarations wins.
+        assert_eq!(p.value("padding"), px(2.0));
+        // A rule counts with its most specific matching selector, `#x`.
+        assert_eq!(p.value("margin"), px(1.0));
+        // Importance beats source order.
+        assert_eq!(p.value("width"), px(1.0));
+        // Inherited properties come from the parent, others don't.
+        assert!(matches!(p.value("color"), Some(Value::Color(_))));
+        assert_eq!(p.value("height"), None);
+
+        // Important declarations reverse the order of the origins.
+        let rank = css::Origin::cascade_rank;
+        assert!(rank(css::Origin::UserAgent, false) < rank(css::Origin::Author, false));
+        assert!(rank(css::Origin::Author, false) < rank(css::Origin::Author, true));
+        assert!(rank(css::Origin::Author, true) < rank(css::Origin::UserAgent, true));
+    }
+
+    #[test]
+    fn test_cascade_layers() {
+        let tree = crate::html::parse("<p id='x' class='a'>text</p>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "@layer reset, base;
+            p { margin: 1px; }
+            @layer base { #x { margin: 2px; padding: 2px !important; } }
+            @layer reset { p { margin: 3px; padding: 3px !important; } }
+            .a { padding: 4px !important; }
+            @layer base { p { color: #ff0000; } }
+            @layer reset { #x { color: #00ff00; } }"
+                .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let px = |length| Some(Value::Length(length, css::Unit::Px));
+
+        // Unlayered rules beat layered ones whatever their specificity...
+        assert_eq!(styled.value("margin"), px(1.0));
+        // ...but for !important the earliest layer wins.
+        assert_eq!(styled.value("padding"), px(3.0));
+        // A later layer beats an earlier one despite lower specificity.
+        assert_eq!(
+            styled.value("color"),
+            Some(Value::Color(css::Color {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 255
+            }))
+        );
+    }
+
+    #[test]
+    fn test_html_names_match_case_insensitively() {
+        let tree = crate::html::parse("<DIV ID='Main' Class='Box'><P>text</P></DIV>".to_string());
+        let root = tree.document_element().unwrap();
+        let elem = root.element().unwrap();
+        assert_eq!(elem.tag_name, Atom::new("div"));
+        assert_eq!(elem.id(), Some(&Atom::new("Main")));
+
+        let style_sheet = css::parse(
+            "Div P { width: 1px; } #Main { height: 1px; } #main, .box, [CLASS=Box] { margin: 1px; }"
+                .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let mut names: Vec<_> = styled.specified_values.keys().collect();
+        names.sort();
+        assert_eq!(names, ["height", "margin"]);
+        assert!(styled.children[0].specified_values.contains_key("width"));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let tree = crate::html::parse(
+            "<div><svg viewBox='0 0 1 1'><rect/><foreignObject><p>text</p></foreignObject>\
+             <linearGradient/></svg><p>text</p></div>"
+                .to_string(),
+        );
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "@namespace svg url(http://www.w3.org/2000/svg);
+             svg|rect { width: 1px; } svg|* p { height: 1px; } |p { margin: 1px; }
+             svg|lineargradient, svg[viewbox] { padding: 1px; } *|rect { color: red; }"
+                .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let names = |node: &StyledNode| {
+            let mut names: Vec<_> = node.specified_values.keys().cloned().collect();
+            names.sort();
+            names
         };
-        println!("{:?}", specified_values(&elem, &style_sheet()));
+        let svg = &styled.children[0];
+        assert_eq!(names(svg), ["padding"]);
+        assert_eq!(names(&svg.children[0]), ["color", "width"]);
+        assert_eq!(names(&svg.children[1].children[0]), ["height"]);
+        assert_eq!(names(&svg.children[2]), ["padding"]);
+        assert!(names(&styled.children[1]).is_empty());
+
+        let html = css::parse(
+            "@namespace url(http://www.w3.org/1999/xhtml); rect, * p { width: 1px; }".to_string(),
+        );
+        let styled = style_tree(root, &html);
+        assert!(styled.children[0].children[0].specified_values.is_empty());
+        assert!(styled.children[1].specified_values.contains_key("width"));
+    }
+
+    #[test]
+    fn test_combinators() {
+        let tree = crate::html::parse(
+            "<div class='a'><p>1</p><span><p>2</p></span><p class='b'>3</p>text<p>4</p></div>"
+                .to_string(),
+        );
+        let style_sheet = css::parse(
+            ".a p { width: 1px; } .a > p { height: 1px; } .b + p { margin: 1px; }
+             span ~ p { padding: 1px; } div p p { color: red; }"
+                .to_string(),
+        );
+        let styled = style_tree(tree.document_element().unwrap(), &style_sheet);
+        let declared = |i: usize| {
+            let mut names: Vec<_> = styled.children[i]
+                .specified_values
+                .keys()
+                .map(String::as_str)
+                .collect();
+            names.sort();
+            names.join(" ")
+        };
+        assert_eq!(declared(0), "height width");
+        let nested = &styled.children[1].children[0];
+        assert_eq!(nested.value("height"), None);
+        assert!(nested.value("width").is_some());
+        assert_eq!(declared(2), "height padding width");
+        // The text node between them doesn't stop `.b + p` matching.
+        assert_eq!(declared(4), "height margin padding width");
+        assert_eq!(nested.value("color"), None);
+    }
+
+    #[test]
+    fn test_supports() {
+        let keyword = |keyword: &str| Value::Keyword(keyword.to_string());
+        assert!(is_supported("width", &keyword("auto")));
+        assert!(is_supported(
+            "margin-top",
+            &Value::Length(1.0, css::Unit::Em)
+        ));
+        assert!(!is_supported("padding", &keyword("auto")));
+        assert!(is_supported("line-height", &keyword("normal")));
+        assert!(is_supported(
+            "line-height",
+            &Value::Length(1.5, css::Unit::Em)
+        ));
+        assert!(!is_supported("line-height", &keyword("auto")));
+        assert!(is_supported("white-space", &keyword("pre-wrap")));
+        assert!(!is_supported("white-space", &keyword("wrap")));
+        assert!(is_supported("display", &keyword("inline-block")));
+        assert!(!is_supported("display", &keyword("grid")));
+        assert!(!is_supported("gap", &Value::Length(1.0, css::Unit::Px)));
+        assert!(is_supported("--anything", &keyword("grid")));
+
+        let tree = crate::html::parse("<p>text</p>".to_string());
+
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "p { display: grid; }
+            @supports not (display: grid) { p { display: block; } }
+            @supports (display: grid) and (not (gap: 1px)) { p { margin: 1px; } }
+            @supports (width: calc(100% - var(--gutter))) or (color: red) { p { padding: 1px; } }
+            @supports selector(p:hover) { p { width: 1px; } }
+            @supports selector(p.a[b]) { p { height: 1px; } }"
+                .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let px = |length| Some(Value::Length(length, css::Unit::Px));
+        assert_eq!(styled.value("display"), Some(keyword("block")));
+        assert_eq!(styled.value("margin"), None);
+        assert_eq!(styled.value("padding"), px(1.0));
+        assert_eq!(styled.value("width"), None);
+        assert_eq!(styled.value("height"), px(1.0));
+    }
+
+    #[test]
+    fn test_var_substitution() {
+        let tree = crate::html::parse("<div><p class='a'>text</p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "div { --brand-color: #ff0000; --spacing: 4px; --size: var(--spacing); }
+            p {
+                color: var(--brand-color);
+                margin: var(--size) var( --missing , 8px );
+                padding: var(--missing, var(--also-missing, 2px));
+                --spacing: 1px;
+            }"
+            .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let p = &styled.children[0];
+        assert_eq!(
+            p.value("color"),
+            Some(Value::Color(css::Color {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 255
+            }))
+        );
+        // `--size` was substituted on the div, so the p's `--spacing` doesn't matter.
+        assert_eq!(
+            p.value("margin"),
+            Some(Value::List(vec![
+                Value::Length(4.0, css::Unit::Px),
+                Value::Length(8.0, css::Unit::Px)
+            ]))
+        );
+        assert_eq!(p.value("padding"), Some(Value::Length(2.0, css::Unit::Px)));
+        assert_eq!(
+            style_tree(
+                root,
+                &css::parse("p { --gap: 2em; width: calc(100% - var(--gap)); }".to_string())
+            )
+            .children[0]
+                .value("width"),
+            Some(Value::Calc(css::CalcNode::Sum(vec![
+                css::CalcNode::Length(100.0, css::Unit::Percent),
+                css::CalcNode::Negate(Box::new(css::CalcNode::Length(2.0, css::Unit::Em))),
+            ])))
+        );
+        assert_eq!(
+            p.children[0].value("--spacing"),
+            Some(Value::Unparsed(vec![Token::Dimension(
+                1.0,
+                "px".to_string()
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_var_cycles_and_invalid_at_computed_value_time() {
+        let tree = crate::html::parse("<div><p>text</p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "div { color: #0000ff; }
+            p {
+                --a: var(--b, 1px);
+                --b: var(--a, 2px);
+                --c: var(--a, 3px);
+                margin: var(--a, 5px);
+                padding: var(--missing);
+                color: var(--missing);
+                width: var(--c);
+            }"
+            .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let p = &styled.children[0];
+        assert_eq!(p.value("--a"), None);
+        assert_eq!(p.value("--b"), None);
+        assert_eq!(p.value("margin"), Some(Value::Length(5.0, css::Unit::Px)));
+        assert_eq!(p.value("width"), Some(Value::Length(3.0, css::Unit::Px)));
+        // Invalid values fall back to inheritance or the initial value.
+        assert_eq!(p.value("padding"), None);
+        assert_eq!(p.value("color"), styled.value("color"));
+    }
+
+    #[test]
+    fn test_media_queries() {
+        let tree = crate::html::parse("<p>text</p>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "p { margin: 0; }
+            @media (min-width: 600px) { p { margin: 1px; } }
+            @media (400px <= width < 600px) { p { margin: 2px; } }
+            @media screen and (orientation: portrait) { p { padding: 1px; } }
+            @media print { p { color: #000000; } }
+            @media (prefers-color-scheme: dark) and (not (prefers-reduced-motion)) {
+                p { border: 1px; }
+            }
+            @media not all and (prefers-reduced-motion: reduce), (max-height: 10em) {
+                p { width: 1px; }
+            }"
+            .to_string(),
+        );
+        let values = |media: &MediaEnvironment| {
+            let styled = style_tree_with(root, &style_sheet, media, Traversal::Sequential);
+            ["margin", "padding", "color", "border", "width"]
+                .iter()
+                .map(|name| styled.value(name).map(|value| value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let some = |value: &str| Some(value.to_string());
+
+        let desktop = MediaEnvironment::default();
+        assert_eq!(
+            values(&desktop),
+            vec![some("1px"), None, None, None, some("1px")]
+        );
+
+        let phone = MediaEnvironment {
+            width: 500.0,
+            height: 900.0,
+            color_scheme: ColorScheme::Dark,
+            reduced_motion: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            values(&phone),
+            vec![some("2px"), some("1px"), None, None, None]
+        );
+
+        let print = MediaEnvironment {
+            media_type: "print".to_string(),
+            width: 300.0,
+            height: 160.0,
+            color_scheme: ColorScheme::Dark,
+            ..Default::default()
+        };
+        assert_eq!(
+            values(&print),
+            vec![some("0px"), None, some("#000000"), some("1px"), some("1px")]
+        );
+    }
+
+    #[test]
+    fn test_font_size_is_computed_against_the_parent() {
+        let tree = crate::html::parse("<div><p><span>text</span></p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "div { font-size: 20px; } p { font-size: 1.5em; } span { font-size: calc(50% + 1rem); }"
+                .to_string(),
+        );
+        let styled = style_tree(root, &style_sheet);
+        let p = &styled.children[0];
+        assert_eq!(
+            p.value("font-size"),
+            Some(Value::Length(30.0, css::Unit::Px))
+        );
+        assert_eq!(
+            p.children[0].value("font-size"),
+            Some(Value::Length(31.0, css::Unit::Px))
+        );
+        assert_eq!(
+            p.children[0].children[0].value("font-size"),
+            Some(Value::Length(31.0, css::Unit::Px))
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let tree =
+            crate::html::parse("<div class='box'><p id='x' class='a'>text</p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "p { margin: 1px; }
+            .box { color: #00ff00; }
+            #x, p.a { margin: 2px; }
+            .a { margin: 3px !important; }
+            div { color: #0000ff; }"
+                .to_string(),
+        );
+        let p = root.first_child().unwrap();
+        let media = MediaEnvironment::default();
+
+        let explanation = explain(&[root, p], &style_sheet, &media, "margin").unwrap();
+        let selectors: Vec<String> = explanation
+            .declarations
+            .iter()
+            .map(|matched| matched.selector.to_string())
+            .collect();
+        assert_eq!(selectors, vec!["p", "#x", ".a"]);
+        assert_eq!(explanation.winner, Some(2));
+        assert_eq!(explanation.declarations[2].specificity, (0, 1, 0));
+        assert!(explanation.declarations[2].declaration.important);
+        assert_eq!(explanation.declarations[2].declaration.span.start.line, 4);
+        assert_eq!(
+            explanation.value(),
+            Some(&Value::Length(3.0, css::Unit::Px))
+        );
+
+        let explanation = explain(&[root, p], &style_sheet, &media, "color").unwrap();
+        assert!(explanation.declarations.is_empty());
+        let source = explanation.inherited_from.as_ref().unwrap();
+        assert_eq!(source.generations, 1);
+        assert_eq!(source.declaration.selector.to_string(), ".box");
+        assert_eq!(
+            explanation.to_string(),
+            "* inherited from <div> at 1:1 1 generation(s) up: \
+             .box (0, 1, 0) author at 2:20: color: #00ff00\n\
+             = computed value: #00ff00\n"
+        );
+        assert_eq!(
+            explanation.value(),
+            style_tree(root, &style_sheet).children[0]
+                .value("color")
+                .as_ref()
+        );
+
+        let explanation = explain(&[root, p], &style_sheet, &media, "padding").unwrap();
+        assert!(explanation.inherited_from.is_none());
+        assert_eq!(explanation.value(), None);
+        assert!(explain(&[], &style_sheet, &media, "padding").is_none());
+    }
+
+    #[test]
+    fn test_explain_reports_computed_values() {
+        let tree = crate::html::parse("<div><p>text</p></div>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse(
+            "div { --gap: 4px; color: #0000ff; }
+            p { margin: var(--gap); color: var(--missing); font-size: 2em; }"
+                .to_string(),
+        );
+        let p = root.first_child().unwrap();
+        let media = MediaEnvironment::default();
+        let styled = style_tree(root, &style_sheet);
+
+        for property in ["margin", "color", "font-size"] {
+            let explanation = explain(&[root, p], &style_sheet, &media, property).unwrap();
+            assert_eq!(
+                explanation.computed,
+                styled.children[0].value(property),
+                "{}",
+                property
+            );
+        }
+
+        let margin = explain(&[root, p], &style_sheet, &media, "margin").unwrap();
+        assert!(!margin.invalid_at_computed_value_time);
+        assert!(margin.to_string().ends_with("= computed value: 4px\n"));
+
+        // `color` can't be resolved, so it inherits as if it were unset.
+        let color = explain(&[root, p], &style_sheet, &media, "color").unwrap();
+        assert!(color.invalid_at_computed_value_time);
+        assert!(color
+            .to_string()
+            .ends_with("= invalid at computed-value time, so unset: #0000ff\n"));
+    }
+
+    #[test]
+    fn test_display_parse() {
+        assert_eq!(Display::parse(&["block"]), Some(Display::BLOCK));
+        assert_eq!(Display::parse(&["inline", "flow"]), Some(Display::INLINE));
+        assert_eq!(
+            Display::parse(&["inline-block"]),
+            Display::parse(&["inline", "flow-root"])
+        );
+        assert_eq!(
+            Display::parse(&["flex"]),
+            Display::parse(&["block", "flex"])
+        );
+        assert_eq!(
+            Display::parse(&["ruby"]),
+            Some(Display::Box {
+                outside: DisplayOutside::Inline,
+                inside: DisplayInside::Ruby,
+                list_item: false,
+            })
+        );
+        assert_eq!(
+            Display::parse(&["list-item"]),
+            Some(Display::Box {
+                outside: DisplayOutside::Block,
+                inside: DisplayInside::Flow,
+                list_item: true,
+            })
+        );
+        assert_eq!(
+            Display::parse(&["table-cell"]),
+            Some(Display::Internal(DisplayInternal::TableCell))
+        );
+        assert_eq!(Display::parse(&["contents"]), Some(Display::Contents));
+        assert_eq!(Display::parse(&["none"]), Some(Display::None));
+        assert_eq!(Display::parse(&["inline", "block"]), None);
+        assert_eq!(Display::parse(&["grid", "list-item"]), None);
+        assert_eq!(Display::parse(&["unknown"]), None);
+    }
+
+    #[test]
+    fn test_invalid_display_is_dropped() {
+        let tree = crate::html::parse("<p>text</p>".to_string());
+        let root = tree.document_element().unwrap();
+        let style_sheet = css::parse("p { display: block; } p { display: wobbly; }".to_string());
+        assert_eq!(style_sheet.rules[1].declarations, []);
+        assert_eq!(style_tree(root, &style_sheet).display(), Display::BLOCK);
+    }
+
+    #[test]
+    fn test_parallel_style_tree_matches_sequential() {
+        let mut html = String::from("<html>");
+        for i in 0..200 {
+            html.push_str(&format!(
+                "<div class='row' id='{}'><h1 id='1'>Title</h1><p class='text'>body</p></div>",
+                i
+            ));
+        }
+        html.push_str("</html>");
+        let tree = crate::html::parse(html);
+        let root = tree.document_element().unwrap();
+        let style_sheet = crate::css::parse(
+            "div { display: block; } .row { margin: 10px; } #1 { margin: auto; } h1 { padding: 2px; }"
+                .to_string(),
+        );
+        let media = MediaEnvironment::default();
+
+        let sequential = style_tree_with(root, &style_sheet, &media, Traversal::Sequential);
+        let parallel = style_tree_with(root, &style_sheet, &media, Traversal::Parallel);
+        assert_eq!(sequential, parallel);
+        assert_eq!(sequential, style_tree(root, &style_sheet));
     }
 }
@@ -0,0 +1,200 @@
+use unicode_linebreak::linebreaks;
+use unicode_width::UnicodeWidthStr;
+
+/// Measures text set in a font, for laying it out.
+pub trait FontMetrics {
+    /// How far `text` advances along the line at `font_size`.
+    fn advance(&self, text: &str, font_size: f32) -> f32;
+
+    /// How far the font reaches above the baseline at `font_size`.
+    fn ascent(&self, font_size: f32) -> f32 {
+        font_size * 0.8
+    }
+
+    /// How far the font reaches below the baseline at `font_size`.
+    fn descent(&self, font_size: f32) -> f32 {
+        font_size * 0.2
+    }
+}
+
+/// Text drawn in the cells of a terminal, whatever its font size. Wide
+/// characters such as CJK take two cells and combining marks none.
+#[derive(Debug, Clone, Copy)]
+pub struct TerminalMetrics {
+    pub cell_width: f32,
+    pub cell_height: f32,
+}
+
+impl Default for TerminalMetrics {
+    fn default() -> TerminalMetrics {
+        TerminalMetrics {
+            cell_width: 8.0,
+            cell_height: 16.0,
+        }
+    }
+}
+
+impl FontMetrics for TerminalMetrics {
+    fn advance(&self, text: &str, _font_size: f32) -> f32 {
+        text.width() as f32 * self.cell_width
+    }
+
+    fn ascent(&self, _font_size: f32) -> f32 {
+        self.cell_height
+    }
+
+    fn descent(&self, _font_size: f32) -> f32 {
+        0.0
+    }
+}
+
+/// A font whose every character advances by the same fraction of the font
+/// size, which makes layouts easy to work out by hand.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedAdvance {
+    pub advance: f32,
+}
+
+impl FontMetrics for FixedAdvance {
+    fn advance(&self, text: &str, font_size: f32) -> f32 {
+        text.chars().count() as f32 * self.advance * font_size
+    }
+}
+
+/// A piece of text up to a place a line may break, after the white space
+/// that ends it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Segment<'a> {
+    pub word: &'a str,
+    /// The white space at the end, which hangs past the end of a line
+    /// broken after it.
+    pub space: &'a str,
+}
+
+/// Split `text` at the line break opportunities of Unicode line breaking
+/// (UAX #14): after spaces, after hyphens, between ideographs and so on.
+pub fn segments(text: &str) -> Vec<Segment<'_>> {
+    let mut start = 0;
+    linebreaks(text)
+        .map(|(end, _)| {
+            let segment = &text[start..end];
+            start = end;
+            let word = segment.trim_end_matches(is_white_space);
+            Segment {
+                word,
+                space: &segment[word.len()..],
+            }
+        })
+        .collect()
+}
+
+/// Collapse white space the way `white-space: normal` does: each run of
+/// it becomes one space. With `preserve_newlines`, as for `pre-line`, a run
+/// holding newlines becomes just those newlines instead.
+pub fn collapse_white_space(text: &str, preserve_newlines: bool) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    // How many newlines the run of white space being read holds.
+    let mut run = None;
+    let end_run = |collapsed: &mut String, newlines: usize| {
+        if preserve_newlines && newlines > 0 {
+            collapsed.extend(std::iter::repeat_n('\n', newlines));
+        } else {
+            collapsed.push(' ');
+        }
+    };
+    for char in text.chars() {
+        if is_white_space(char) {
+            *run.get_or_insert(0) += (char == '\n') as usize;
+            continue;
+        }
+        if let Some(newlines) = run.take() {
+            end_run(&mut collapsed, newlines);
+        }
+        collapsed.push(char);
+    }
+    if let Some(newlines) = run {
+        end_run(&mut collapsed, newlines);
+    }
+    collapsed
+}
+
+/// How many columns apart tab stops are.
+pub const TAB_SIZE: usize = 8;
+
+/// Replace the tabs in a line of preserved text with spaces up to the next
+/// tab stop, counting columns from the start of the line.
+pub fn expand_tabs(line: &str) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for char in line.chars() {
+        if char == '\t' {
+            let spaces = TAB_SIZE - column % TAB_SIZE;
+            expanded.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            expanded.push(char);
+            column += 1;
+        }
+    }
+    expanded
+}
+
+/// Whether `char` is document white space, which unlike other spaces such
+/// as U+00A0 NO-BREAK SPACE may be collapsed and wrapped at.
+pub fn is_white_space(char: char) -> bool {
+    matches!(char, ' ' | '\t' | '\n' | '\r' | '\x0c')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<(&str, &str)> {
+        segments(text)
+            .into_iter()
+            .map(|segment| (segment.word, segment.space))
+            .collect()
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(
+            words("A well-known  fact\n"),
+            [("A", " "), ("well-", ""), ("known", "  "), ("fact", "\n")]
+        );
+        assert_eq!(words("日本語"), [("日", ""), ("本", ""), ("語", "")]);
+        // No break at a no-break space or before closing punctuation.
+        assert_eq!(
+            words("10\u{a0}km (ok)."),
+            [("10\u{a0}km", " "), ("(ok).", "")]
+        );
+        assert_eq!(words(""), []);
+    }
+
+    #[test]
+    fn test_collapse_white_space() {
+        assert_eq!(collapse_white_space("  a \t b\n\n c ", false), " a b c ");
+        assert_eq!(
+            collapse_white_space("  a \t b \n\n c\n", true),
+            " a b\n\nc\n"
+        );
+        assert_eq!(
+            collapse_white_space("a\u{a0}\u{a0}b", false),
+            "a\u{a0}\u{a0}b"
+        );
+        assert_eq!(expand_tabs("\ta\tbc"), "        a       bc");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let terminal = TerminalMetrics::default();
+        assert_eq!(terminal.advance("abc", 32.0), 24.0);
+        assert_eq!(terminal.advance("日本", 16.0), 32.0);
+        assert_eq!(terminal.advance("e\u{301}", 16.0), 8.0);
+        assert_eq!(terminal.ascent(32.0), 16.0);
+
+        let fixed = FixedAdvance { advance: 0.5 };
+        assert_eq!(fixed.advance("日本", 10.0), 10.0);
+        assert_eq!(fixed.ascent(10.0) + fixed.descent(10.0), 10.0);
+    }
+}